@@ -1,7 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
-        .build_server(false)
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile(&["proto/nauthz.proto"], &["proto"])?;
+        .compile(&["proto/nauthz.proto", "proto/nstream.proto"], &["proto"])?;
     Ok(())
 }
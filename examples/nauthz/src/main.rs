@@ -1,15 +1,25 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tonic::{transport::Server, Request, Response, Status};
 
 use nauthz_grpc::authorization_server::{Authorization, AuthorizationServer};
-use nauthz_grpc::{Decision, EventReply, EventRequest};
+use nauthz_grpc::{
+    ConnectionReply, ConnectionRequest, Decision, EventReply, EventRequest, ReqReply, ReqRequest,
+};
 
 pub mod nauthz_grpc {
     tonic::include_proto!("nauthz");
 }
 
+/// Connections permitted from one IP address each minute
+const CONNECTIONS_PER_MINUTE: u32 = 10;
+
 #[derive(Default)]
 pub struct EventAuthz {
     allowed_kinds: Vec<u64>,
+    // start of the current minute, and connections made in it, by IP
+    connections: Mutex<HashMap<String, (Instant, u32)>>,
 }
 
 #[tonic::async_trait]
@@ -30,16 +40,111 @@ impl Authorization for EventAuthz {
             reply = nauthz_grpc::EventReply {
                 decision: Decision::Permit as i32,
                 message: None,
+                // decisions only depend on the kind, so may be reused
+                cache_ttl_seconds: Some(300),
             };
         } else {
             println!("Blocked! (kind={})", event.kind);
             reply = nauthz_grpc::EventReply {
                 decision: Decision::Deny as i32,
                 message: Some(format!("kind {} not permitted", event.kind)),
+                cache_ttl_seconds: Some(300),
             };
         }
         Ok(Response::new(reply))
     }
+
+    async fn connection_admit(
+        &self,
+        request: Request<ConnectionRequest>,
+    ) -> Result<Response<ConnectionReply>, Status> {
+        let req = request.into_inner();
+        let ip = req.ip_addr.unwrap_or_default();
+        println!(
+            "recvd connection, [ip={:?}, user_agent={:?}, header_count={}]",
+            ip,
+            req.user_agent,
+            req.headers.len()
+        );
+        // Forbid clients that do not identify themselves
+        if req.user_agent.is_none() {
+            println!("Blocked! (no user agent)");
+            return Ok(Response::new(ConnectionReply {
+                decision: Decision::Deny as i32,
+                message: Some("a user agent is required".to_owned()),
+                retry_after_seconds: None,
+            }));
+        }
+        // Rate limit connections from each IP
+        let mut connections = self.connections.lock().unwrap();
+        let now = Instant::now();
+        let entry = connections.entry(ip.clone()).or_insert((now, 0));
+        if now.duration_since(entry.0) > Duration::from_secs(60) {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        let reply = if entry.1 > CONNECTIONS_PER_MINUTE {
+            println!("Rate limited! (ip={:?})", ip);
+            let wait = Duration::from_secs(60).saturating_sub(now.duration_since(entry.0));
+            ConnectionReply {
+                decision: Decision::Deny as i32,
+                message: Some("too many connections".to_owned()),
+                retry_after_seconds: Some(wait.as_secs() as u32 + 1),
+            }
+        } else {
+            ConnectionReply {
+                decision: Decision::Permit as i32,
+                message: None,
+                retry_after_seconds: None,
+            }
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn req_admit(
+        &self,
+        request: Request<ReqRequest>,
+    ) -> Result<Response<ReqReply>, Status> {
+        let req = request.into_inner();
+        println!(
+            "recvd req, [sub_id={:?}, filter_count={}, origin={:?}]",
+            req.sub_id,
+            req.filters.len(),
+            req.origin
+        );
+        // Narrow every filter to the whitelisted kinds, dropping any
+        // filter that asks for none of them.
+        let filters: Vec<_> = req
+            .filters
+            .into_iter()
+            .filter_map(|mut f| {
+                if f.kinds.is_empty() {
+                    f.kinds = self.allowed_kinds.clone();
+                } else {
+                    f.kinds.retain(|k| self.allowed_kinds.contains(k));
+                    if f.kinds.is_empty() {
+                        return None;
+                    }
+                }
+                Some(f)
+            })
+            .collect();
+        let reply = if filters.is_empty() {
+            println!("Blocked! (sub_id={:?})", req.sub_id);
+            nauthz_grpc::ReqReply {
+                decision: Decision::Deny as i32,
+                message: Some("no permitted kinds requested".to_owned()),
+                filters: vec![],
+            }
+        } else {
+            nauthz_grpc::ReqReply {
+                decision: Decision::Permit as i32,
+                message: None,
+                filters,
+            }
+        };
+        Ok(Response::new(reply))
+    }
 }
 
 #[tokio::main]
@@ -49,6 +154,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // A simple authorization engine that allows kinds 0-3
     let checker = EventAuthz {
         allowed_kinds: vec![0, 1, 2, 3],
+        ..Default::default()
     };
     println!("EventAuthz Server listening on {}", addr);
     // Start serving
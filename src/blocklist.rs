@@ -0,0 +1,226 @@
+//! Authors blocked by relay moderators
+//!
+//! Moderators block authors by listing them in their NIP-51 mute list
+//! (kind 10000).  The latest mute list from each moderator is kept in
+//! memory, and events from any author on any of them are rejected.
+//! Changes to the set of blocked authors are published, so that their
+//! stored events can be left out of query results.
+use crate::config::Settings;
+use crate::db::QueryResult;
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::is_hex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{info, warn};
+
+/// Kind of NIP-51 mute lists
+pub const MUTE_LIST_KIND: u64 = 10000;
+
+/// A moderator's latest mute list
+struct MuteList {
+    created_at: u64,
+    pubkeys: HashSet<String>,
+}
+
+/// Authors blocked by moderators' mute lists
+#[derive(Clone)]
+pub struct Blocklist {
+    moderators: Arc<HashSet<String>>,
+    lists: Arc<RwLock<HashMap<String, MuteList>>>,
+    /// The blocked authors, updated whenever they change
+    changes: Arc<watch::Sender<HashSet<String>>>,
+}
+
+impl Blocklist {
+    #[must_use]
+    pub fn new(settings: &Settings) -> Blocklist {
+        Blocklist {
+            moderators: Arc::new(
+                settings
+                    .authorization
+                    .moderators
+                    .iter()
+                    .map(|pk| pk.to_lowercase())
+                    .collect(),
+            ),
+            lists: Arc::new(RwLock::new(HashMap::new())),
+            changes: Arc::new(watch::channel(HashSet::new()).0),
+        }
+    }
+
+    /// Watch the set of blocked authors.
+    #[must_use]
+    pub fn subscribe(&self) -> watch::Receiver<HashSet<String>> {
+        self.changes.subscribe()
+    }
+
+    /// Are any moderators configured?
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        !self.moderators.is_empty()
+    }
+
+    /// Is the author blocked?  Moderators themselves never are.
+    #[must_use]
+    pub fn is_blocked(&self, pubkey: &str) -> bool {
+        if self.moderators.contains(pubkey) {
+            return false;
+        }
+        self.lists
+            .read()
+            .unwrap()
+            .values()
+            .any(|l| l.pubkeys.contains(pubkey))
+    }
+
+    /// Every blocked author.
+    #[must_use]
+    pub fn blocked(&self) -> HashSet<String> {
+        self.lists
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|l| l.pubkeys.iter())
+            .filter(|pk| !self.moderators.contains(*pk))
+            .cloned()
+            .collect()
+    }
+
+    /// Update the blocklist from a newly stored event, if it is a
+    /// moderator's mute list.  Returns whether the blocked authors
+    /// changed.
+    pub fn observe(&self, event: &Event) -> bool {
+        if event.kind != MUTE_LIST_KIND || !self.moderators.contains(&event.pubkey) {
+            return false;
+        }
+        let before = self.blocked();
+        {
+            let mut lists = self.lists.write().unwrap();
+            if lists
+                .get(&event.pubkey)
+                .is_some_and(|l| l.created_at > event.created_at)
+            {
+                return false;
+            }
+            let pubkeys = event
+                .tag_values_by_name("p")
+                .into_iter()
+                .filter(|pk| pk.len() == 64 && is_hex(pk))
+                .map(|pk| pk.to_lowercase())
+                .collect();
+            lists.insert(
+                event.pubkey.clone(),
+                MuteList {
+                    created_at: event.created_at,
+                    pubkeys,
+                },
+            );
+        }
+        let after = self.blocked();
+        info!(
+            "mute list from moderator {:?} updated ({} authors blocked)",
+            event.get_author_prefix(),
+            after.len()
+        );
+        if after == before {
+            return false;
+        }
+        self.changes.send_replace(after);
+        true
+    }
+
+    /// Load the moderators' stored mute lists.
+    pub async fn load(&self, repo: &Arc<dyn NostrRepo>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let sub = Subscription {
+            id: "mute-lists".to_owned(),
+            filters: vec![ReqFilter {
+                ids: None,
+                kinds: Some(vec![MUTE_LIST_KIND]),
+                since: None,
+                until: None,
+                authors: Some(self.moderators.iter().cloned().collect()),
+                limit: None,
+                tags: None,
+                force_no_match: false,
+            }],
+        };
+        let (query_tx, mut query_rx) = mpsc::channel::<QueryResult>(16);
+        let (_abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+        if let Err(e) = repo
+            .query_subscription(sub, "blocklist".to_owned(), query_tx, abandon_query_rx)
+            .await
+        {
+            warn!("could not load moderator mute lists: {:?}", e);
+            return;
+        }
+        while let Some(qr) = query_rx.recv().await {
+            if qr.event == "EOSE" {
+                break;
+            }
+            if let Ok(event) = serde_json::from_str::<Event>(&qr.event) {
+                self.observe(&event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODERATOR: &str = "35d26e4690cbe1a898af61cc3515661eb5fa763b57bd0b42e45099c8b32fd50f";
+    const SPAMMER: &str = "887645fef0ce0c3c1218d2f5d8e6132a19304cdc57cd20281d082f38cfea0072";
+
+    fn mute_list(pubkey: &str, created_at: u64, muted: &[&str]) -> Event {
+        let mut event = Event::simple_event();
+        event.pubkey = pubkey.to_owned();
+        event.kind = MUTE_LIST_KIND;
+        event.created_at = created_at;
+        event.tags = muted
+            .iter()
+            .map(|pk| vec!["p".to_owned(), (*pk).to_owned()])
+            .collect();
+        event.build_index();
+        event
+    }
+
+    fn blocklist() -> Blocklist {
+        let mut settings = Settings::default();
+        settings.authorization.moderators = vec![MODERATOR.to_owned()];
+        Blocklist::new(&settings)
+    }
+
+    #[test]
+    fn moderator_mute_lists_block() {
+        let bl = blocklist();
+        let changes = bl.subscribe();
+        assert!(bl.observe(&mute_list(MODERATOR, 10, &[SPAMMER])));
+        assert!(bl.is_blocked(SPAMMER));
+        assert!(changes.borrow().contains(SPAMMER));
+        // older lists are ignored
+        assert!(!bl.observe(&mute_list(MODERATOR, 5, &[])));
+        assert!(bl.is_blocked(SPAMMER));
+        // a newer list with the same authors changes nothing
+        assert!(!bl.observe(&mute_list(MODERATOR, 15, &[SPAMMER])));
+        // newer lists replace the old one
+        assert!(bl.observe(&mute_list(MODERATOR, 20, &[])));
+        assert!(!bl.is_blocked(SPAMMER));
+        assert!(changes.borrow().is_empty());
+    }
+
+    #[test]
+    fn other_mute_lists_ignored() {
+        let bl = blocklist();
+        assert!(!bl.observe(&mute_list(SPAMMER, 10, &[MODERATOR])));
+        assert!(!bl.is_blocked(MODERATOR));
+        // moderators can not be blocked
+        bl.observe(&mute_list(MODERATOR, 10, &[MODERATOR]));
+        assert!(!bl.is_blocked(MODERATOR));
+    }
+}
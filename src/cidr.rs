@@ -0,0 +1,142 @@
+//! IP address ranges in CIDR notation
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of IP addresses, such as `10.0.0.0/8` or `fd00::/8`.  A
+/// plain address is a range containing only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Is the address within this range?  IPv4 addresses mapped into
+    /// IPv6 are treated as IPv4.
+    #[must_use]
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Is the address within any of the ranges?
+#[must_use]
+pub fn any_contains(ranges: &[Cidr], ip: &IpAddr) -> bool {
+    ranges.iter().any(|c| c.contains(ip))
+}
+
+/// Convert IPv4-mapped IPv6 addresses to IPv4.
+fn canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+/// Do the first `prefix` bits of the addresses match?
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full = usize::from(prefix / 8);
+    if net[..full] != ip[..full] {
+        return false;
+    }
+    let rem = prefix % 8;
+    if rem == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rem);
+    net[full] & mask == ip[full] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::CustomError(format!("invalid CIDR: {s}"));
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.trim(), None),
+        };
+        let addr = canonical(&IpAddr::from_str(addr).map_err(|_| invalid())?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Cidr::from_str(&s)
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        let c: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(c.contains(&ip("10.1.200.3")));
+        assert!(!c.contains(&ip("10.2.0.1")));
+        assert!(c.contains(&ip("::ffff:10.1.0.9")));
+        let c: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(c.contains(&ip("192.168.1.200")));
+        assert!(!c.contains(&ip("192.168.1.127")));
+        let single: Cidr = "1.2.3.4".parse().unwrap();
+        assert!(single.contains(&ip("1.2.3.4")));
+        assert!(!single.contains(&ip("1.2.3.5")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        let c: Cidr = "fd00::/8".parse().unwrap();
+        assert!(c.contains(&ip("fd12:3456::1")));
+        assert!(!c.contains(&ip("fe80::1")));
+        assert!(!c.contains(&ip("10.0.0.1")));
+        let all: Cidr = "::/0".parse().unwrap();
+        assert!(all.contains(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+}
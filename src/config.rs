@@ -1,7 +1,9 @@
 //! Configuration file and settings management
+use crate::cidr::Cidr;
 use crate::payment::Processor;
 use config::{Config, ConfigError, File};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,6 +36,36 @@ pub struct Database {
 pub struct Grpc {
     pub event_admission_server: Option<String>,
     pub restricts_write: bool,
+    pub req_admission: bool, // ask the admission server to authorize subscriptions
+    pub connection_admission: bool, // ask the admission server to authorize websocket connections
+    pub listen_address: Option<String>, // serve the event stream on this address
+    pub timeout_ms: u64,     // deadline for connecting and each admission call (0 for none)
+    pub fail_open: bool,     // admit if the admission server cannot decide
+    pub connect_retries: u32, // connection attempts after the first
+    pub retry_backoff_ms: u64, // delay before the first retry, doubled each time
+    pub decision_cache_size: usize, // cached decisions, for replies with a ttl (0 to disable)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct PolicyScript {
+    pub path: Option<String>, // Rhai script that decides on event admission
+    pub max_operations: u64,  // operations a script may perform per event (0 for unlimited)
+    pub timeout_ms: u64,      // time a script may run per event (0 for unlimited)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct ContentRules {
+    pub path: Option<String>, // TOML file of rules matching event content and tags
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct WritePolicy {
+    pub plugin: Option<String>, // strfry-compatible write policy plugin executable
+    pub timeout_ms: u64,        // time to wait for a plugin decision
+    pub fail_open: bool,        // admit events if the plugin fails or times out
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,13 +74,76 @@ pub struct Network {
     pub port: u16,
     pub address: String,
     pub remote_ip_header: Option<String>, // retrieve client IP from this HTTP header if present
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>, // peers whose remote_ip_header is believed
     pub ping_interval_seconds: u32,
+    pub compression: bool, // negotiate permessage-deflate with clients that support it
+    pub compression_level: u32, // deflate level, from 0 (fastest) to 9 (smallest)
+    pub compression_min_bytes: usize, // smaller outgoing messages are not compressed
+    #[serde(default)]
+    pub listeners: Vec<Listener>, // if present, these replace address and port
+    #[serde(default)]
+    pub proxy_protocol_trusted: Vec<Cidr>, // peers allowed to send PROXY protocol headers
+}
+
+impl Network {
+    /// Every configured listener.  Without any, the relay listens on
+    /// `address` and `port`.
+    #[must_use]
+    pub fn all_listeners(&self) -> Vec<Listener> {
+        if self.listeners.is_empty() {
+            vec![Listener {
+                address: Some(format!("{}:{}", self.address.trim(), self.port)),
+                path: None,
+                socket_mode: None,
+                role: ListenerRole::Relay,
+                proxy_protocol: false,
+            }]
+        } else {
+            self.listeners.clone()
+        }
+    }
+}
+
+/// What a listener serves
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerRole {
+    /// The relay websocket and every HTTP endpoint except /admin/
+    #[default]
+    Relay,
+    /// Only the metrics and /admin/ endpoints
+    Admin,
+}
+
+impl ListenerRole {
+    /// Should a request for this path be served?
+    #[must_use]
+    pub fn serves(self, path: &str) -> bool {
+        match self {
+            ListenerRole::Relay => !path.starts_with("/admin/"),
+            ListenerRole::Admin => path == "/metrics" || path.starts_with("/admin/"),
+        }
+    }
+}
+
+/// A TCP address or Unix domain socket to accept connections on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Listener {
+    pub address: Option<String>,  // TCP address and port
+    pub path: Option<String>,     // Unix domain socket path
+    pub socket_mode: Option<u32>, // permissions for a Unix domain socket
+    #[serde(default)]
+    pub role: ListenerRole,
+    #[serde(default)]
+    pub proxy_protocol: bool, // read PROXY protocol headers from trusted peers
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Options {
     pub reject_future_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the future
+    pub reject_past_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the past
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,6 +161,9 @@ pub struct Retention {
 pub struct Limits {
     pub messages_per_sec: Option<u32>, // Artificially slow down event writing to limit disk consumption (averaged over 1 minute)
     pub subscriptions_per_min: Option<u32>, // Artificially slow down request (db query) creation to prevent abuse (averaged over 1 minute)
+    pub max_conns_per_ip: Option<u32>, // How many websocket connections may be open from one IP address
+    pub conns_per_ip_per_min: Option<u32>, // How many websocket connections may be opened from one IP address (averaged over 1 minute)
+    pub ipv6_prefix_len: u8, // IPv6 addresses sharing this many leading bits count as one address for connection limits
     pub db_conns_per_client: Option<u32>, // How many concurrent database queries (not subscriptions) may a client have?
     pub max_blocking_threads: usize,
     pub max_event_bytes: Option<usize>, // Maximum size of an EVENT message
@@ -76,6 +174,97 @@ pub struct Limits {
     pub event_kind_blacklist: Option<Vec<u64>>,
     pub event_kind_allowlist: Option<Vec<u64>>,
     pub limit_scrapers: bool,
+    pub kind_limits: Option<KindMap<KindLimits>>, // per-kind event limits
+    pub admission_workers: usize, // events from different authors admitted concurrently
+    pub write_batch_size: usize,  // most events persisted together
+    pub verify_workers: usize,    // threads verifying event signatures (0 verifies inline)
+    pub verify_events_per_take: usize, // most events a verification worker dequeues at once
+    pub recent_ids_cache: usize,  // recently stored event ids, which skip verification
+    pub outbound_buffer_bytes: usize, // bytes queued for each client before live events are refused
+    pub lag_policy: LagPolicy,    // what to do when a client misses live events
+}
+
+impl Limits {
+    /// Limits specific to an event kind, if any are configured.
+    #[must_use]
+    pub fn for_kind(&self, kind: u64) -> Option<&KindLimits> {
+        self.kind_limits.as_ref().and_then(|m| m.get(kind))
+    }
+}
+
+/// Settings for individual event kinds.  Configuration keys are
+/// strings, so kinds are parsed once, as the configuration is loaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KindMap<V>(HashMap<u64, V>);
+
+impl<V> KindMap<V> {
+    /// The setting for an event kind, if it has one.
+    #[must_use]
+    pub fn get(&self, kind: u64) -> Option<&V> {
+        self.0.get(&kind)
+    }
+}
+
+impl<V: Serialize> Serialize for KindMap<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k.to_string(), v)))
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for KindMap<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::<String, V>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(k, v)| {
+                k.trim()
+                    .parse::<u64>()
+                    .map(|kind| (kind, v))
+                    .map_err(|_| serde::de::Error::custom(format!("{k:?} is not an event kind")))
+            })
+            .collect::<Result<_, _>>()
+            .map(KindMap)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct DuplicateContent {
+    pub enabled: bool,           // reject content posted by too many authors
+    pub kinds: Vec<u64>,         // event kinds checked
+    pub window_seconds: u64,     // how long authors are remembered for each content
+    pub max_authors: usize,      // distinct authors that may post the same content
+    pub min_length: usize,       // shorter content (letters and digits) is not checked
+    pub max_fingerprints: usize, // most distinct contents remembered
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct KindLimits {
+    pub max_content_bytes: Option<usize>, // Maximum size of the event content
+    pub max_tags: Option<usize>,          // Maximum number of tags
+    pub max_tag_value_bytes: Option<usize>, // Maximum size of any single tag value
+    pub reject_past_seconds: Option<usize>, // overrides options.reject_past_seconds for this kind
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct ProofOfWork {
+    pub min_difficulty: Option<u32>, // minimum NIP-13 difficulty (leading zero bits of event id) to publish
+    pub kind_difficulty: Option<KindMap<u32>>, // per-kind overrides of min_difficulty
+    pub exempt_whitelist: bool, // pubkeys in the authorization whitelist are not required to do work
+    pub exempt_paid: bool,      // admitted pay-to-relay pubkeys are not required to do work
+}
+
+impl ProofOfWork {
+    /// Difficulty required for an event kind, if any work is required at all.
+    #[must_use]
+    pub fn required_difficulty(&self, kind: u64) -> Option<u32> {
+        self.kind_difficulty
+            .as_ref()
+            .and_then(|m| m.get(kind).copied())
+            .or(self.min_difficulty)
+            .filter(|d| *d > 0)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,6 +273,80 @@ pub struct Authorization {
     pub pubkey_whitelist: Option<Vec<String>>, // If present, only allow these pubkeys to publish events
     pub nip42_auth: bool,                      // if true enables NIP-42 authentication
     pub nip42_dms: bool, // if true send DMs only to their authenticated recipients
+    #[serde(default)]
+    pub ip_allowlist: Vec<Cidr>, // If present, only these addresses may connect
+    #[serde(default)]
+    pub ip_denylist: Vec<Cidr>, // These addresses may not connect
+    pub ip_access_file: Option<String>, // more allowed and denied addresses, reloaded when changed
+    #[serde(default)]
+    pub moderators: Vec<String>, // authors on these pubkeys' mute lists may not publish
+    pub hide_muted_events: bool, // hide the stored events of muted authors
+    #[serde(default)]
+    pub shadow_reject: Vec<ShadowCheck>, // checks whose rejections are hidden from the client
+    pub admin_token: Option<String>, // bearer token required for admin requests that change state
+}
+
+impl Authorization {
+    /// Are events failing this check shadow-rejected?
+    #[must_use]
+    pub fn shadows(&self, check: ShadowCheck) -> bool {
+        self.shadow_reject.contains(&check)
+    }
+}
+
+/// Relay checks whose rejections can be hidden from the client, by
+/// reporting the event as saved
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowCheck {
+    /// Authors not in the pubkey whitelist
+    Whitelist,
+    /// Authors on a moderator's mute list
+    Muted,
+    /// Content posted by too many authors
+    DuplicateContent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Quarantine {
+    pub enabled: bool,            // hold events for moderator approval
+    pub new_authors: bool,        // hold events from authors with no published events
+    pub unverified_authors: bool, // hold events from authors without a valid NIP-05 verification
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Invites {
+    pub enabled: bool,                     // only admitted accounts may publish
+    pub member_invites: bool,              // admitted members may mint invite codes
+    pub member_max_uses: u64,              // redemptions allowed for a code minted by a member
+    pub member_expiration: Option<String>, // how long codes minted by members last
+    pub member_invites_per_day: u64,       // codes each member may mint per day (0 for no limit)
+}
+
+impl Invites {
+    #[must_use]
+    pub fn member_expiration_duration(&self) -> Option<Duration> {
+        self.member_expiration
+            .as_ref()
+            .and_then(|x| parse_duration::parse(x).ok())
+    }
+
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.member_max_uses > 0
+            && (self.member_expiration.is_none() || self.member_expiration_duration().is_some())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Groups {
+    pub enabled: bool,              // host NIP-29 groups
+    pub secret_key: Option<String>, // relay key that signs group state
+    #[serde(default)]
+    pub creators: Vec<String>, // pubkeys allowed to create groups (empty for anyone)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -108,6 +371,20 @@ pub struct Diagnostics {
     pub tracing: bool, // enables tokio console-subscriber
 }
 
+/// How a connection that cannot keep up with live events is handled
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Drop the oldest queued events to make room for new ones
+    DropOldest,
+    /// Drop new events, and send a NOTICE that events were missed
+    Notice,
+    /// Send CLOSED for subscriptions that missed events
+    Close,
+    /// Disconnect the client
+    Disconnect,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum VerifiedUsersMode {
@@ -184,9 +461,17 @@ pub struct Settings {
     pub diagnostics: Diagnostics,
     pub database: Database,
     pub grpc: Grpc,
+    pub policy_script: PolicyScript,
+    pub content_rules: ContentRules,
+    pub write_policy: WritePolicy,
     pub network: Network,
     pub limits: Limits,
+    pub duplicate_content: DuplicateContent,
+    pub pow: ProofOfWork,
     pub authorization: Authorization,
+    pub quarantine: Quarantine,
+    pub invites: Invites,
+    pub groups: Groups,
     pub pay_to_relay: PayToRelay,
     pub verified_users: VerifiedUsers,
     pub retention: Retention,
@@ -195,6 +480,16 @@ pub struct Settings {
 }
 
 impl Settings {
+    /// How far in the past events of this kind may be dated, with
+    /// per-kind limits overriding the global option.
+    #[must_use]
+    pub fn reject_past_seconds(&self, kind: u64) -> Option<usize> {
+        self.limits
+            .for_kind(kind)
+            .and_then(|l| l.reject_past_seconds)
+            .or(self.options.reject_past_seconds)
+    }
+
     pub fn new(config_file_name: &Option<String>) -> Result<Self, ConfigError> {
         let default_settings = Self::default();
         // attempt to construct settings with file
@@ -247,6 +542,24 @@ impl Settings {
         // initialize durations for verified users
         settings.verified_users.init();
 
+        // ensure invite codes can be used, and durations parse
+        assert!(
+            settings.invites.is_valid(),
+            "Invites member_max_uses must be positive, and member_expiration must parse"
+        );
+
+        // groups are published under the relay's own key
+        if settings.groups.enabled {
+            assert!(
+                settings
+                    .groups
+                    .secret_key
+                    .as_ref()
+                    .is_some_and(|key| key != "<nostr nsec>"),
+                "Groups require a secret_key"
+            );
+        }
+
         // Validate pay to relay settings
         if settings.pay_to_relay.enabled {
             if settings.pay_to_relay.processor == Processor::ClnRest {
@@ -301,16 +614,44 @@ impl Default for Settings {
             grpc: Grpc {
                 event_admission_server: None,
                 restricts_write: false,
+                req_admission: false,
+                connection_admission: false,
+                listen_address: None,
+                timeout_ms: 1000,
+                fail_open: true,
+                connect_retries: 3,
+                retry_backoff_ms: 100,
+                decision_cache_size: 10_000,
+            },
+            policy_script: PolicyScript {
+                path: None,
+                max_operations: 1_000_000,
+                timeout_ms: 100,
+            },
+            content_rules: ContentRules { path: None },
+            write_policy: WritePolicy {
+                plugin: None,
+                timeout_ms: 1000,
+                fail_open: true,
             },
             network: Network {
                 port: 8080,
                 ping_interval_seconds: 300,
                 address: "0.0.0.0".to_owned(),
                 remote_ip_header: None,
+                trusted_proxies: vec![],
+                compression: false,
+                compression_level: 6,
+                compression_min_bytes: 1024,
+                listeners: vec![],
+                proxy_protocol_trusted: vec![],
             },
             limits: Limits {
                 messages_per_sec: None,
                 subscriptions_per_min: None,
+                max_conns_per_ip: None,
+                conns_per_ip_per_min: None,
+                ipv6_prefix_len: 64,
                 db_conns_per_client: None,
                 max_blocking_threads: 16,
                 max_event_bytes: Some(2 << 17),      // 128K
@@ -321,11 +662,57 @@ impl Default for Settings {
                 event_kind_blacklist: None,
                 event_kind_allowlist: None,
                 limit_scrapers: false,
+                kind_limits: None,
+                admission_workers: 8,
+                write_batch_size: 64,
+                verify_workers: 4,
+                verify_events_per_take: 1,
+                recent_ids_cache: 100_000,
+                outbound_buffer_bytes: 4 * 1024 * 1024,
+                lag_policy: LagPolicy::DropOldest,
+            },
+            duplicate_content: DuplicateContent {
+                enabled: false,
+                kinds: vec![1],
+                window_seconds: 600,
+                max_authors: 5,
+                min_length: 20,
+                max_fingerprints: 100_000,
+            },
+            pow: ProofOfWork {
+                min_difficulty: None,   // No work required
+                kind_difficulty: None,  // No per-kind requirements
+                exempt_whitelist: true, // Whitelisted authors need not do work
+                exempt_paid: true,      // Paying authors need not do work
             },
             authorization: Authorization {
                 pubkey_whitelist: None, // Allow any address to publish
                 nip42_auth: false,      // Disable NIP-42 authentication
                 nip42_dms: false,       // Send DMs to everybody
+                ip_allowlist: vec![],
+                ip_denylist: vec![],
+                ip_access_file: None,
+                moderators: vec![],
+                hide_muted_events: false,
+                shadow_reject: vec![],
+                admin_token: None,
+            },
+            quarantine: Quarantine {
+                enabled: false,
+                new_authors: true,
+                unverified_authors: false,
+            },
+            invites: Invites {
+                enabled: false,
+                member_invites: false,
+                member_max_uses: 1,
+                member_expiration: Some("1 week".to_owned()),
+                member_invites_per_day: 5,
+            },
+            groups: Groups {
+                enabled: false,
+                secret_key: None,
+                creators: vec![],
             },
             pay_to_relay: PayToRelay {
                 enabled: false,
@@ -358,6 +745,7 @@ impl Default for Settings {
             },
             options: Options {
                 reject_future_seconds: None, // Reject events in the future if defined
+                reject_past_seconds: None,   // Reject events in the past if defined
             },
             logging: Logging {
                 folder_path: None,
@@ -366,3 +754,78 @@ impl Default for Settings {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_split_paths() {
+        assert!(ListenerRole::Relay.serves("/"));
+        assert!(ListenerRole::Relay.serves("/metrics"));
+        assert!(ListenerRole::Relay.serves("/join"));
+        assert!(!ListenerRole::Relay.serves("/admin/pending"));
+        assert!(ListenerRole::Admin.serves("/metrics"));
+        assert!(ListenerRole::Admin.serves("/admin/pending"));
+        assert!(!ListenerRole::Admin.serves("/"));
+        assert!(!ListenerRole::Admin.serves("/join"));
+    }
+
+    #[test]
+    fn listeners_default_to_address_and_port() {
+        let mut network = Settings::default().network;
+        network.address = " 127.0.0.1 ".to_owned();
+        network.port = 7000;
+        let listeners = network.all_listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address.as_deref(), Some("127.0.0.1:7000"));
+        assert_eq!(listeners[0].path, None);
+        assert_eq!(listeners[0].role, ListenerRole::Relay);
+        assert!(!listeners[0].proxy_protocol);
+
+        // configured listeners replace the default one.
+        network.listeners = vec![Listener {
+            address: None,
+            path: Some("/run/relay.sock".to_owned()),
+            socket_mode: Some(0o660),
+            role: ListenerRole::Admin,
+            proxy_protocol: false,
+        }];
+        let listeners = network.all_listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address, None);
+        assert_eq!(listeners[0].role, ListenerRole::Admin);
+    }
+
+    #[test]
+    fn listener_role_defaults_to_relay() {
+        let listener: Listener = serde_json::from_str(r#"{"address":"0.0.0.0:80"}"#).unwrap();
+        assert_eq!(listener.role, ListenerRole::Relay);
+        assert!(!listener.proxy_protocol);
+        let listener: Listener =
+            serde_json::from_str(r#"{"path":"/run/a.sock","role":"admin"}"#).unwrap();
+        assert_eq!(listener.role, ListenerRole::Admin);
+    }
+
+    #[test]
+    fn kind_maps_keyed_by_kind() {
+        let source = "min_difficulty = 8\n\
+                      kind_difficulty = { 1 = 24, 7 = 0 }\n\
+                      exempt_whitelist = true\n\
+                      exempt_paid = true\n";
+        let pow: ProofOfWork = Config::builder()
+            .add_source(File::from_str(source, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(pow.required_difficulty(1), Some(24));
+        assert_eq!(pow.required_difficulty(7), None);
+        assert_eq!(pow.required_difficulty(3), Some(8));
+        // kinds survive being written back out, as the defaults are
+        let json = serde_json::to_string(&pow.kind_difficulty).unwrap();
+        let again: KindMap<u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(again), pow.kind_difficulty);
+        assert!(serde_json::from_str::<KindMap<u32>>(r#"{"text":1}"#).is_err());
+    }
+}
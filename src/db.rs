@@ -1,14 +1,23 @@
 //! Event persistence and querying
-use crate::config::Settings;
+use crate::blocklist::Blocklist;
+use crate::config::{Settings, ShadowCheck};
+use crate::duplicates::DuplicateDetector;
 use crate::error::{Error, Result};
 use crate::event::Event;
+use crate::groups::GroupManager;
+use crate::invite;
 use crate::nauthz;
 use crate::notice::Notice;
 use crate::payment::PaymentMessage;
+use crate::policy::plugin::PluginPolicy;
+use crate::policy::rules::RulesPolicy;
+use crate::policy::script::ScriptPolicy;
+use crate::policy::{PolicyAction, PolicyDecision, PolicyRequest};
 use crate::repo::postgres::{PostgresPool, PostgresRepo};
 use crate::repo::sqlite::SqliteRepo;
-use crate::repo::NostrRepo;
+use crate::repo::{NostrRepo, WriteOutcome};
 use crate::server::NostrMetrics;
+use crate::verify::VerifyPool;
 use governor::clock::Clock;
 use governor::{Quota, RateLimiter};
 use log::LevelFilter;
@@ -18,10 +27,12 @@ use r2d2;
 use sqlx::pool::PoolOptions;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::ConnectOptions;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
-use std::thread;
 use std::time::{Duration, Instant};
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type PooledConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
@@ -36,6 +47,14 @@ pub struct SubmittedEvent {
     pub auth_pubkey: Option<Vec<u8>>,
 }
 
+/// A moderator's decision on an event held for moderation
+pub struct Moderation {
+    pub id: String,
+    pub approve: bool,
+    /// Replies with whether an event with this id was held
+    pub reply: tokio::sync::oneshot::Sender<Result<bool>>,
+}
+
 /// Database file
 pub const DB_FILE: &str = "nostr.db";
 
@@ -98,53 +117,195 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
     repo
 }
 
+/// An event that passed admission, waiting to be persisted
+struct AdmittedEvent {
+    event: Event,
+    notice_tx: tokio::sync::mpsc::Sender<Notice>,
+    source_ip: String,
+    /// Amount reserved from a paying author's balance, which is
+    /// charged once the event is written.  Zero for authors that post
+    /// for free.
+    reserved: u64,
+    /// Held for moderator approval, rather than published
+    pending: bool,
+}
+
+/// Events queued for each admission worker
+const ADMISSION_QUEUE: usize = 256;
+
+/// Balance reserved by admitted events of paying authors, until they
+/// are charged.  Events are admitted faster than they are written, so
+/// checking an author's stored balance alone would let them queue
+/// events they cannot pay for.  The balance is read while reserving,
+/// and charged while releasing, under the same lock, so a reservation
+/// never sees a charge without its release.
+#[derive(Clone, Default)]
+struct Reservations {
+    reserved: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
+}
+
+impl Reservations {
+    /// Reserve an amount from an author's stored balance, returning
+    /// false if too little of it is left.
+    async fn reserve(&self, repo: &Arc<dyn NostrRepo>, pubkey: &Keys, amount: u64) -> Result<bool> {
+        let mut reserved = self.reserved.lock().await;
+        let (_, balance) = repo.get_account_balance(pubkey).await?;
+        let key = pubkey.public_key().to_string();
+        let held = reserved.get(&key).copied().unwrap_or(0);
+        if balance < held + amount {
+            return Ok(false);
+        }
+        reserved.insert(key, held + amount);
+        Ok(true)
+    }
+
+    /// Release a reservation, once the event has been written or
+    /// dropped, charging the author if it was written.
+    async fn release(&self, repo: &Arc<dyn NostrRepo>, pubkey: &Keys, amount: u64, charge: bool) {
+        let mut reserved = self.reserved.lock().await;
+        if charge {
+            if let Err(e) = repo.update_account_balance(pubkey, false, amount).await {
+                warn!("could not charge for event: {:?}", e);
+            }
+        }
+        let key = pubkey.public_key().to_string();
+        if let Some(held) = reserved.get_mut(&key) {
+            *held = held.saturating_sub(amount);
+            if *held == 0 {
+                reserved.remove(&key);
+            }
+        }
+    }
+}
+
+/// Handles the database writer shares with the rest of the relay
+#[derive(Clone)]
+pub struct WriterContext {
+    pub repo: Arc<dyn NostrRepo>,
+    pub settings: Settings,
+    /// Stored events are published to subscribers here
+    pub bcast_tx: tokio::sync::broadcast::Sender<Event>,
+    /// Metadata events are sent to the NIP-05 verifier
+    pub metadata_tx: tokio::sync::broadcast::Sender<Event>,
+    pub payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
+    pub verifier: VerifyPool,
+    pub duplicates: DuplicateDetector,
+    pub groups: GroupManager,
+    pub admissions: invite::Admissions,
+}
+
+/// Choose the admission worker for an author.  Every event from an
+/// author is handled by the same worker, so they are admitted and
+/// persisted in the order they were received.
+fn admission_worker_for(pubkey: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    pubkey.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
 /// Spawn a database writer that persists events to the `SQLite` store.
+///
+/// Events are admitted (checked against relay policy) by a pool of
+/// workers, with every event from one author going to the same
+/// worker.  Admitted events are persisted in batches by a single
+/// task, which also carries out moderators' decisions on held events.
 pub async fn db_writer(
-    repo: Arc<dyn NostrRepo>,
-    settings: Settings,
+    ctx: WriterContext,
     mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
-    bcast_tx: tokio::sync::broadcast::Sender<Event>,
-    metadata_tx: tokio::sync::broadcast::Sender<Event>,
-    payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
+    moderation_rx: tokio::sync::mpsc::Receiver<Moderation>,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<()> {
-    // are we performing NIP-05 checking?
-    let nip05_active = settings.verified_users.is_active();
-    // are we requriing NIP-05 user verification?
-    let nip05_enabled = settings.verified_users.is_enabled();
+    let settings = &ctx.settings;
+    let repo = &ctx.repo;
+    debug!("Pay to relay: {}", settings.pay_to_relay.enabled);
+    let workers = settings.limits.admission_workers.max(1);
 
-    let pay_to_relay_enabled = settings.pay_to_relay.enabled;
-    let cost_per_event = settings.pay_to_relay.cost_per_event;
-    debug!("Pay to relay: {}", pay_to_relay_enabled);
+    // create a client if GRPC is enabled.
+    // Check with externalized event admitter service, if one is defined.
+    // Each worker uses a copy, sharing the connection and decision
+    // cache.
+    let grpc_client = if settings.grpc.event_admission_server.is_some() {
+        Some(nauthz::EventAuthzService::connect(&settings.grpc).await)
+    } else {
+        None
+    };
 
-    //upgrade_db(&mut pool.get()?)?;
+    // start a write policy plugin, if one is defined.  The plugin
+    // handles one event at a time, so it is shared by the workers.
+    let plugin_policy = settings.write_policy.plugin.as_ref().map(|_| {
+        info!("using write policy plugin");
+        Arc::new(tokio::sync::Mutex::new(PluginPolicy::new(
+            &settings.write_policy,
+        )))
+    });
 
-    // Make a copy of the whitelist
-    let whitelist = &settings.authorization.pubkey_whitelist.clone();
+    // authors muted by moderators may not publish.
+    let blocklist = Blocklist::new(settings);
+    blocklist.load(repo).await;
+    tokio::task::spawn(hide_muted_authors(
+        repo.clone(),
+        blocklist.subscribe(),
+        settings.authorization.hide_muted_events,
+    ));
+    ctx.groups.load(repo).await;
 
-    // get rate limit settings
-    let rps_setting = settings.limits.messages_per_sec;
-    let mut most_recent_rate_limit = Instant::now();
-    let mut lim_opt = None;
-    let clock = governor::clock::QuantaClock::default();
-    if let Some(rps) = rps_setting {
-        if rps > 0 {
-            info!("Enabling rate limits for event creation ({}/sec)", rps);
-            let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
-            lim_opt = Some(RateLimiter::direct(Quota::per_minute(quota)));
+    // admitted events are sent to the persistence stage.
+    let (persist_tx, persist_rx) =
+        tokio::sync::mpsc::channel::<AdmittedEvent>(settings.limits.event_persist_buffer);
+    let reservations = Reservations::default();
+    let persister = tokio::task::spawn(persist_events(
+        ctx.clone(),
+        persist_rx,
+        moderation_rx,
+        blocklist.clone(),
+        reservations.clone(),
+    ));
+
+    // load content rules, if a file is defined.  The rules are
+    // shared by the workers, and reloaded when the file changes.
+    let rules_policy = if settings.content_rules.path.is_some() {
+        match RulesPolicy::load(&settings.content_rules) {
+            Ok(p) => Some(Arc::new(std::sync::Mutex::new(p))),
+            Err(e) => {
+                error!("Failed to load content rules {e}");
+                std::process::exit(1);
+            }
         }
-    }
-    // create a client if GRPC is enabled.
-    // Check with externalized event admitter service, if one is defined.
-    let mut grpc_client = if let Some(svr) = settings.grpc.event_admission_server {
-        Some(nauthz::EventAuthzService::connect(&svr).await)
     } else {
         None
     };
 
-    //let gprc_client = settings.grpc.event_admission_server.map(|s| {
-    //        event_admitter_connect(&s);
-    //    });
+    let mut worker_txs = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        // load an embedded policy script, if one is defined.
+        let script_policy = if settings.policy_script.path.is_some() {
+            match ScriptPolicy::load(&settings.policy_script) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    error!("Failed to load policy script {e}");
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+        let worker = AdmissionWorker {
+            ctx: ctx.clone(),
+            grpc_client: grpc_client.clone(),
+            script_policy,
+            rules_policy: rules_policy.clone(),
+            plugin_policy: plugin_policy.clone(),
+            blocklist: blocklist.clone(),
+            reservations: reservations.clone(),
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel::<SubmittedEvent>(ADMISSION_QUEUE);
+        tokio::task::spawn(worker.run(rx, persist_tx.clone()));
+        worker_txs.push(tx);
+    }
+    // only the workers hold the persistence channel now, so it closes
+    // once they have all finished.
+    drop(persist_tx);
+    info!("started {} event admission workers", workers);
 
     loop {
         if shutdown.try_recv().is_ok() {
@@ -154,19 +315,69 @@ pub async fn db_writer(
         // call blocking read on channel
         let next_event = event_rx.recv().await;
         // if the channel has closed, we will never get work
-        if next_event.is_none() {
+        let Some(subm_event) = next_event else {
+            break;
+        };
+        let worker = admission_worker_for(&subm_event.event.pubkey, workers);
+        if worker_txs[worker].send(subm_event).await.is_err() {
+            warn!("event admission worker stopped");
             break;
         }
-        // track if an event write occurred; this is used to
-        // update the rate limiter
-        let mut event_write = false;
-        let subm_event = next_event.unwrap();
+    }
+    // let the workers finish what they have been given, and wait
+    // for those events to be persisted.
+    drop(worker_txs);
+    persister.await.ok();
+    info!("database connection closed");
+    Ok(())
+}
+
+/// Decides whether submitted events should be persisted, according
+/// to relay policy.
+struct AdmissionWorker {
+    ctx: WriterContext,
+    grpc_client: Option<nauthz::EventAuthzService>,
+    script_policy: Option<ScriptPolicy>,
+    rules_policy: Option<Arc<std::sync::Mutex<RulesPolicy>>>,
+    plugin_policy: Option<Arc<tokio::sync::Mutex<PluginPolicy>>>,
+    blocklist: Blocklist,
+    reservations: Reservations,
+}
+
+impl AdmissionWorker {
+    /// Admit events in the order received, passing them on for
+    /// persistence.
+    async fn run(
+        mut self,
+        mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
+        persist_tx: tokio::sync::mpsc::Sender<AdmittedEvent>,
+    ) {
+        while let Some(subm_event) = event_rx.recv().await {
+            if let Some(admitted) = self.admit(subm_event).await {
+                if persist_tx.send(admitted).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Check an event against relay policy, notifying the client of
+    /// any rejection.  Returns the event if it should be persisted.
+    async fn admit(&mut self, subm_event: SubmittedEvent) -> Option<AdmittedEvent> {
+        let settings = &self.ctx.settings;
+        let repo = &self.ctx.repo;
+        // are we performing NIP-05 checking?
+        let nip05_active = settings.verified_users.is_active();
+        // are we requriing NIP-05 user verification?
+        let nip05_enabled = settings.verified_users.is_enabled();
+        let pay_to_relay_enabled = settings.pay_to_relay.enabled;
+        let cost_per_event = settings.pay_to_relay.cost_per_event;
+        let whitelist = &settings.authorization.pubkey_whitelist;
         let event = subm_event.event;
         let notice_tx = subm_event.notice_tx;
 
         // Check that event kind isn't blacklisted
-        let kinds_blacklist = &settings.limits.event_kind_blacklist.clone();
-        if let Some(event_kind_blacklist) = kinds_blacklist {
+        if let Some(event_kind_blacklist) = &settings.limits.event_kind_blacklist {
             if event_kind_blacklist.contains(&event.kind) {
                 debug!(
                     "rejecting event: {}, blacklisted kind: {}",
@@ -176,13 +387,12 @@ pub async fn db_writer(
                 notice_tx
                     .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
                     .ok();
-                continue;
+                return None;
             }
         }
 
         // Check that event kind isn't allowlisted
-        let kinds_allowlist = &settings.limits.event_kind_allowlist.clone();
-        if let Some(event_kind_allowlist) = kinds_allowlist {
+        if let Some(event_kind_allowlist) = &settings.limits.event_kind_allowlist {
             if !event_kind_allowlist.contains(&event.kind) {
                 debug!(
                     "rejecting event: {}, allowlist kind: {}",
@@ -192,21 +402,87 @@ pub async fn db_writer(
                 notice_tx
                     .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
                     .ok();
-                continue;
+                return None;
             }
         }
 
+        // Check that a moderator has not muted the author
+        if self.blocklist.is_blocked(&event.pubkey) {
+            if settings.authorization.shadows(ShadowCheck::Muted) {
+                shadow_reject("mute list", event, &subm_event.source_ip, &notice_tx);
+                return None;
+            }
+            debug!(
+                "rejecting event: {}, muted author",
+                event.get_event_id_prefix()
+            );
+            notice_tx
+                .try_send(Notice::blocked(event.id, "pubkey is blocked by relay"))
+                .ok();
+            return None;
+        }
+
+        // Check that the author may publish to the event's group
+        let auth_pubkey = subm_event.auth_pubkey.as_ref().map(hex::encode);
+        if let Some(notice) = self.ctx.groups.rejection(&event, auth_pubkey.as_deref()) {
+            debug!(
+                "rejecting event: {}, not permitted in group",
+                event.get_event_id_prefix()
+            );
+            notice_tx.try_send(notice).ok();
+            return None;
+        }
+
+        // Join requests come from authors who are not admitted yet
+        let join_request = settings.invites.enabled && event.kind == invite::JOIN_REQUEST_KIND;
+
         // Set to none until balance is got from db
         // Will stay none if user in whitelisted and does not have to pay to post
         // When pay to relay is enabled the whitelist is not a list of who can post
         // It is a list of who can post for free
         let mut user_balance: Option<u64> = None;
         if !pay_to_relay_enabled {
-            // check if this event is authorized.
-            if let Some(allowed_addrs) = whitelist {
+            let whitelisted = whitelist
+                .as_ref()
+                .is_some_and(|wl| wl.contains(&event.pubkey));
+            // with invites, accounts admitted by an invite code may
+            // also publish.
+            if settings.invites.enabled {
+                let exempt = whitelisted
+                    || join_request
+                    || settings.authorization.moderators.contains(&event.pubkey);
+                if !exempt {
+                    match self.ctx.admissions.is_admitted(repo, &event.pubkey).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            debug!(
+                                "rejecting event: {}, author not admitted",
+                                event.get_event_id_prefix()
+                            );
+                            notice_tx
+                                .try_send(Notice::restricted(
+                                    event.id,
+                                    "an invite code is needed to publish to this relay",
+                                ))
+                                .ok();
+                            return None;
+                        }
+                        Err(e) => {
+                            warn!("Error checking admission status: {:?}", e);
+                            let msg = "relay experienced an error checking your admission status";
+                            notice_tx.try_send(Notice::error(event.id, msg)).ok();
+                            return None;
+                        }
+                    }
+                }
+            } else if let Some(allowed_addrs) = whitelist {
                 // TODO: incorporate delegated pubkeys
                 // if the event address is not in allowed_addrs.
                 if !allowed_addrs.contains(&event.pubkey) {
+                    if settings.authorization.shadows(ShadowCheck::Whitelist) {
+                        shadow_reject("whitelist", event, &subm_event.source_ip, &notice_tx);
+                        return None;
+                    }
                     debug!(
                         "rejecting event: {}, unauthorized author",
                         event.get_event_id_prefix()
@@ -217,13 +493,15 @@ pub async fn db_writer(
                             "pubkey is not allowed to publish to this relay",
                         ))
                         .ok();
-                    continue;
+                    return None;
                 }
             }
         } else {
             // If the user is on whitelist there is no need to check if the user is admitted or has balance to post
-            if whitelist.is_none()
-                || (whitelist.is_some() && !whitelist.as_ref().unwrap().contains(&event.pubkey))
+            if !join_request
+                && (whitelist.is_none()
+                    || (whitelist.is_some()
+                        && !whitelist.as_ref().unwrap().contains(&event.pubkey)))
             {
                 let key = Keys::from_pk_str(&event.pubkey).unwrap();
                 match repo.get_account_balance(&key).await {
@@ -234,13 +512,14 @@ pub async fn db_writer(
 
                             // If the user is in DB but not admitted
                             // Send meeage to payment thread to check if outstanding invoice has been paid
-                            payment_tx
+                            self.ctx
+                                .payment_tx
                                 .send(PaymentMessage::CheckAccount(event.pubkey))
                                 .ok();
                             notice_tx
                                 .try_send(Notice::blocked(event.id, "User is not admitted"))
                                 .ok();
-                            continue;
+                            return None;
                         }
 
                         // Checks that user has enough balance to post
@@ -250,7 +529,7 @@ pub async fn db_writer(
                             notice_tx
                                 .try_send(Notice::blocked(event.id, "Insufficient balance"))
                                 .ok();
-                            continue;
+                            return None;
                         }
                         user_balance = Some(balance);
                         debug!("User balance: {:?}", user_balance);
@@ -262,20 +541,21 @@ pub async fn db_writer(
                         // User does not exist
                         info!("Unregistered user");
                         if settings.pay_to_relay.sign_ups && settings.pay_to_relay.direct_message {
-                            payment_tx
+                            self.ctx
+                                .payment_tx
                                 .send(PaymentMessage::NewAccount(event.pubkey))
                                 .ok();
                         }
                         let msg = "Pubkey not registered";
                         notice_tx.try_send(Notice::error(event.id, msg)).ok();
-                        continue;
+                        return None;
                     }
                     Err(err) => {
                         warn!("Error checking admission status: {:?}", err);
                         let msg = "relay experienced an error checking your admission status";
                         notice_tx.try_send(Notice::error(event.id, msg)).ok();
                         // Other error
-                        continue;
+                        return None;
                     }
                 }
             }
@@ -310,7 +590,7 @@ pub async fn db_writer(
                                 "NIP-05 verification is no longer valid (expired/wrong domain)",
                             ))
                             .ok();
-                        continue;
+                        return None;
                     }
                 }
                 Err(
@@ -327,21 +607,86 @@ pub async fn db_writer(
                             "NIP-05 verification needed to publish events",
                         ))
                         .ok();
-                    continue;
+                    return None;
                 }
                 Err(e) => {
                     warn!("checking nip05 verification status failed: {:?}", e);
-                    continue;
+                    return None;
                 }
             }
         }
 
+        let verified = validation.as_ref().is_some_and(|v| {
+            v.as_ref()
+                .is_ok_and(|uv| uv.is_valid(&settings.verified_users))
+        });
+
         // nip05 address
         let nip05_address: Option<crate::nip05::Nip05Name> =
             validation.and_then(|x| x.ok().map(|y| y.name));
 
+        // Check that the content is not being posted by many authors.
+        // The author is only counted once every check has passed.
+        if !self.ctx.duplicates.admit(&event) {
+            reject_duplicate_content(settings, event, &subm_event.source_ip, &notice_tx);
+            return None;
+        }
+
+        // Policy script and plugin checks
+        let policy_req = PolicyRequest {
+            event: &event,
+            ip_addr: &subm_event.source_ip,
+            origin: subm_event.origin.as_deref(),
+            user_agent: subm_event.user_agent.as_deref(),
+            auth_pubkey: subm_event.auth_pubkey.as_deref(),
+            nip05: nip05_address.as_ref(),
+        };
+        if let Some(ref mut p) = self.script_policy {
+            trace!("checking if policy script permits");
+            match p.admit_event(&policy_req).await {
+                Ok(decision) => {
+                    if !policy_permits("policy script", decision, &policy_req, &notice_tx) {
+                        return None;
+                    }
+                }
+                Err(e) => {
+                    warn!("policy script error: {:?}", e);
+                }
+            }
+        }
+        if let Some(ref p) = self.rules_policy {
+            trace!("checking if content rules permit");
+            let decision = p.lock().unwrap().admit_event(&policy_req);
+            if !policy_permits("content rule", decision, &policy_req, &notice_tx) {
+                return None;
+            }
+        }
+        if let Some(ref p) = self.plugin_policy {
+            trace!("checking if write policy plugin permits");
+            let decision_res = p.lock().await.admit_event(&policy_req).await;
+            match decision_res {
+                Ok(decision) => {
+                    if !policy_permits("write policy", decision, &policy_req, &notice_tx) {
+                        return None;
+                    }
+                }
+                Err(e) => {
+                    warn!("write policy plugin error: {:?}", e);
+                    if !settings.write_policy.fail_open {
+                        notice_tx
+                            .try_send(Notice::error(
+                                event.id,
+                                "relay could not evaluate its write policy",
+                            ))
+                            .ok();
+                        return None;
+                    }
+                }
+            }
+        }
+
         // GRPC check
-        if let Some(ref mut c) = grpc_client {
+        if let Some(ref c) = self.grpc_client {
             trace!("checking if grpc permits");
             let grpc_start = Instant::now();
             let decision_res = c
@@ -356,6 +701,10 @@ pub async fn db_writer(
                 .await;
             match decision_res {
                 Ok(decision) => {
+                    if decision.shadow_rejected() {
+                        shadow_reject("GRPC", event, &subm_event.source_ip, &notice_tx);
+                        return None;
+                    }
                     if !decision.permitted() {
                         // GPRC returned a decision to reject this event
                         info!(
@@ -369,106 +718,541 @@ pub async fn db_writer(
                         notice_tx
                             .try_send(Notice::blocked(
                                 event.id,
-                                &decision.message().unwrap_or_default(),
+                                &decision
+                                    .message()
+                                    .filter(|m| !m.is_empty())
+                                    .unwrap_or_else(|| "event not permitted".to_owned()),
                             ))
                             .ok();
-                        continue;
+                        return None;
                     }
                 }
                 Err(e) => {
                     warn!("GRPC server error: {:?}", e);
+                    if !settings.grpc.fail_open {
+                        notice_tx
+                            .try_send(Notice::error(
+                                event.id,
+                                "relay could not reach its authorization server",
+                            ))
+                            .ok();
+                        return None;
+                    }
                 }
             }
         }
 
+        // Join and invite requests are answered by the relay
+        if let Some(notice) = invite::handle_event(
+            settings,
+            repo,
+            &self.ctx.admissions,
+            &event,
+            auth_pubkey.as_deref(),
+        )
+        .await
+        {
+            notice_tx.try_send(notice).ok();
+            return None;
+        }
+
+        // reserve the cost of the event, now that it will be written.
+        // Earlier events from the author may not have been charged
+        // yet.
+        let mut reserved = 0;
+        if user_balance.is_some() {
+            let key = Keys::from_pk_str(&event.pubkey).unwrap();
+            match self.reservations.reserve(repo, &key, cost_per_event).await {
+                Ok(true) => reserved = cost_per_event,
+                Ok(false) => {
+                    debug!("user: {}, does not have a balance", &event.pubkey);
+                    notice_tx
+                        .try_send(Notice::blocked(event.id, "Insufficient balance"))
+                        .ok();
+                    return None;
+                }
+                Err(e) => {
+                    warn!("could not reserve balance: {:?}", e);
+                    notice_tx
+                        .try_send(Notice::error(
+                            event.id,
+                            "relay could not check your balance",
+                        ))
+                        .ok();
+                    return None;
+                }
+            }
+        }
+
+        // other authors of the content may have been counted while
+        // this event was checked
+        if !self.ctx.duplicates.record(&event) {
+            if reserved > 0 {
+                let key = Keys::from_pk_str(&event.pubkey).unwrap();
+                self.reservations.release(repo, &key, reserved, false).await;
+            }
+            reject_duplicate_content(settings, event, &subm_event.source_ip, &notice_tx);
+            return None;
+        }
+        let pending = self.holds_for_moderation(&event, verified).await;
+
         // send any metadata events to the NIP-05 verifier
         if nip05_active && event.is_kind_metadata() {
             // we are sending this prior to even deciding if we
             // persist it.  this allows the nip05 module to
             // inspect it, update if necessary, or persist a new
             // event and broadcast it itself.
-            metadata_tx.send(event.clone()).ok();
+            self.ctx.metadata_tx.send(event.clone()).ok();
+        }
+
+        Some(AdmittedEvent {
+            event,
+            notice_tx,
+            source_ip: subm_event.source_ip,
+            reserved,
+            pending,
+        })
+    }
+
+    /// Should an event be held for moderator approval?
+    async fn holds_for_moderation(&self, event: &Event, verified: bool) -> bool {
+        let settings = &self.ctx.settings;
+        let quarantine = &settings.quarantine;
+        if !quarantine.enabled || event.is_ephemeral() || event.is_kind_metadata() {
+            return false;
         }
+        let exempt = settings
+            .authorization
+            .pubkey_whitelist
+            .as_ref()
+            .is_some_and(|wl| wl.contains(&event.pubkey))
+            || settings.authorization.moderators.contains(&event.pubkey);
+        if exempt {
+            return false;
+        }
+        if quarantine.unverified_authors && !verified {
+            return true;
+        }
+        if quarantine.new_authors {
+            return match self.ctx.repo.author_has_events(&event.pubkey).await {
+                Ok(published) => !published,
+                Err(e) => {
+                    // hold the event, rather than publish it unchecked
+                    warn!("could not check for published events: {:?}", e);
+                    true
+                }
+            };
+        }
+        false
+    }
+}
 
-        // TODO: cache recent list of authors to remove a DB call.
+/// Persist admitted events, and publish them to subscribers.  Events
+/// waiting to be written are taken together, up to the configured
+/// batch size.  Events approved by a moderator are published here
+/// too.
+async fn persist_events(
+    ctx: WriterContext,
+    mut persist_rx: tokio::sync::mpsc::Receiver<AdmittedEvent>,
+    mut moderation_rx: tokio::sync::mpsc::Receiver<Moderation>,
+    blocklist: Blocklist,
+    reservations: Reservations,
+) {
+    let WriterContext {
+        repo,
+        settings,
+        bcast_tx,
+        verifier,
+        ..
+    } = &ctx;
+    // authors of held events are told when they are approved, if
+    // they are still connected.
+    let mut held_notices: HashMap<String, tokio::sync::mpsc::Sender<Notice>> = HashMap::new();
+    let batch_size = settings.limits.write_batch_size.max(1);
+
+    // get rate limit settings
+    let rps_setting = settings.limits.messages_per_sec;
+    let mut most_recent_rate_limit = Instant::now();
+    let mut lim_opt = None;
+    let clock = governor::clock::QuantaClock::default();
+    if let Some(rps) = rps_setting {
+        if rps > 0 {
+            info!("Enabling rate limits for event creation ({}/sec)", rps);
+            let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
+            lim_opt = Some(RateLimiter::direct(Quota::per_minute(quota)));
+        }
+    }
+
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        // wait for an event, then take any others that are ready
+        tokio::select! {
+            admitted = persist_rx.recv() => match admitted {
+                Some(admitted) => batch.push(admitted),
+                None => break,
+            },
+            Some(moderation) = moderation_rx.recv() => {
+                moderate(&ctx, &blocklist, &mut held_notices, moderation).await;
+                continue;
+            }
+        }
+        while batch.len() < batch_size {
+            match persist_rx.try_recv() {
+                Ok(admitted) => batch.push(admitted),
+                Err(_) => break,
+            }
+        }
+        trace!("persisting batch of {} events", batch.len());
         let start = Instant::now();
-        if event.is_ephemeral() {
-            bcast_tx.send(event.clone()).ok();
-            debug!(
-                "published ephemeral event: {:?} from: {:?} in: {:?}",
-                event.get_event_id_prefix(),
-                event.get_author_prefix(),
-                start.elapsed()
-            );
-            event_write = true;
+        // ephemeral events are never stored; the rest are written
+        // together.
+        let stored: Vec<Event> = batch
+            .iter()
+            .filter(|admitted| !admitted.event.is_ephemeral() && !admitted.pending)
+            .map(|admitted| admitted.event.clone())
+            .collect();
+        let mut outcomes = write_batch(repo, &stored).await.into_iter();
+        for admitted in batch.drain(..) {
+            let event = admitted.event;
+            let notice_tx = admitted.notice_tx;
+            // track if an event write occurred; this is used to
+            // update the rate limiter
+            let mut event_write = false;
+            // held events are charged for as if they were written
+            let mut held = false;
+            if event.is_ephemeral() {
+                bcast_tx.send(event.clone()).ok();
+                debug!(
+                    "published ephemeral event: {:?} from: {:?} in: {:?}",
+                    event.get_event_id_prefix(),
+                    event.get_author_prefix(),
+                    start.elapsed()
+                );
+                event_write = true;
 
-            // send OK message
-            notice_tx.try_send(Notice::saved(event.id)).ok();
-        } else {
-            match repo.write_event(&event).await {
-                Ok(updated) => {
-                    if updated == 0 {
-                        trace!("ignoring duplicate or deleted event");
-                        notice_tx.try_send(Notice::duplicate(event.id)).ok();
-                    } else {
+                // send OK message
+                notice_tx.try_send(Notice::saved(event.id.clone())).ok();
+            } else if admitted.pending {
+                match repo.write_pending_event(&event).await {
+                    Ok(true) => {
+                        held = true;
                         info!(
-                            "persisted event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
+                            "held event for moderation: {:?} (kind: {}) from: {:?} (IP: {:?})",
                             event.get_event_id_prefix(),
                             event.kind,
                             event.get_author_prefix(),
-                            start.elapsed(),
-                            subm_event.source_ip,
+                            admitted.source_ip,
                         );
-                        event_write = true;
-                        // send this out to all clients
-                        bcast_tx.send(event.clone()).ok();
-                        notice_tx.try_send(Notice::saved(event.id)).ok();
+                        notice_tx.try_send(Notice::pending(event.id.clone())).ok();
+                        held_notices.retain(|_, tx| !tx.is_closed());
+                        held_notices.insert(event.id.clone(), notice_tx.clone());
+                    }
+                    Ok(false) => {
+                        notice_tx.try_send(Notice::duplicate(event.id.clone())).ok();
+                    }
+                    Err(err) => {
+                        warn!("held event insert failed: {:?}", err);
+                        let msg = "relay experienced an error trying to publish the latest event";
+                        notice_tx
+                            .try_send(Notice::error(event.id.clone(), msg))
+                            .ok();
                     }
                 }
-                Err(err) => {
-                    warn!("event insert failed: {:?}", err);
-                    let msg = "relay experienced an error trying to publish the latest event";
-                    notice_tx.try_send(Notice::error(event.id, msg)).ok();
+            } else {
+                let outcome = outcomes
+                    .next()
+                    .unwrap_or_else(|| Err(Error::CustomError("missing write outcome".to_owned())));
+                match outcome {
+                    Ok(outcome) => {
+                        if outcome != WriteOutcome::Blocked {
+                            // resubmissions of this event can skip
+                            // verification
+                            verifier.record_stored(&event.id);
+                        }
+                        if outcome != WriteOutcome::Saved {
+                            trace!("ignoring duplicate or deleted event ({:?})", outcome);
+                            notice_tx.try_send(Notice::duplicate(event.id.clone())).ok();
+                        } else {
+                            info!(
+                                "persisted event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
+                                event.get_event_id_prefix(),
+                                event.kind,
+                                event.get_author_prefix(),
+                                start.elapsed(),
+                                admitted.source_ip,
+                            );
+                            event_write =
+                                publish_stored(&ctx, &blocklist, &event, &notice_tx).await;
+                        }
+                    }
+                    Err(err) => {
+                        warn!("event insert failed: {:?}", err);
+                        let msg = "relay experienced an error trying to publish the latest event";
+                        notice_tx
+                            .try_send(Notice::error(event.id.clone(), msg))
+                            .ok();
+                    }
                 }
             }
-        }
 
-        // use rate limit, if defined, and if an event was actually written.
-        if event_write {
-            // If pay to relay is diabaled or the cost per event is 0
-            // No need to update user balance
-            if pay_to_relay_enabled && cost_per_event > 0 {
-                // If the user balance is some, user was not on whitelist
-                // Their balance should be reduced by the cost per event
-                if let Some(_balance) = user_balance {
-                    let pubkey = Keys::from_pk_str(&event.pubkey)?;
-                    repo.update_account_balance(&pubkey, false, cost_per_event)
-                        .await?;
+            // paying authors are charged for events that were written
+            // (or held), and their reservation released.
+            if admitted.reserved > 0 {
+                if let Ok(pubkey) = Keys::from_pk_str(&event.pubkey) {
+                    reservations
+                        .release(repo, &pubkey, admitted.reserved, event_write || held)
+                        .await;
                 }
             }
-            if let Some(ref lim) = lim_opt {
-                if let Err(n) = lim.check() {
-                    let wait_for = n.wait_time_from(clock.now());
-                    // check if we have recently logged rate
-                    // limits, but print out a message only once
-                    // per second.
-                    if most_recent_rate_limit.elapsed().as_secs() > 10 {
-                        warn!(
-                            "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
-                            wait_for
-                        );
-                        // reset last rate limit message
-                        most_recent_rate_limit = Instant::now();
+
+            // use rate limit, if defined, and if an event was actually written.
+            if event_write {
+                if let Some(ref lim) = lim_opt {
+                    if let Err(n) = lim.check() {
+                        let wait_for = n.wait_time_from(clock.now());
+                        // check if we have recently logged rate
+                        // limits, but print out a message only once
+                        // per second.
+                        if most_recent_rate_limit.elapsed().as_secs() > 10 {
+                            warn!(
+                                "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
+                                wait_for
+                            );
+                            // reset last rate limit message
+                            most_recent_rate_limit = Instant::now();
+                        }
+                        // block event writes, allowing them to queue up
+                        tokio::time::sleep(wait_for).await;
                     }
-                    // block event writes, allowing them to queue up
-                    thread::sleep(wait_for);
-                    continue;
                 }
             }
         }
     }
-    info!("database connection closed");
-    Ok(())
+}
+
+/// Publish a newly stored event to subscribers and its author, and
+/// apply its effect on moderators' mute lists and groups.  Returns
+/// false if the event's group no longer permits it, in which case it
+/// was hidden instead.
+async fn publish_stored(
+    ctx: &WriterContext,
+    blocklist: &Blocklist,
+    event: &Event,
+    notice_tx: &tokio::sync::mpsc::Sender<Notice>,
+) -> bool {
+    // group events may change the group
+    if let Some(notice) = ctx.groups.apply(event, &ctx.repo, &ctx.bcast_tx).await {
+        notice_tx.try_send(notice).ok();
+        return false;
+    }
+    // send this out to all clients
+    ctx.bcast_tx.send(event.clone()).ok();
+    notice_tx.try_send(Notice::saved(event.id.clone())).ok();
+    // a moderator's mute list may block authors; their stored events
+    // are hidden in the background.
+    blocklist.observe(event);
+    true
+}
+
+/// Approve or reject an event held for moderation.  Approved events
+/// are published as if they had just been written; their authors
+/// were charged when they were held.
+async fn moderate(
+    ctx: &WriterContext,
+    blocklist: &Blocklist,
+    held_notices: &mut HashMap<String, tokio::sync::mpsc::Sender<Notice>>,
+    moderation: Moderation,
+) {
+    let id = moderation.id;
+    let notice_tx = held_notices.remove(&id);
+    let result = if moderation.approve {
+        match ctx.repo.approve_pending_event(&id).await {
+            Ok(Some((event, outcome))) => {
+                info!(
+                    "moderator approved event: {:?} ({:?})",
+                    event.get_event_id_prefix(),
+                    outcome
+                );
+                if outcome != WriteOutcome::Blocked {
+                    ctx.verifier.record_stored(&event.id);
+                }
+                if outcome == WriteOutcome::Saved {
+                    // the author may have disconnected
+                    let notice_tx = notice_tx.unwrap_or_else(|| tokio::sync::mpsc::channel(1).0);
+                    publish_stored(ctx, blocklist, &event, &notice_tx).await;
+                }
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        }
+    } else {
+        ctx.repo
+            .reject_pending_event(&id)
+            .await
+            .inspect(|rejected| {
+                if *rejected {
+                    info!("moderator rejected event: {:?}", id);
+                }
+            })
+    };
+    if let Err(ref e) = result {
+        warn!("could not moderate held event: {:?}", e);
+    }
+    moderation.reply.send(result).ok();
+}
+
+/// Keep the stored events of authors muted by moderators out of
+/// query results, following changes to the blocklist.  If hiding is
+/// disabled, authors hidden before are shown again.
+async fn hide_muted_authors(
+    repo: Arc<dyn NostrRepo>,
+    mut blocked_rx: tokio::sync::watch::Receiver<HashSet<String>>,
+    enabled: bool,
+) {
+    loop {
+        let muted: Vec<String> = if enabled {
+            blocked_rx.borrow_and_update().iter().cloned().collect()
+        } else {
+            vec![]
+        };
+        let count = muted.len();
+        match repo.set_muted_authors(muted).await {
+            Ok(()) => debug!("hiding events by {} muted authors", count),
+            Err(e) => warn!("could not update muted authors: {:?}", e),
+        }
+        if !enabled || blocked_rx.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Write a batch of events in one transaction.  If the batch fails,
+/// each event is retried on its own, so that one bad event does not
+/// fail the others.
+async fn write_batch(repo: &Arc<dyn NostrRepo>, events: &[Event]) -> Vec<Result<WriteOutcome>> {
+    if events.is_empty() {
+        return vec![];
+    }
+    match repo.write_events(events).await {
+        Ok(outcomes) => outcomes.into_iter().map(Ok).collect(),
+        Err(err) => {
+            warn!(
+                "batch insert of {} events failed, writing individually: {:?}",
+                events.len(),
+                err
+            );
+            let mut results = Vec::with_capacity(events.len());
+            for e in events {
+                // a batch of one, so blocked and hidden events are
+                // still told apart from duplicates
+                let outcome = repo
+                    .write_events(std::slice::from_ref(e))
+                    .await
+                    .and_then(|o| {
+                        o.into_iter()
+                            .next()
+                            .ok_or_else(|| Error::CustomError("no outcome for event".to_owned()))
+                    });
+                results.push(outcome);
+            }
+            results
+        }
+    }
+}
+
+/// Act on a policy decision, notifying the client of any rejection.
+/// Returns true if the event should continue to be processed.
+fn policy_permits(
+    policy: &str,
+    decision: PolicyDecision,
+    req: &PolicyRequest,
+    notice_tx: &tokio::sync::mpsc::Sender<Notice>,
+) -> bool {
+    let event = req.event;
+    match decision.action {
+        PolicyAction::Accept => true,
+        PolicyAction::Reject => {
+            info!(
+                "{} rejected event: {:?} (kind: {}) from: {:?} (IP: {:?})",
+                policy,
+                event.get_event_id_prefix(),
+                event.kind,
+                event.get_author_prefix(),
+                req.ip_addr
+            );
+            notice_tx
+                .try_send(Notice::blocked(
+                    event.id.clone(),
+                    &decision.message.unwrap_or_default(),
+                ))
+                .ok();
+            false
+        }
+        PolicyAction::Flag => {
+            warn!(
+                "{} flagged event: {:?} (kind: {}) from: {:?} (IP: {:?}) ({})",
+                policy,
+                event.get_event_id_prefix(),
+                event.kind,
+                event.get_author_prefix(),
+                req.ip_addr,
+                decision.message.unwrap_or_default()
+            );
+            true
+        }
+        PolicyAction::ShadowReject => {
+            shadow_reject(policy, event.clone(), req.ip_addr, notice_tx);
+            false
+        }
+    }
+}
+
+/// Reject an event whose content has been posted by too many
+/// authors, shadow-rejecting it if configured.
+fn reject_duplicate_content(
+    settings: &Settings,
+    event: Event,
+    source_ip: &str,
+    notice_tx: &tokio::sync::mpsc::Sender<Notice>,
+) {
+    if settings
+        .authorization
+        .shadows(ShadowCheck::DuplicateContent)
+    {
+        shadow_reject("duplicate content", event, source_ip, notice_tx);
+        return;
+    }
+    info!(
+        "rejecting event: {:?}, content posted by too many authors (IP: {:?})",
+        event.get_event_id_prefix(),
+        source_ip
+    );
+    notice_tx
+        .try_send(Notice::blocked(
+            event.id,
+            "this content has been posted by too many accounts",
+        ))
+        .ok();
+}
+
+/// Discard an event, while telling the client it was saved.  The
+/// client's own subscriptions are still sent the event.
+fn shadow_reject(
+    check: &str,
+    event: Event,
+    source_ip: &str,
+    notice_tx: &tokio::sync::mpsc::Sender<Notice>,
+) {
+    info!(
+        "{} shadow-rejected event: {:?} (kind: {}) from: {:?} (IP: {:?})",
+        check,
+        event.get_event_id_prefix(),
+        event.kind,
+        event.get_author_prefix(),
+        source_ip
+    );
+    notice_tx.try_send(Notice::shadow_rejected(event)).ok();
 }
 
 /// Serialized event associated with a specific subscription request.
@@ -479,3 +1263,381 @@ pub struct QueryResult {
     /// Serialized event
     pub event: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTHOR: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const OTHER: &str = "35d26e4690cbe1a898af61cc3515661eb5fa763b57bd0b42e45099c8b32fd50f";
+
+    /// Start a database writer on a new database in `dir`.
+    async fn start_writer(
+        settings: &mut Settings,
+        dir: &tempfile::TempDir,
+    ) -> (
+        Arc<dyn NostrRepo>,
+        tokio::sync::mpsc::Sender<SubmittedEvent>,
+        tokio::sync::mpsc::Sender<Moderation>,
+    ) {
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = build_repo(settings, metrics.clone()).await;
+        let ctx = WriterContext {
+            repo: repo.clone(),
+            settings: settings.clone(),
+            bcast_tx: tokio::sync::broadcast::channel(16).0,
+            metadata_tx: tokio::sync::broadcast::channel(16).0,
+            payment_tx: tokio::sync::broadcast::channel(16).0,
+            verifier: VerifyPool::new(&settings.limits),
+            duplicates: DuplicateDetector::new(
+                &settings.duplicate_content,
+                metrics.duplicates_rejected,
+            ),
+            groups: GroupManager::new(settings).unwrap(),
+            admissions: invite::Admissions::default(),
+        };
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(16);
+        let (moderation_tx, moderation_rx) = tokio::sync::mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        tokio::task::spawn(async move {
+            db_writer(ctx, event_rx, moderation_rx, shutdown_rx)
+                .await
+                .ok();
+            drop(shutdown_tx);
+        });
+        (repo, event_tx, moderation_tx)
+    }
+
+    fn submitted(
+        pubkey: &str,
+        n: u64,
+        notice_tx: &tokio::sync::mpsc::Sender<Notice>,
+    ) -> SubmittedEvent {
+        let mut event = Event::simple_event();
+        event.id = format!("{:032x}{:032x}", n, hex_prefix(pubkey));
+        event.pubkey = pubkey.to_owned();
+        event.kind = 1;
+        event.created_at = n;
+        SubmittedEvent {
+            event,
+            notice_tx: notice_tx.clone(),
+            source_ip: "127.0.0.1".to_owned(),
+            origin: None,
+            user_agent: None,
+            auth_pubkey: None,
+        }
+    }
+
+    fn hex_prefix(pubkey: &str) -> u64 {
+        u64::from_str_radix(&pubkey[..8], 16).unwrap()
+    }
+
+    fn result(notice: Notice) -> (String, &'static str) {
+        match notice {
+            Notice::EventResult(res) => (res.id, res.status.prefix()),
+            _ => panic!("expected an event result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn admission_keeps_order_and_balance() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.limits.admission_workers = 4;
+        settings.pay_to_relay.enabled = true;
+        settings.pay_to_relay.cost_per_event = 10;
+        settings.authorization.pubkey_whitelist = Some(vec![OTHER.to_owned()]);
+        let (repo, event_tx, _moderation_tx) = start_writer(&mut settings, &dir).await;
+        let author = Keys::from_pk_str(AUTHOR).unwrap();
+        repo.create_account(&author).await.unwrap();
+        repo.admit_account(&author, 0).await.unwrap();
+        repo.update_account_balance(&author, true, 25)
+            .await
+            .unwrap();
+
+        // events are submitted faster than they are written, by a
+        // paying author and a whitelisted one
+        let (author_tx, mut author_rx) = tokio::sync::mpsc::channel(16);
+        let (other_tx, mut other_rx) = tokio::sync::mpsc::channel(16);
+        for n in 0..5 {
+            event_tx
+                .send(submitted(AUTHOR, n, &author_tx))
+                .await
+                .unwrap();
+            event_tx.send(submitted(OTHER, n, &other_tx)).await.unwrap();
+        }
+        let mut author_results = vec![];
+        let mut other_results = vec![];
+        for _ in 0..5 {
+            author_results.push(result(author_rx.recv().await.unwrap()));
+            other_results.push(result(other_rx.recv().await.unwrap()));
+        }
+        // each author's events are written in the order sent
+        for (n, (id, status)) in other_results.iter().enumerate() {
+            assert_eq!(id, &submitted(OTHER, n as u64, &other_tx).event.id);
+            assert_eq!(status, &"saved");
+        }
+        // the earliest two events are paid for, and the rest refused
+        // (refusals are sent before the writes complete)
+        for n in 0..5 {
+            let id = submitted(AUTHOR, n, &author_tx).event.id;
+            let (_, status) = author_results.iter().find(|(i, _)| i == &id).unwrap();
+            let expected = if n < 2 { "saved" } else { "blocked" };
+            assert_eq!(status, &expected);
+        }
+        // authors are charged just after the OK is sent
+        let mut balance = 0;
+        for _ in 0..50 {
+            balance = repo.get_account_balance(&author).await.unwrap().1;
+            if balance == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(balance, 5);
+    }
+
+    async fn moderate(
+        moderation_tx: &tokio::sync::mpsc::Sender<Moderation>,
+        id: &str,
+        approve: bool,
+    ) -> bool {
+        let (reply, reply_rx) = tokio::sync::oneshot::channel();
+        let id = id.to_owned();
+        moderation_tx
+            .send(Moderation { id, approve, reply })
+            .await
+            .ok();
+        reply_rx.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn new_authors_held_until_approved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.quarantine.enabled = true;
+        settings.quarantine.new_authors = true;
+        settings.authorization.moderators = vec![OTHER.to_owned()];
+        let (_repo, event_tx, moderation_tx) = start_writer(&mut settings, &dir).await;
+        let (notice_tx, mut notice_rx) = tokio::sync::mpsc::channel(16);
+
+        // a new author's events are held
+        for n in 0..3 {
+            event_tx
+                .send(submitted(AUTHOR, n, &notice_tx))
+                .await
+                .unwrap();
+        }
+        // moderators are never held
+        event_tx
+            .send(submitted(OTHER, 0, &notice_tx))
+            .await
+            .unwrap();
+        let mut results = vec![];
+        for _ in 0..4 {
+            results.push(result(notice_rx.recv().await.unwrap()));
+        }
+        let first = submitted(AUTHOR, 0, &notice_tx).event.id;
+        let second = submitted(AUTHOR, 1, &notice_tx).event.id;
+        let moderator = submitted(OTHER, 0, &notice_tx).event.id;
+        assert!(results.contains(&(first.clone(), "pending")));
+        assert!(results.contains(&(second.clone(), "pending")));
+        assert!(results.contains(&(moderator, "saved")));
+
+        // approval is reported to the author, and publishes the event
+        assert!(moderate(&moderation_tx, &first, true).await);
+        assert_eq!(
+            result(notice_rx.recv().await.unwrap()),
+            (first.clone(), "saved")
+        );
+        assert!(!moderate(&moderation_tx, &first, true).await);
+        assert!(moderate(&moderation_tx, &second, false).await);
+        assert!(!moderate(&moderation_tx, &second, true).await);
+
+        // once approved, the author is no longer new
+        event_tx
+            .send(submitted(AUTHOR, 5, &notice_tx))
+            .await
+            .unwrap();
+        let later = submitted(AUTHOR, 5, &notice_tx).event.id;
+        assert_eq!(result(notice_rx.recv().await.unwrap()), (later, "saved"));
+    }
+
+    #[tokio::test]
+    async fn join_requests_admit_authors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.invites.enabled = true;
+        let (repo, event_tx, _moderation_tx) = start_writer(&mut settings, &dir).await;
+        let invite = invite::Invite::new("admin", 1, None);
+        repo.create_invite(&invite).await.unwrap();
+        let (notice_tx, mut notice_rx) = tokio::sync::mpsc::channel(16);
+
+        // authors who are not admitted may not publish
+        let before = submitted(AUTHOR, 0, &notice_tx);
+        let id = before.event.id.clone();
+        event_tx.send(before).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()), (id, "restricted"));
+
+        // but may ask to join
+        let mut join = submitted(AUTHOR, 1, &notice_tx);
+        join.event.kind = invite::JOIN_REQUEST_KIND;
+        join.event.tags = vec![vec!["claim".to_owned(), invite.code.clone()]];
+        let id = join.event.id.clone();
+        event_tx.send(join).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()), (id, "saved"));
+
+        // the cached refusal is replaced by the admission
+        let after = submitted(AUTHOR, 2, &notice_tx);
+        let id = after.event.id.clone();
+        event_tx.send(after).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()), (id, "saved"));
+
+        // the code is used up
+        let mut join = submitted(OTHER, 3, &notice_tx);
+        join.event.kind = invite::JOIN_REQUEST_KIND;
+        join.event.tags = vec![vec!["claim".to_owned(), invite.code]];
+        let id = join.event.id.clone();
+        event_tx.send(join).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()), (id, "restricted"));
+    }
+
+    #[tokio::test]
+    async fn invite_requests_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.invites.enabled = true;
+        settings.invites.member_invites = true;
+        settings.invites.member_invites_per_day = 1;
+        settings.authorization.pubkey_whitelist = Some(vec![AUTHOR.to_owned()]);
+        let (_repo, event_tx, _moderation_tx) = start_writer(&mut settings, &dir).await;
+        let (notice_tx, mut notice_rx) = tokio::sync::mpsc::channel(16);
+        let now = crate::utils::unix_time();
+        let request = |n: u64, created_at: u64, auth: bool| {
+            let mut req = submitted(AUTHOR, n, &notice_tx);
+            req.event.kind = invite::INVITE_REQUEST_KIND;
+            req.event.created_at = created_at;
+            if auth {
+                req.auth_pubkey = Some(hex::decode(AUTHOR).unwrap());
+            }
+            req
+        };
+        // requests must come from an authenticated author
+        event_tx.send(request(0, now, false)).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()).1, "auth-required");
+        // and be recent
+        event_tx.send(request(1, now - 3600, true)).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()).1, "invalid");
+        event_tx.send(request(2, now, true)).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()).1, "saved");
+        // a copy of a request mints nothing
+        event_tx.send(request(2, now, true)).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()).1, "duplicate");
+        // members mint a limited number of codes
+        event_tx.send(request(3, now, true)).await.unwrap();
+        assert_eq!(result(notice_rx.recv().await.unwrap()).1, "rate-limited");
+    }
+
+    #[tokio::test]
+    async fn batch_fallback_keeps_outcomes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = build_repo(&settings, metrics).await;
+        let (notice_tx, _notice_rx) = tokio::sync::mpsc::channel(16);
+        let metadata = |n| {
+            let mut event = submitted(AUTHOR, n, &notice_tx).event;
+            event.kind = 0;
+            event
+        };
+        repo.write_event(&metadata(20)).await.unwrap();
+        // an event that cannot be stored fails the whole batch
+        rusqlite::Connection::open(dir.path().join(DB_FILE))
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER refuse BEFORE INSERT ON event WHEN NEW.content LIKE '%unstorable%' \
+                 BEGIN SELECT RAISE(ABORT, 'refused'); END;",
+            )
+            .unwrap();
+        let mut unstorable = submitted(OTHER, 1, &notice_tx).event;
+        unstorable.content = "unstorable".to_owned();
+        let events = vec![
+            unstorable,
+            metadata(10),
+            submitted(OTHER, 2, &notice_tx).event,
+        ];
+        let outcomes = write_batch(&repo, &events).await;
+        assert!(outcomes[0].is_err());
+        assert_eq!(outcomes[1].as_ref().unwrap(), &WriteOutcome::Blocked);
+        assert_eq!(outcomes[2].as_ref().unwrap(), &WriteOutcome::Saved);
+    }
+
+    #[tokio::test]
+    async fn reservations_limit_balance() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = build_repo(&settings, metrics).await;
+        let author = Keys::from_pk_str(AUTHOR).unwrap();
+        let other = Keys::from_pk_str(OTHER).unwrap();
+        for keys in [&author, &other] {
+            repo.create_account(keys).await.unwrap();
+            repo.update_account_balance(keys, true, 25).await.unwrap();
+        }
+        let reservations = Reservations::default();
+        assert!(reservations.reserve(&repo, &author, 10).await.unwrap());
+        assert!(reservations.reserve(&repo, &author, 10).await.unwrap());
+        assert!(!reservations.reserve(&repo, &author, 10).await.unwrap());
+        // other authors are not affected
+        assert!(reservations.reserve(&repo, &other, 10).await.unwrap());
+        // a charged reservation is taken from the balance, and a
+        // dropped one returned
+        reservations.release(&repo, &author, 10, true).await;
+        reservations.release(&repo, &author, 10, false).await;
+        assert!(reservations.reserve(&repo, &author, 15).await.unwrap());
+        assert!(!reservations.reserve(&repo, &author, 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn concurrent_events_charged_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.limits.admission_workers = 2;
+        settings.pay_to_relay.enabled = true;
+        settings.pay_to_relay.cost_per_event = 10;
+        let (repo, event_tx, _moderation_tx) = start_writer(&mut settings, &dir).await;
+        let author = Keys::from_pk_str(AUTHOR).unwrap();
+        repo.create_account(&author).await.unwrap();
+        repo.admit_account(&author, 0).await.unwrap();
+        repo.update_account_balance(&author, true, 10)
+            .await
+            .unwrap();
+
+        // two events at once, from a balance that pays for one
+        let (notice_tx, mut notice_rx) = tokio::sync::mpsc::channel(16);
+        let (first, second) = tokio::join!(
+            event_tx.send(submitted(AUTHOR, 0, &notice_tx)),
+            event_tx.send(submitted(AUTHOR, 1, &notice_tx)),
+        );
+        first.unwrap();
+        second.unwrap();
+        let mut statuses = vec![
+            result(notice_rx.recv().await.unwrap()).1,
+            result(notice_rx.recv().await.unwrap()).1,
+        ];
+        statuses.sort_unstable();
+        assert_eq!(statuses, vec!["blocked", "saved"]);
+        let mut balance = 10;
+        for _ in 0..50 {
+            balance = repo.get_account_balance(&author).await.unwrap().1;
+            if balance == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(balance, 0);
+    }
+}
@@ -0,0 +1,561 @@
+//! WebSocket permessage-deflate compression (RFC 7692)
+//!
+//! Compression is negotiated without context takeover in either
+//! direction, so each message is compressed on its own.  Outgoing
+//! messages are compressed by a [`MessageDeflater`] before they are
+//! written.  Incoming compressed messages are inflated by
+//! [`DeflateStream`], which sits between the connection and the
+//! websocket protocol handling, so that message size limits apply to
+//! the inflated messages.
+use crate::config::Network;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use http::header::HeaderMap;
+use prometheus::IntCounterVec;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::Frame;
+use tungstenite::protocol::Message;
+
+/// Bytes removed from the end of each compressed message
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Output is grown by this much while compressing or inflating
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The extension response for an accepted offer
+pub const DEFLATE_RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Does the client offer permessage-deflate with parameters we can
+/// accept?
+#[must_use]
+pub fn offer_acceptable(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("sec-websocket-extensions")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            params.next() == Some("permessage-deflate") && params.all(param_acceptable)
+        })
+}
+
+/// Parameters of a permessage-deflate offer that we can agree to.
+/// Smaller server windows are not supported.
+fn param_acceptable(param: &str) -> bool {
+    let (name, value) = match param.split_once('=') {
+        Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+        None => (param, None),
+    };
+    match name {
+        "server_no_context_takeover" | "client_no_context_takeover" => value.is_none(),
+        // we can always inflate with the largest window
+        "client_max_window_bits" => true,
+        "server_max_window_bits" => value == Some("15"),
+        _ => false,
+    }
+}
+
+/// Compress a message payload, without the trailing empty block.
+fn deflate(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut c = Compress::new(Compression::new(level), false);
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        if out.capacity() - out.len() < 64 {
+            out.reserve(CHUNK_SIZE);
+        }
+        let consumed = c.total_in() as usize;
+        c.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // a full output buffer may mean more is pending
+        if c.total_in() as usize == data.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+    if out.ends_with(&DEFLATE_TRAILER) {
+        out.truncate(out.len() - DEFLATE_TRAILER.len());
+    }
+    Ok(out)
+}
+
+/// Inflate a message payload.  Inflation stops once the output is
+/// larger than `limit`, so oversized messages can still be detected
+/// without inflating all of them.
+fn inflate(data: &[u8], limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut d = Decompress::new(false);
+    let mut input = Vec::with_capacity(data.len() + DEFLATE_TRAILER.len());
+    input.extend_from_slice(data);
+    input.extend_from_slice(&DEFLATE_TRAILER);
+    let mut out = Vec::with_capacity(data.len() * 4);
+    loop {
+        if let Some(l) = limit {
+            if out.len() > l {
+                out.truncate(l + 1);
+                break;
+            }
+        }
+        if out.capacity() - out.len() < 64 {
+            out.reserve(CHUNK_SIZE);
+        }
+        let (in_before, out_before) = (d.total_in(), out.len());
+        let status = d
+            .decompress_vec(
+                &input[in_before as usize..],
+                &mut out,
+                FlushDecompress::Sync,
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let input_done = d.total_in() as usize == input.len();
+        if status == Status::StreamEnd || (input_done && out.len() < out.capacity()) {
+            break;
+        }
+        if d.total_in() == in_before && out.len() == out_before {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated compressed message",
+            ));
+        }
+    }
+    Ok(out)
+}
+
+/// Compresses outgoing messages on a connection that negotiated
+/// permessage-deflate.
+pub struct MessageDeflater {
+    level: u32,
+    min_bytes: usize,
+    raw_bytes: IntCounterVec,
+    compressed_bytes: IntCounterVec,
+}
+
+impl MessageDeflater {
+    #[must_use]
+    pub fn new(
+        network: &Network,
+        raw_bytes: IntCounterVec,
+        compressed_bytes: IntCounterVec,
+    ) -> MessageDeflater {
+        MessageDeflater {
+            level: network.compression_level.min(9),
+            min_bytes: network.compression_min_bytes,
+            raw_bytes,
+            compressed_bytes,
+        }
+    }
+
+    /// Compress a message, if it is large enough to be worthwhile.
+    #[must_use]
+    pub fn encode(&self, msg: Message) -> Message {
+        let (payload, opcode) = match &msg {
+            Message::Text(t) if t.len() >= self.min_bytes => (t.as_bytes(), Data::Text),
+            Message::Binary(b) if b.len() >= self.min_bytes => (b.as_slice(), Data::Binary),
+            _ => return msg,
+        };
+        match deflate(payload, self.level) {
+            Ok(compressed) if compressed.len() < payload.len() => {
+                self.raw_bytes
+                    .with_label_values(&["sent"])
+                    .inc_by(payload.len() as u64);
+                self.compressed_bytes
+                    .with_label_values(&["sent"])
+                    .inc_by(compressed.len() as u64);
+                let mut frame = Frame::message(compressed, OpCode::Data(opcode), true);
+                frame.header_mut().rsv1 = true;
+                Message::Frame(frame)
+            }
+            _ => msg,
+        }
+    }
+}
+
+/// A compressed message being received
+struct CompressedMessage {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// A client connection that inflates compressed messages, presenting
+/// them as uncompressed frames.  Everything else is passed through.
+pub struct DeflateStream<S> {
+    inner: S,
+    /// Whether permessage-deflate was negotiated
+    enabled: bool,
+    max_message_bytes: Option<usize>,
+    max_frame_bytes: Option<usize>,
+    /// Bytes read from the client, not yet processed
+    input: Vec<u8>,
+    /// Bytes ready to be read by the websocket
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Payload bytes of an uncompressed frame still to pass through
+    passthrough: u64,
+    compressed: Option<CompressedMessage>,
+    eof: bool,
+    raw_bytes: IntCounterVec,
+    compressed_bytes: IntCounterVec,
+}
+
+impl<S> DeflateStream<S> {
+    #[must_use]
+    pub fn new(
+        inner: S,
+        enabled: bool,
+        max_message_bytes: Option<usize>,
+        max_frame_bytes: Option<usize>,
+        raw_bytes: IntCounterVec,
+        compressed_bytes: IntCounterVec,
+    ) -> DeflateStream<S> {
+        DeflateStream {
+            inner,
+            enabled,
+            max_message_bytes,
+            max_frame_bytes,
+            input: vec![],
+            output: vec![],
+            output_pos: 0,
+            passthrough: 0,
+            compressed: None,
+            eof: false,
+            raw_bytes,
+            compressed_bytes,
+        }
+    }
+
+    /// Move passthrough payload bytes to the output.
+    fn pass_payload(&mut self) {
+        let n = (self.passthrough.min(self.input.len() as u64)) as usize;
+        self.output.extend(self.input.drain(..n));
+        self.passthrough -= n as u64;
+    }
+
+    /// Process one frame from the input.  Returns false if more input
+    /// is needed.
+    fn process_frame(&mut self) -> io::Result<bool> {
+        if self.passthrough > 0 {
+            if self.input.is_empty() {
+                return Ok(false);
+            }
+            self.pass_payload();
+            return Ok(true);
+        }
+        let Some((header_len, payload_len, mask)) = parse_header(&self.input) else {
+            return Ok(false);
+        };
+        let b0 = self.input[0];
+        let rsv1 = b0 & 0x40 != 0;
+        let opcode = b0 & 0x0f;
+        let fin = b0 & 0x80 != 0;
+        let starts_compressed = rsv1 && (opcode == 0x1 || opcode == 0x2);
+        let continues_compressed = opcode == 0x0 && self.compressed.is_some();
+        // only the first frame of a message is marked as compressed
+        if rsv1 && !starts_compressed {
+            return Err(protocol_error(
+                "RSV1 set on a frame that does not start a message",
+            ));
+        }
+        // the websocket checks the masking of frames passed through,
+        // but not of those inflated here, which are re-masked.
+        if (starts_compressed || continues_compressed) && mask.is_none() {
+            return Err(protocol_error("client frames must be masked"));
+        }
+        if !starts_compressed && !continues_compressed {
+            // pass the frame through untouched
+            self.output.extend(self.input.drain(..header_len));
+            self.passthrough = payload_len;
+            self.pass_payload();
+            return Ok(true);
+        }
+        // compressed frames are collected whole.  Compressed data is
+        // never much larger than the message it holds.
+        let limit = self
+            .max_message_bytes
+            .map_or(usize::MAX, |l| l.saturating_add(l / 64 + 64));
+        let collected = self.compressed.as_ref().map_or(0, |c| c.payload.len());
+        if payload_len > (limit.saturating_sub(collected)) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed message exceeds maximum size",
+            ));
+        }
+        let frame_len = header_len + payload_len as usize;
+        if self.input.len() < frame_len {
+            return Ok(false);
+        }
+        let mut payload: Vec<u8> = self.input.drain(..frame_len).skip(header_len).collect();
+        if let Some(key) = mask {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= key[i % 4];
+            }
+        }
+        let message = self.compressed.get_or_insert(CompressedMessage {
+            opcode,
+            payload: vec![],
+        });
+        message.payload.extend_from_slice(&payload);
+        if fin {
+            if let Some(message) = self.compressed.take() {
+                let inflated = inflate(&message.payload, self.max_message_bytes)?;
+                self.compressed_bytes
+                    .with_label_values(&["received"])
+                    .inc_by(message.payload.len() as u64);
+                self.raw_bytes
+                    .with_label_values(&["received"])
+                    .inc_by(inflated.len() as u64);
+                write_frames(
+                    &mut self.output,
+                    message.opcode,
+                    &inflated,
+                    self.max_frame_bytes,
+                );
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("protocol error: {msg}"))
+}
+
+/// Parse a frame header, returning its length, the payload length,
+/// and the masking key.
+fn parse_header(buf: &[u8]) -> Option<(usize, u64, Option<[u8; 4]>)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (mut pos, payload_len) = match buf[1] & 0x7f {
+        126 => {
+            let b = buf.get(2..4)?;
+            (4, u64::from(u16::from_be_bytes([b[0], b[1]])))
+        }
+        127 => {
+            let b = buf.get(2..10)?;
+            (10, u64::from_be_bytes(b.try_into().ok()?))
+        }
+        n => (2, u64::from(n)),
+    };
+    let mask = if masked {
+        let m = buf.get(pos..pos + 4)?;
+        pos += 4;
+        Some([m[0], m[1], m[2], m[3]])
+    } else {
+        None
+    };
+    Some((pos, payload_len, mask))
+}
+
+/// Write an inflated message as frames no larger than `max_frame`.
+/// Frames are marked as masked with an all-zero key, as a client's
+/// frames must be.
+fn write_frames(out: &mut Vec<u8>, opcode: u8, payload: &[u8], max_frame: Option<usize>) {
+    let chunk_size = max_frame.unwrap_or(usize::MAX).max(1);
+    let mut chunks = payload.chunks(chunk_size).peekable();
+    let mut first = true;
+    if payload.is_empty() {
+        write_frame(out, true, opcode, &[]);
+        return;
+    }
+    while let Some(chunk) = chunks.next() {
+        let fin = chunks.peek().is_none();
+        write_frame(out, fin, if first { opcode } else { 0x0 }, chunk);
+        first = false;
+    }
+}
+
+fn write_frame(out: &mut Vec<u8>, fin: bool, opcode: u8, payload: &[u8]) {
+    out.push(if fin { 0x80 } else { 0 } | opcode);
+    let len = payload.len();
+    if len < 126 {
+        out.push(0x80 | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(0x80 | 126);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0x80 | 127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(payload);
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.output_pos < this.output.len() {
+                let n = (this.output.len() - this.output_pos).min(buf.remaining());
+                buf.put_slice(&this.output[this.output_pos..this.output_pos + n]);
+                this.output_pos += n;
+                if this.output_pos == this.output.len() {
+                    this.output.clear();
+                    this.output_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.process_frame()? {
+                continue;
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => {
+                    if chunk_buf.filled().is_empty() {
+                        // any partial frame is passed on, for the
+                        // websocket to report.
+                        this.eof = true;
+                        this.output.append(&mut this.input);
+                    } else {
+                        this.input.extend_from_slice(chunk_buf.filled());
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+    use prometheus::Opts;
+    use tokio::io::AsyncReadExt;
+
+    fn counter() -> IntCounterVec {
+        IntCounterVec::new(Opts::new("test_bytes", "test"), &["direction"]).unwrap()
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut headers = HeaderMap::new();
+        assert!(!offer_acceptable(&headers));
+        headers.insert(
+            "sec-websocket-extensions",
+            HeaderValue::from_static("permessage-deflate; server_max_window_bits=10"),
+        );
+        assert!(!offer_acceptable(&headers));
+        headers.insert(
+            "sec-websocket-extensions",
+            HeaderValue::from_static(
+                "permessage-deflate; server_max_window_bits=10, permessage-deflate; client_max_window_bits",
+            ),
+        );
+        assert!(offer_acceptable(&headers));
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = "nostr ".repeat(1000);
+        let compressed = deflate(data.as_bytes(), 6).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(inflate(&compressed, None).unwrap(), data.as_bytes());
+        // inflation stops just past the limit
+        assert_eq!(inflate(&compressed, Some(100)).unwrap().len(), 101);
+    }
+
+    #[tokio::test]
+    async fn inflates_client_frames() {
+        let text = "hello ".repeat(100);
+        let compressed = deflate(text.as_bytes(), 6).unwrap();
+        // a masked, compressed text frame followed by an
+        // uncompressed ping
+        let key = [1, 2, 3, 4];
+        let mut input = vec![0x80 | 0x40 | 0x1, 0x80 | 126];
+        input.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+        input.extend_from_slice(&key);
+        input.extend(compressed.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        input.extend_from_slice(&[0x89, 0x80, 0, 0, 0, 0]);
+        let mut stream = DeflateStream::new(
+            input.as_slice(),
+            true,
+            None,
+            Some(256),
+            counter(),
+            counter(),
+        );
+        let mut out = vec![];
+        stream.read_to_end(&mut out).await.unwrap();
+        let mut expected = vec![];
+        write_frames(&mut expected, 0x1, text.as_bytes(), Some(256));
+        expected.extend_from_slice(&[0x89, 0x80, 0, 0, 0, 0]);
+        assert_eq!(out, expected);
+        // the message was split into frames no larger than the limit
+        assert_eq!(parse_header(&out), Some((8, 256, Some([0, 0, 0, 0]))));
+    }
+
+    /// Read client input through an inflating stream.
+    async fn read_client(input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = DeflateStream::new(input, true, None, None, counter(), counter());
+        let mut out = vec![];
+        stream.read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn rsv1_only_on_first_frame() {
+        let compressed = deflate(b"hello", 6).unwrap();
+        let (first, rest) = compressed.split_at(2);
+        // a compressed text frame, continued by a frame also marked
+        // as compressed
+        let mut input = vec![0x40 | 0x1, 0x80 | first.len() as u8, 0, 0, 0, 0];
+        input.extend_from_slice(first);
+        input.extend_from_slice(&[0x80 | 0x40, 0x80 | rest.len() as u8, 0, 0, 0, 0]);
+        input.extend_from_slice(rest);
+        let err = read_client(&input).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // nor on control frames
+        let err = read_client(&[0x80 | 0x40 | 0x9, 0x80, 0, 0, 0, 0])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_must_be_masked() {
+        let compressed = deflate(b"hello", 6).unwrap();
+        let mut input = vec![0x80 | 0x40 | 0x1, compressed.len() as u8];
+        input.extend_from_slice(&compressed);
+        let err = read_client(&input).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // the same frame, masked, is inflated
+        let mut input = vec![0x80 | 0x40 | 0x1, 0x80 | compressed.len() as u8, 0, 0, 0, 0];
+        input.extend_from_slice(&compressed);
+        let mut expected = vec![];
+        write_frames(&mut expected, 0x1, b"hello", None);
+        assert_eq!(read_client(&input).await.unwrap(), expected);
+    }
+}
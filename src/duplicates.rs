@@ -0,0 +1,275 @@
+//! Detection of the same content posted by many authors
+//!
+//! Spam bots repost identical text from many fresh keys.  Content is
+//! normalized (ignoring case, whitespace and punctuation) and hashed,
+//! and the distinct authors posting each fingerprint are counted over
+//! a sliding window.  Once too many authors have posted a fingerprint,
+//! events from further authors are rejected.
+use crate::config::DuplicateContent;
+use crate::event::Event;
+use prometheus::IntCounter;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often to forget fingerprints outside the window
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Characters of content kept to show what a fingerprint is
+const SAMPLE_CHARS: usize = 80;
+
+/// Normalize content so that trivial variations match: only letters
+/// and digits are kept, in lower case.
+fn normalize(content: &str) -> String {
+    content
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn fingerprint(normalized: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    normalized.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Authors seen posting one fingerprint
+struct Seen {
+    /// When each author last posted it
+    authors: HashMap<String, Instant>,
+    /// Events rejected because of it
+    rejected: u64,
+    sample: String,
+}
+
+impl Seen {
+    /// Forget authors outside the window, returning false if none
+    /// are left.
+    fn expire(&mut self, window: Duration) -> bool {
+        self.authors.retain(|_, t| t.elapsed() < window);
+        !self.authors.is_empty()
+    }
+}
+
+/// A fingerprint posted by many authors
+#[derive(Debug, Serialize)]
+pub struct Offender {
+    pub fingerprint: String,
+    pub authors: usize,
+    pub rejected: u64,
+    pub sample: String,
+}
+
+struct State {
+    seen: HashMap<u64, Seen>,
+    last_sweep: Instant,
+}
+
+/// Counts the authors posting each fingerprint.  Shared by every
+/// admission worker.
+#[derive(Clone)]
+pub struct DuplicateDetector {
+    settings: DuplicateContent,
+    state: Arc<Mutex<State>>,
+    rejected: IntCounter,
+}
+
+impl DuplicateDetector {
+    #[must_use]
+    pub fn new(settings: &DuplicateContent, rejected: IntCounter) -> DuplicateDetector {
+        DuplicateDetector {
+            settings: settings.clone(),
+            state: Arc::new(Mutex::new(State {
+                seen: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+            rejected,
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.settings.window_seconds)
+    }
+
+    /// The fingerprint of an event's content, if it is tracked.
+    fn key(&self, event: &Event) -> Option<u64> {
+        if !self.settings.enabled || !self.settings.kinds.contains(&event.kind) {
+            return None;
+        }
+        let normalized = normalize(&event.content);
+        if normalized.chars().count() < self.settings.min_length {
+            return None;
+        }
+        Some(fingerprint(&normalized))
+    }
+
+    /// Check an event's content, returning false if too many other
+    /// authors have already posted it.  The author is not counted
+    /// until the event is [recorded](DuplicateDetector::record).
+    pub fn admit(&self, event: &Event) -> bool {
+        let Some(key) = self.key(event) else {
+            return true;
+        };
+        let window = self.window();
+        let mut state = self.state.lock().unwrap();
+        if state.last_sweep.elapsed() > SWEEP_INTERVAL {
+            state.last_sweep = Instant::now();
+            state.seen.retain(|_, s| s.expire(window));
+        }
+        match state.seen.get_mut(&key) {
+            Some(seen) => self.permits(seen, event, window),
+            None => true,
+        }
+    }
+
+    /// Count the author of an admitted event as having posted its
+    /// content.  Other events with the content may have been recorded
+    /// since it was admitted, so the limit is checked again; returns
+    /// false, without counting the author, if it has been reached.
+    #[must_use]
+    pub fn record(&self, event: &Event) -> bool {
+        let Some(key) = self.key(event) else {
+            return true;
+        };
+        let window = self.window();
+        let mut state = self.state.lock().unwrap();
+        if !state.seen.contains_key(&key) && state.seen.len() >= self.settings.max_fingerprints {
+            // too much content to track; admit rather than use more
+            // memory.
+            return true;
+        }
+        let seen = state.seen.entry(key).or_insert_with(|| Seen {
+            authors: HashMap::new(),
+            rejected: 0,
+            sample: event.content.chars().take(SAMPLE_CHARS).collect(),
+        });
+        if !self.permits(seen, event, window) {
+            return false;
+        }
+        seen.authors.insert(event.pubkey.clone(), Instant::now());
+        true
+    }
+
+    /// May the event's author post content others have posted?
+    /// Rejections are counted.
+    fn permits(&self, seen: &mut Seen, event: &Event, window: Duration) -> bool {
+        seen.expire(window);
+        if !seen.authors.contains_key(&event.pubkey)
+            && seen.authors.len() >= self.settings.max_authors
+        {
+            seen.rejected += 1;
+            self.rejected.inc();
+            return false;
+        }
+        true
+    }
+
+    /// The fingerprints posted by the most authors within the window
+    #[must_use]
+    pub fn top_offenders(&self, count: usize) -> Vec<Offender> {
+        let window = self.window();
+        let mut state = self.state.lock().unwrap();
+        state.seen.retain(|_, s| s.expire(window));
+        let mut offenders: Vec<Offender> = state
+            .seen
+            .iter()
+            .filter(|(_, s)| s.authors.len() > 1)
+            .map(|(k, s)| Offender {
+                fingerprint: format!("{k:016x}"),
+                authors: s.authors.len(),
+                rejected: s.rejected,
+                sample: s.sample.clone(),
+            })
+            .collect();
+        offenders.sort_by_key(|o| std::cmp::Reverse((o.authors, o.rejected)));
+        offenders.truncate(count);
+        offenders
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(pubkey: &str, content: &str) -> Event {
+        let mut e = Event::simple_event();
+        e.kind = 1;
+        e.pubkey = pubkey.to_owned();
+        e.content = content.to_owned();
+        e
+    }
+
+    fn detector() -> DuplicateDetector {
+        let settings = DuplicateContent {
+            enabled: true,
+            kinds: vec![1],
+            window_seconds: 600,
+            max_authors: 2,
+            min_length: 10,
+            max_fingerprints: 100,
+        };
+        DuplicateDetector::new(&settings, IntCounter::new("test", "test").unwrap())
+    }
+
+    /// Check an event, recording it if it is admitted.
+    fn admit_and_record(d: &DuplicateDetector, event: &Event) -> bool {
+        d.admit(event) && d.record(event)
+    }
+
+    #[test]
+    fn distinct_authors_limited() {
+        let d = detector();
+        let spam = "Claim your FREE tokens now!";
+        assert!(admit_and_record(&d, &event("a", spam)));
+        assert!(admit_and_record(
+            &d,
+            &event("b", "claim your free tokens now")
+        ));
+        // the same authors may post it again
+        assert!(admit_and_record(&d, &event("a", spam)));
+        assert!(!admit_and_record(
+            &d,
+            &event("c", "claim  your free tokens... NOW")
+        ));
+        // short content is not tracked
+        for pk in ["c", "d", "e"] {
+            assert!(admit_and_record(&d, &event(pk, "gm!")));
+        }
+        let top = d.top_offenders(10);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].authors, 2);
+        assert_eq!(top[0].rejected, 1);
+        assert_eq!(top[0].sample, spam);
+    }
+
+    #[test]
+    fn unrecorded_authors_not_counted() {
+        let d = detector();
+        let spam = "Claim your FREE tokens now!";
+        // events rejected by later checks are never recorded
+        for pk in ["a", "b", "c"] {
+            assert!(d.admit(&event(pk, spam)));
+        }
+        assert!(admit_and_record(&d, &event("d", spam)));
+        assert!(admit_and_record(&d, &event("e", spam)));
+        assert!(!d.admit(&event("f", spam)));
+    }
+
+    #[test]
+    fn limit_checked_when_recorded() {
+        let d = detector();
+        let spam = "Claim your FREE tokens now!";
+        // admitted at the same time, before any was recorded
+        for pk in ["a", "b", "c"] {
+            assert!(d.admit(&event(pk, spam)));
+        }
+        assert!(d.record(&event("a", spam)));
+        assert!(d.record(&event("b", spam)));
+        assert!(!d.record(&event("c", spam)));
+        assert_eq!(d.top_offenders(10)[0].authors, 2);
+    }
+}
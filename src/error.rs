@@ -37,7 +37,7 @@ pub enum Error {
     #[error("JSON parsing failed")]
     JsonParseFailed(serde_json::Error),
     #[error("WebSocket proto error")]
-    WebsocketError(WsError),
+    WebsocketError(Box<WsError>),
     #[error("Command unknown")]
     CommandUnknownError,
     #[error("SQL error")]
@@ -67,7 +67,7 @@ pub enum Error {
     #[error("Authz error")]
     AuthzError,
     #[error("Tonic GRPC error")]
-    TonicError(tonic::Status),
+    TonicError(Box<tonic::Status>),
     #[error("Invalid AUTH message")]
     AuthFailure,
     #[error("I/O Error")]
@@ -140,7 +140,7 @@ impl From<serde_json::Error> for Error {
 impl From<WsError> for Error {
     /// Wrap Websocket error
     fn from(r: WsError) -> Self {
-        Error::WebsocketError(r)
+        Error::WebsocketError(Box::new(r))
     }
 }
 
@@ -154,7 +154,7 @@ impl From<config::ConfigError> for Error {
 impl From<tonic::Status> for Error {
     /// Wrap Config error
     fn from(r: tonic::Status) -> Self {
-        Error::TonicError(r)
+        Error::TonicError(Box::new(r))
     }
 }
 
@@ -1,4 +1,5 @@
 //! Event parsing and validation
+use crate::config::KindLimits;
 use crate::delegation::validate_delegation;
 use crate::error::Error::{
     CommandUnknownError, EventCouldNotCanonicalize, EventInvalidId, EventInvalidSignature,
@@ -37,6 +38,11 @@ impl EventCmd {
     pub fn event_id(&self) -> &str {
         &self.event.id
     }
+
+    #[must_use]
+    pub fn cmd(&self) -> &str {
+        &self.cmd
+    }
 }
 
 /// Parsed nostr event.
@@ -315,12 +321,75 @@ impl Event {
             .collect()
     }
 
+    /// Check the event against limits for its kind, describing the
+    /// first one exceeded.
     #[must_use]
-    pub fn is_valid_timestamp(&self, reject_future_seconds: Option<usize>) -> bool {
+    pub fn limit_violation(&self, limits: &KindLimits) -> Option<String> {
+        let kind = self.kind;
+        if let Some(max) = limits.max_content_bytes {
+            let len = self.content.len();
+            if len > max {
+                return Some(format!(
+                    "content is too large for kind {kind} ({len} > {max} bytes)"
+                ));
+            }
+        }
+        if let Some(max) = limits.max_tags {
+            let count = self.tags.len();
+            if count > max {
+                return Some(format!("too many tags for kind {kind} ({count} > {max})"));
+            }
+        }
+        if let Some(max) = limits.max_tag_value_bytes {
+            if let Some(len) = self.tags.iter().flatten().map(String::len).max() {
+                if len > max {
+                    return Some(format!(
+                        "tag value is too large for kind {kind} ({len} > {max} bytes)"
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// NIP-13 difficulty, the number of leading zero bits in the event id.
+    #[must_use]
+    pub fn pow_difficulty(&self) -> u32 {
+        let mut bits = 0;
+        for c in self.id.chars() {
+            match c.to_digit(16) {
+                Some(0) => bits += 4,
+                // a nibble has 28 leading zero bits more as a u32
+                Some(n) => {
+                    bits += n.leading_zeros() - 28;
+                    break;
+                }
+                None => break,
+            }
+        }
+        bits
+    }
+
+    /// Target difficulty committed to in the `nonce` tag, if present.
+    #[must_use]
+    pub fn pow_target(&self) -> Option<u32> {
+        self.tags
+            .iter()
+            .filter(|x| x.len() > 2)
+            .find(|x| x.first().unwrap() == "nonce")
+            .and_then(|x| x.get(2).unwrap().parse::<u32>().ok())
+    }
+
+    #[must_use]
+    pub fn is_valid_timestamp(
+        &self,
+        reject_future_seconds: Option<usize>,
+        reject_past_seconds: Option<usize>,
+    ) -> bool {
+        let curr_time = unix_time();
         if let Some(allowable_future) = reject_future_seconds {
-            let curr_time = unix_time();
             // calculate difference, plus how far future we allow
-            if curr_time + (allowable_future as u64) < self.created_at {
+            if curr_time.saturating_add(allowable_future as u64) < self.created_at {
                 let delta = self.created_at - curr_time;
                 debug!(
                     "event is too far in the future ({} seconds), rejecting",
@@ -329,6 +398,17 @@ impl Event {
                 return false;
             }
         }
+        if let Some(allowable_past) = reject_past_seconds {
+            // calculate difference, minus how far past we allow
+            if self.created_at.saturating_add(allowable_past as u64) < curr_time {
+                let delta = curr_time - self.created_at;
+                debug!(
+                    "event is too far in the past ({} seconds), rejecting",
+                    delta
+                );
+                return false;
+            }
+        }
         true
     }
 
@@ -718,6 +798,119 @@ mod tests {
         assert_eq!(event.distinct_param(), Some("".to_string()));
     }
 
+    #[test]
+    fn kind_limits_unset() {
+        let mut event = Event::simple_event();
+        event.content = "x".repeat(10_000);
+        assert_eq!(event.limit_violation(&KindLimits::default()), None);
+    }
+
+    #[test]
+    fn kind_limits_content() {
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        event.content = "x".repeat(11);
+        let limits = KindLimits {
+            max_content_bytes: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            event.limit_violation(&limits),
+            Some("content is too large for kind 1 (11 > 10 bytes)".to_owned())
+        );
+        event.content = "x".repeat(10);
+        assert_eq!(event.limit_violation(&limits), None);
+    }
+
+    #[test]
+    fn kind_limits_tags() {
+        let mut event = Event::simple_event();
+        event.kind = 3;
+        event.tags = vec![
+            vec!["p".to_owned(), "aaaa".to_owned()],
+            vec!["p".to_owned(), "bbbbbbbb".to_owned()],
+        ];
+        let limits = KindLimits {
+            max_tags: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            event.limit_violation(&limits),
+            Some("too many tags for kind 3 (2 > 1)".to_owned())
+        );
+        let limits = KindLimits {
+            max_tag_value_bytes: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            event.limit_violation(&limits),
+            Some("tag value is too large for kind 3 (8 > 4 bytes)".to_owned())
+        );
+    }
+
+    #[test]
+    fn timestamp_unbounded() {
+        let mut event = Event::simple_event();
+        event.created_at = 0;
+        assert!(event.is_valid_timestamp(None, None));
+        event.created_at = unix_time() + 1_000_000;
+        assert!(event.is_valid_timestamp(None, None));
+    }
+
+    #[test]
+    fn timestamp_future() {
+        let mut event = Event::simple_event();
+        event.created_at = unix_time() + 600;
+        assert!(event.is_valid_timestamp(Some(1800), None));
+        event.created_at = unix_time() + 3600;
+        assert!(!event.is_valid_timestamp(Some(1800), None));
+    }
+
+    #[test]
+    fn timestamp_past() {
+        let mut event = Event::simple_event();
+        event.created_at = unix_time() - 600;
+        assert!(event.is_valid_timestamp(None, Some(3600)));
+        event.created_at = unix_time() - 7200;
+        assert!(!event.is_valid_timestamp(None, Some(3600)));
+        // the future limit does not affect old events
+        assert!(event.is_valid_timestamp(Some(1800), None));
+    }
+
+    #[test]
+    fn timestamp_far_future() {
+        let mut event = Event::simple_event();
+        event.created_at = u64::MAX;
+        assert!(event.is_valid_timestamp(None, Some(3600)));
+        assert!(!event.is_valid_timestamp(Some(1800), Some(3600)));
+    }
+
+    #[test]
+    fn pow_difficulty_leading_zeros() {
+        let mut event = Event::simple_event();
+        event.id = "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358".to_owned();
+        assert_eq!(event.pow_difficulty(), 21);
+        event.id = "8000000000000000000000000000000000000000000000000000000000000000".to_owned();
+        assert_eq!(event.pow_difficulty(), 0);
+        event.id = "0".repeat(64);
+        assert_eq!(event.pow_difficulty(), 256);
+    }
+
+    #[test]
+    fn pow_target_from_nonce() {
+        let mut event = Event::simple_event();
+        assert_eq!(event.pow_target(), None);
+        // a nonce without a committed target
+        event.tags = vec![vec!["nonce".to_owned(), "776797".to_owned()]];
+        assert_eq!(event.pow_target(), None);
+        event.tags = vec![vec![
+            "nonce".to_owned(),
+            "776797".to_owned(),
+            "20".to_owned(),
+        ]];
+        assert_eq!(event.pow_target(), Some(20));
+    }
+
     #[test]
     fn expiring_event_none() {
         // regular events do not expire
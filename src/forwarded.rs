@@ -0,0 +1,150 @@
+//! Client addresses reported by HTTP proxies
+//!
+//! Proxies append the address they received a request from to
+//! `X-Forwarded-For` (or `Forwarded`), so the header holds a chain of
+//! addresses with the nearest hop last.  Only entries added by trusted
+//! proxies can be believed, so the chain is read from the right,
+//! stopping at the first address that is not a trusted proxy.
+use crate::cidr::{self, Cidr};
+use hyper::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Determine the client address from a forwarding header, given the
+/// address of the socket peer.  The header is ignored unless the peer
+/// is a trusted proxy.  With no trusted proxies, every peer is
+/// trusted, and the nearest hop is believed.
+#[must_use]
+pub fn client_ip(headers: &HeaderMap, header: &str, trusted: &[Cidr], peer: IpAddr) -> IpAddr {
+    if !trusted.is_empty() && !cidr::any_contains(trusted, &peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in hops(headers, header).into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !cidr::any_contains(trusted, &ip) {
+                    break;
+                }
+            }
+            // an obfuscated or garbled entry; the hop that added it
+            // is the furthest we know about.
+            None => break,
+        }
+    }
+    client
+}
+
+/// Addresses in every instance of the header, in order.  Entries that
+/// are not addresses are `None`.
+fn hops(headers: &HeaderMap, header: &str) -> Vec<Option<IpAddr>> {
+    let forwarded = header.eq_ignore_ascii_case("forwarded");
+    headers
+        .get_all(header)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|entry| {
+            if forwarded {
+                forwarded_for(entry).and_then(parse_addr)
+            } else {
+                parse_addr(entry)
+            }
+        })
+        .collect()
+}
+
+/// The `for` parameter of a `Forwarded` element, such as
+/// `for="[2001:db8::1]:4711";proto=https`.
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim().eq_ignore_ascii_case("for").then_some(value)
+    })
+}
+
+/// Parse an address, which may be quoted, and may have a port.
+fn parse_addr(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // a bracketed IPv6 address without a port
+    s.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .and_then(|s| s.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for v in values {
+            h.append(name, HeaderValue::from_static(v));
+        }
+        h
+    }
+
+    #[test]
+    fn chain_read_from_right() {
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let h = headers("x-forwarded-for", &["6.6.6.6, 203.0.113.5", "10.0.0.2"]);
+        assert_eq!(
+            client_ip(&h, "x-forwarded-for", &trusted, ip("10.0.0.1")),
+            ip("203.0.113.5")
+        );
+        // only trusted proxies are believed
+        assert_eq!(
+            client_ip(&h, "x-forwarded-for", &trusted, ip("198.51.100.1")),
+            ip("198.51.100.1")
+        );
+        // every hop is a trusted proxy
+        let h = headers("x-forwarded-for", &["10.1.1.1, 10.0.0.2"]);
+        assert_eq!(
+            client_ip(&h, "x-forwarded-for", &trusted, ip("10.0.0.1")),
+            ip("10.1.1.1")
+        );
+    }
+
+    #[test]
+    fn untrusted_configuration() {
+        let h = headers("x-forwarded-for", &["6.6.6.6, 203.0.113.5"]);
+        assert_eq!(
+            client_ip(&h, "x-forwarded-for", &[], ip("10.0.0.1")),
+            ip("203.0.113.5")
+        );
+        let h = HeaderMap::new();
+        assert_eq!(
+            client_ip(&h, "x-forwarded-for", &[], ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let h = headers(
+            "forwarded",
+            &["for=192.0.2.60;proto=http, for=\"[2001:db8:cafe::17]:4711\";by=10.0.0.2"],
+        );
+        assert_eq!(
+            client_ip(&h, "forwarded", &trusted, ip("10.0.0.1")),
+            ip("2001:db8:cafe::17")
+        );
+        let h = headers("forwarded", &["for=192.0.2.60, for=_hidden"]);
+        assert_eq!(
+            client_ip(&h, "forwarded", &trusted, ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+    }
+}
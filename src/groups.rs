@@ -0,0 +1,776 @@
+//! Relay-based groups (NIP-29)
+//!
+//! Events in a group carry an `h` tag naming it.  Group admins
+//! change the group with moderation events (kinds 9000-9020), and
+//! users ask to join or leave with kinds 9021 and 9022.  The current
+//! state of each group is kept in memory, persisted, and published as
+//! events signed by the relay (kinds 39000-39003).
+use crate::config::Settings;
+use crate::db::QueryResult;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::notice::Notice;
+use crate::repo::NostrRepo;
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::{is_hex, unix_time};
+use nostr::event::TagKind;
+use nostr::key::FromSkStr;
+use nostr::secp256k1::{Message, Secp256k1};
+use nostr::{EventId, Keys, Kind, Tag, Timestamp};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Add a user to a group, or change their roles
+pub const PUT_USER_KIND: u64 = 9000;
+/// Remove a user from a group
+pub const REMOVE_USER_KIND: u64 = 9001;
+/// Change a group's metadata
+pub const EDIT_METADATA_KIND: u64 = 9002;
+/// Delete events from a group
+pub const DELETE_EVENT_KIND: u64 = 9005;
+/// Create a group
+pub const CREATE_GROUP_KIND: u64 = 9007;
+/// Delete a group
+pub const DELETE_GROUP_KIND: u64 = 9008;
+/// Ask to join a group
+pub const JOIN_REQUEST_KIND: u64 = 9021;
+/// Leave a group
+pub const LEAVE_REQUEST_KIND: u64 = 9022;
+/// Group metadata, published by the relay
+pub const GROUP_METADATA_KIND: u64 = 39000;
+/// Group admins, published by the relay
+pub const GROUP_ADMINS_KIND: u64 = 39001;
+/// Group members, published by the relay
+pub const GROUP_MEMBERS_KIND: u64 = 39002;
+/// Roles supported by the relay
+pub const GROUP_ROLES_KIND: u64 = 39003;
+
+/// The role given to group creators.  Members with any role may
+/// moderate the group.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Is this a moderation event, which only admins may publish?
+fn is_moderation(kind: u64) -> bool {
+    (9000..=9020).contains(&kind)
+}
+
+/// The group an event belongs to, from its `h` tag.
+#[must_use]
+pub fn group_id(event: &Event) -> Option<String> {
+    event.tag_values_by_name("h").into_iter().next()
+}
+
+/// Group ids are short, and limited to characters that are safe in
+/// URLs.
+fn is_valid_group_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// A group hosted by the relay
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub about: String,
+    pub picture: String,
+    /// Only members may read the group's events
+    pub private: bool,
+    /// Only admins may add members
+    pub closed: bool,
+    /// Deleted groups are kept, so that their ids are not reused
+    pub deleted: bool,
+    pub created_at: u64,
+    /// Members, and their roles (empty for plain members)
+    pub members: BTreeMap<String, Vec<String>>,
+}
+
+impl Group {
+    /// A new group, with its creator as admin.
+    #[must_use]
+    pub fn new(id: &str, creator: &str, created_at: u64) -> Group {
+        let mut members = BTreeMap::new();
+        members.insert(creator.to_owned(), vec![ADMIN_ROLE.to_owned()]);
+        Group {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            created_at,
+            members,
+            ..Default::default()
+        }
+    }
+
+    #[must_use]
+    pub fn is_member(&self, pubkey: &str) -> bool {
+        self.members.contains_key(pubkey)
+    }
+
+    #[must_use]
+    pub fn is_admin(&self, pubkey: &str) -> bool {
+        self.members.get(pubkey).is_some_and(|r| !r.is_empty())
+    }
+
+    /// Apply a stored group event, returning what changed.
+    fn update(&mut self, event: &Event) -> Option<Change> {
+        match event.kind {
+            PUT_USER_KIND => {
+                for tag in event.tags.iter().filter(|t| t.len() > 1 && t[0] == "p") {
+                    if tag[1].len() == 64 && is_hex(&tag[1]) {
+                        self.members
+                            .insert(tag[1].to_lowercase(), tag[2..].to_vec());
+                    }
+                }
+                Some(Change::Updated)
+            }
+            REMOVE_USER_KIND => {
+                for pubkey in event.tag_values_by_name("p") {
+                    self.members.remove(&pubkey.to_lowercase());
+                }
+                Some(Change::Updated)
+            }
+            EDIT_METADATA_KIND => {
+                for tag in &event.tags {
+                    match (tag.first().map(String::as_str), tag.get(1)) {
+                        (Some("name"), Some(v)) => self.name = v.clone(),
+                        (Some("about"), Some(v)) => self.about = v.clone(),
+                        (Some("picture"), Some(v)) => self.picture = v.clone(),
+                        (Some("private"), _) => self.private = true,
+                        (Some("public"), _) => self.private = false,
+                        (Some("closed"), _) => self.closed = true,
+                        (Some("open"), _) => self.closed = false,
+                        _ => {}
+                    }
+                }
+                Some(Change::Updated)
+            }
+            DELETE_EVENT_KIND => Some(Change::EventsDeleted(event.tag_values_by_name("e"))),
+            DELETE_GROUP_KIND => Some(Change::Deleted),
+            JOIN_REQUEST_KIND if !self.closed && !self.is_member(&event.pubkey) => {
+                self.members.insert(event.pubkey.clone(), vec![]);
+                Some(Change::Updated)
+            }
+            LEAVE_REQUEST_KIND => self.members.remove(&event.pubkey).map(|_| Change::Updated),
+            _ => None,
+        }
+    }
+}
+
+/// The effect of a group event
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    /// The group's metadata or members changed
+    Updated,
+    /// These events were removed from the group
+    EventsDeleted(Vec<String>),
+    /// The group was deleted
+    Deleted,
+}
+
+/// Groups hosted by the relay
+#[derive(Clone)]
+pub struct GroupManager {
+    keys: Option<Arc<Keys>>,
+    relay_pubkey: String,
+    creators: Arc<HashSet<String>>,
+    groups: Arc<RwLock<HashMap<String, Group>>>,
+    /// Ids of deleted groups, which may not be created again
+    deleted: Arc<RwLock<HashSet<String>>>,
+    // state events are replaceable, so each must be newer than the
+    // last.
+    last_published: Arc<Mutex<u64>>,
+}
+
+impl GroupManager {
+    pub fn new(settings: &Settings) -> Result<GroupManager> {
+        let keys = match &settings.groups.secret_key {
+            Some(secret_key) if settings.groups.enabled => Some(Keys::from_sk_str(secret_key)?),
+            _ => None,
+        };
+        let relay_pubkey = keys
+            .as_ref()
+            .map(|k| k.public_key().to_string())
+            .unwrap_or_default();
+        Ok(GroupManager {
+            keys: keys.map(Arc::new),
+            relay_pubkey,
+            creators: Arc::new(
+                settings
+                    .groups
+                    .creators
+                    .iter()
+                    .map(|pk| pk.to_lowercase())
+                    .collect(),
+            ),
+            groups: Arc::new(RwLock::new(HashMap::new())),
+            deleted: Arc::new(RwLock::new(HashSet::new())),
+            last_published: Arc::new(Mutex::new(0)),
+        })
+    }
+
+    /// Are groups hosted?
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// A copy of a group's current state.
+    #[must_use]
+    pub fn group(&self, id: &str) -> Option<Group> {
+        self.groups.read().unwrap().get(id).cloned()
+    }
+
+    /// Check a submitted event against group membership.  Returns a
+    /// notice for the author if it must be rejected.  Group events
+    /// must be published over a connection authenticated (NIP-42) as
+    /// their author.
+    #[must_use]
+    pub fn rejection(&self, event: &Event, auth_pubkey: Option<&str>) -> Option<Notice> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let id = event.id.clone();
+        if (GROUP_METADATA_KIND..=GROUP_ROLES_KIND).contains(&event.kind) {
+            if event.pubkey != self.relay_pubkey {
+                return Some(Notice::blocked(id, "group state is published by the relay"));
+            }
+            return None;
+        }
+        let Some(group_id) = group_id(event) else {
+            if is_moderation(event.kind)
+                || event.kind == JOIN_REQUEST_KIND
+                || event.kind == LEAVE_REQUEST_KIND
+            {
+                return Some(Notice::invalid(id, "group events must have an h tag"));
+            }
+            return None;
+        };
+        if auth_pubkey != Some(event.pubkey.as_str()) {
+            return Some(Notice::auth_required(
+                id,
+                "authenticate to publish to groups",
+            ));
+        }
+        self.group_rejection(&self.groups.read().unwrap(), event, &group_id)
+    }
+
+    /// Check an event from an authenticated author against the
+    /// current state of its group.
+    fn group_rejection(
+        &self,
+        groups: &HashMap<String, Group>,
+        event: &Event,
+        group_id: &str,
+    ) -> Option<Notice> {
+        let id = event.id.clone();
+        let Some(group) = groups.get(group_id) else {
+            if self.deleted.read().unwrap().contains(group_id) {
+                return Some(Notice::invalid(id, "group was deleted"));
+            }
+            if event.kind != CREATE_GROUP_KIND {
+                return Some(Notice::invalid(id, "group not found"));
+            }
+            if !is_valid_group_id(group_id) {
+                return Some(Notice::invalid(
+                    id,
+                    "group id may only contain a-z, 0-9, - and _",
+                ));
+            }
+            if !self.creators.is_empty() && !self.creators.contains(&event.pubkey) {
+                return Some(Notice::restricted(id, "not allowed to create groups"));
+            }
+            return None;
+        };
+        match event.kind {
+            CREATE_GROUP_KIND => Some(Notice::invalid(id, "group already exists")),
+            JOIN_REQUEST_KIND if group.is_member(&event.pubkey) => Some(Notice::duplicate(id)),
+            JOIN_REQUEST_KIND => None,
+            LEAVE_REQUEST_KIND if !group.is_member(&event.pubkey) => {
+                Some(Notice::invalid(id, "not a member of this group"))
+            }
+            kind if is_moderation(kind) && !group.is_admin(&event.pubkey) => Some(
+                Notice::restricted(id, "only group admins may moderate this group"),
+            ),
+            _ if !group.is_member(&event.pubkey) => Some(Notice::restricted(
+                id,
+                "only members may publish to this group",
+            )),
+            _ => None,
+        }
+    }
+
+    /// May a reader (authenticated as `reader`, if at all) see a
+    /// group event?  Events of private groups are only sent to
+    /// members, and events of unknown groups are not sent at all.
+    #[must_use]
+    pub fn can_read(&self, event: &Event, reader: Option<&str>) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let Some(group_id) = group_id(event) else {
+            return true;
+        };
+        match self.groups.read().unwrap().get(&group_id) {
+            Some(group) => !group.private || reader.is_some_and(|pk| group.is_member(pk)),
+            None => false,
+        }
+    }
+
+    /// Load the stored groups.
+    pub async fn load(&self, repo: &Arc<dyn NostrRepo>) {
+        if !self.is_enabled() {
+            return;
+        }
+        match repo.load_groups().await {
+            Ok(loaded) => {
+                let mut groups = self.groups.write().unwrap();
+                let mut deleted = self.deleted.write().unwrap();
+                for group in loaded {
+                    if group.deleted {
+                        deleted.insert(group.id);
+                    } else {
+                        groups.insert(group.id.clone(), group);
+                    }
+                }
+                info!("loaded {} groups", groups.len());
+            }
+            Err(e) => warn!("could not load groups: {:?}", e),
+        }
+        // later state must be dated after the newest published, which
+        // may be ahead of the clock.
+        let filter = self.state_filter(None, Some(1));
+        if let Some(latest) = self.find(repo, filter).await.first() {
+            *self.last_published.lock().unwrap() = latest.created_at;
+        }
+    }
+
+    /// Filter for the state events the relay has published, for one
+    /// group or all of them.
+    fn state_filter(&self, group_id: Option<&str>, limit: Option<u64>) -> ReqFilter {
+        ReqFilter {
+            ids: None,
+            kinds: Some((GROUP_METADATA_KIND..=GROUP_ROLES_KIND).collect()),
+            since: None,
+            until: None,
+            authors: Some(vec![self.relay_pubkey.clone()]),
+            limit,
+            tags: group_id.map(|id| HashMap::from([('d', HashSet::from([id.to_owned()]))])),
+            force_no_match: false,
+        }
+    }
+
+    /// Update group state from a newly stored event, persisting the
+    /// group and publishing its new state.  The group may have changed
+    /// since the event was admitted; if it is no longer permitted, the
+    /// event is hidden and the notice for its author returned.
+    pub async fn apply(
+        &self,
+        event: &Event,
+        repo: &Arc<dyn NostrRepo>,
+        bcast_tx: &broadcast::Sender<Event>,
+    ) -> Option<Notice> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let group_id = group_id(event)?;
+        let applied = {
+            let mut groups = self.groups.write().unwrap();
+            // the author was authenticated when the event was admitted,
+            // but the group may have changed since.
+            match self.group_rejection(&groups, event, &group_id) {
+                Some(notice) => Err(notice),
+                None => {
+                    let change = if event.kind == CREATE_GROUP_KIND {
+                        groups.insert(
+                            group_id.clone(),
+                            Group::new(&group_id, &event.pubkey, event.created_at),
+                        );
+                        Change::Updated
+                    } else {
+                        groups.get_mut(&group_id).and_then(|g| g.update(event))?
+                    };
+                    let group = if change == Change::Deleted {
+                        // the id may not be used again, so that a new group
+                        // does not inherit the old group's events.
+                        self.deleted.write().unwrap().insert(group_id.clone());
+                        groups.remove(&group_id)
+                    } else {
+                        groups.get(&group_id).cloned()
+                    };
+                    Ok((change, group))
+                }
+            }
+        };
+        let (change, group) = match applied {
+            Ok((change, Some(group))) => (change, group),
+            Ok((_, None)) => return None,
+            Err(notice) => {
+                info!(
+                    "event {:?} is no longer permitted in group {:?}",
+                    event.get_event_id_prefix(),
+                    group_id
+                );
+                if let Err(e) = repo.hide_events(std::slice::from_ref(&event.id)).await {
+                    warn!("could not hide event {:?}: {:?}", event.id, e);
+                }
+                return Some(notice);
+            }
+        };
+        match change {
+            Change::Updated => {
+                if let Err(e) = repo.save_group(&group).await {
+                    warn!("could not save group {:?}: {:?}", group.id, e);
+                }
+                self.publish(&group, repo, bcast_tx).await;
+            }
+            Change::EventsDeleted(ids) => {
+                // only events in this group may be deleted by its admins
+                let filter = ReqFilter {
+                    ids: Some(ids),
+                    kinds: None,
+                    since: None,
+                    until: None,
+                    authors: None,
+                    limit: None,
+                    tags: Some(HashMap::from([('h', HashSet::from([group.id.clone()]))])),
+                    force_no_match: false,
+                };
+                let ids: Vec<String> = self
+                    .find(repo, filter)
+                    .await
+                    .into_iter()
+                    .map(|e| e.id)
+                    .collect();
+                match repo.hide_events(&ids).await {
+                    Ok(count) => info!("deleted {} events from group {:?}", count, group.id),
+                    Err(e) => warn!("could not delete events from group {:?}: {:?}", group.id, e),
+                }
+            }
+            Change::Deleted => {
+                if let Err(e) = repo.delete_group(&group.id).await {
+                    warn!("could not delete group {:?}: {:?}", group.id, e);
+                }
+                // withdraw the group's published state
+                let filter = self.state_filter(Some(&group.id), None);
+                let ids: Vec<String> = self
+                    .find(repo, filter)
+                    .await
+                    .into_iter()
+                    .map(|e| e.id)
+                    .collect();
+                if let Err(e) = repo.hide_events(&ids).await {
+                    warn!("could not hide state of group {:?}: {:?}", group.id, e);
+                }
+                info!("deleted group {:?}", group.id);
+            }
+        }
+        None
+    }
+
+    /// Stored events matching a filter.
+    async fn find(&self, repo: &Arc<dyn NostrRepo>, filter: ReqFilter) -> Vec<Event> {
+        let sub = Subscription {
+            id: "groups".to_owned(),
+            filters: vec![filter],
+        };
+        let (query_tx, mut query_rx) = mpsc::channel::<QueryResult>(16);
+        let (_abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+        if let Err(e) = repo
+            .query_subscription(sub, "groups".to_owned(), query_tx, abandon_query_rx)
+            .await
+        {
+            warn!("could not query group events: {:?}", e);
+            return vec![];
+        }
+        let mut events = vec![];
+        while let Some(qr) = query_rx.recv().await {
+            if qr.event == "EOSE" {
+                break;
+            }
+            if let Ok(event) = serde_json::from_str::<Event>(&qr.event) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Publish a group's metadata, admins, members and roles as
+    /// events signed by the relay.
+    async fn publish(
+        &self,
+        group: &Group,
+        repo: &Arc<dyn NostrRepo>,
+        bcast_tx: &broadcast::Sender<Event>,
+    ) {
+        let created_at = self.next_timestamp();
+        for (kind, tags) in state_tags(group) {
+            let event = match self.sign(kind, tags, created_at) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("could not sign group state: {:?}", e);
+                    return;
+                }
+            };
+            match repo.write_event(&event).await {
+                Ok(0) => {}
+                Ok(_) => {
+                    bcast_tx.send(event).ok();
+                }
+                Err(e) => warn!("could not store group state: {:?}", e),
+            }
+        }
+    }
+
+    /// Date for newly published state.  Each is after the last, so
+    /// that it replaces earlier state even when published within the
+    /// same second.
+    fn next_timestamp(&self) -> u64 {
+        let mut last = self.last_published.lock().unwrap();
+        *last = unix_time().max(*last + 1);
+        *last
+    }
+
+    /// Sign an event with the relay's key.
+    fn sign(&self, kind: u64, tags: Vec<Tag>, created_at: u64) -> Result<Event> {
+        let keys = self
+            .keys
+            .as_ref()
+            .ok_or_else(|| Error::CustomError("groups are not enabled".to_owned()))?;
+        let secp = Secp256k1::new();
+        let pubkey = keys.public_key();
+        let created_at = Timestamp::from(created_at);
+        let kind = Kind::from(kind);
+        let id = EventId::new(&pubkey, created_at, &kind, &tags, "");
+        let message =
+            Message::from_slice(id.as_bytes()).map_err(|e| Error::CustomError(e.to_string()))?;
+        let key_pair = keys
+            .key_pair()
+            .map_err(|e| Error::CustomError(e.to_string()))?;
+        let event = nostr::Event {
+            id,
+            pubkey,
+            created_at,
+            kind,
+            tags,
+            content: String::new(),
+            sig: secp.sign_schnorr(&message, &key_pair),
+            ots: None,
+        };
+        let mut event: Event = event.into();
+        event.build_index();
+        Ok(event)
+    }
+}
+
+fn tag(name: &str, values: &[&str]) -> Tag {
+    Tag::Generic(
+        TagKind::Custom(name.to_owned()),
+        values.iter().map(|v| (*v).to_owned()).collect(),
+    )
+}
+
+/// Tags of the events describing a group's state, by kind.
+fn state_tags(group: &Group) -> Vec<(u64, Vec<Tag>)> {
+    let d = tag("d", &[&group.id]);
+    let mut metadata = vec![d.clone(), tag("name", &[&group.name])];
+    if !group.about.is_empty() {
+        metadata.push(tag("about", &[&group.about]));
+    }
+    if !group.picture.is_empty() {
+        metadata.push(tag("picture", &[&group.picture]));
+    }
+    metadata.push(tag(if group.private { "private" } else { "public" }, &[]));
+    metadata.push(tag(if group.closed { "closed" } else { "open" }, &[]));
+    let mut admins = vec![d.clone()];
+    let mut members = vec![d.clone()];
+    for (pubkey, roles) in &group.members {
+        if !roles.is_empty() {
+            let mut values = vec![pubkey.as_str()];
+            values.extend(roles.iter().map(String::as_str));
+            admins.push(tag("p", &values));
+        }
+        members.push(tag("p", &[pubkey]));
+    }
+    let roles = vec![d, tag("role", &[ADMIN_ROLE, "may moderate the group"])];
+    vec![
+        (GROUP_METADATA_KIND, metadata),
+        (GROUP_ADMINS_KIND, admins),
+        (GROUP_MEMBERS_KIND, members),
+        (GROUP_ROLES_KIND, roles),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: &str = "35d26e4690cbe1a898af61cc3515661eb5fa763b57bd0b42e45099c8b32fd50f";
+    const MEMBER: &str = "887645fef0ce0c3c1218d2f5d8e6132a19304cdc57cd20281d082f38cfea0072";
+
+    fn manager() -> GroupManager {
+        let mut settings = Settings::default();
+        settings.groups.enabled = true;
+        settings.groups.secret_key = Some("01".repeat(32));
+        let manager = GroupManager::new(&settings).unwrap();
+        let mut group = Group::new("pizza", ADMIN, 10);
+        group.private = true;
+        manager
+            .groups
+            .write()
+            .unwrap()
+            .insert(group.id.clone(), group);
+        manager
+    }
+
+    fn group_event(pubkey: &str, kind: u64, tags: &[&[&str]]) -> Event {
+        let mut event = Event::simple_event();
+        event.pubkey = pubkey.to_owned();
+        event.kind = kind;
+        event.tags = tags
+            .iter()
+            .map(|t| t.iter().map(|v| (*v).to_owned()).collect())
+            .collect();
+        event.build_index();
+        event
+    }
+
+    fn status(notice: Option<Notice>) -> Option<&'static str> {
+        match notice {
+            Some(Notice::EventResult(res)) => Some(res.status.prefix()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn writes_need_membership() {
+        let m = manager();
+        let post = group_event(MEMBER, 1, &[&["h", "pizza"]]);
+        assert_eq!(status(m.rejection(&post, None)), Some("auth-required"));
+        assert_eq!(status(m.rejection(&post, Some(MEMBER))), Some("restricted"));
+        let post = group_event(ADMIN, 1, &[&["h", "pizza"]]);
+        assert_eq!(status(m.rejection(&post, Some(ADMIN))), None);
+        // only admins moderate
+        let put = group_event(MEMBER, PUT_USER_KIND, &[&["h", "pizza"], &["p", MEMBER]]);
+        assert_eq!(status(m.rejection(&put, Some(MEMBER))), Some("restricted"));
+        // unknown groups must be created first
+        let post = group_event(ADMIN, 1, &[&["h", "pasta"]]);
+        assert_eq!(status(m.rejection(&post, Some(ADMIN))), Some("invalid"));
+        let create = group_event(ADMIN, CREATE_GROUP_KIND, &[&["h", "pasta"]]);
+        assert_eq!(status(m.rejection(&create, Some(ADMIN))), None);
+        // events without an h tag are not affected
+        let note = group_event(MEMBER, 1, &[]);
+        assert_eq!(status(m.rejection(&note, None)), None);
+        // nobody else may publish group state
+        let state = group_event(ADMIN, GROUP_METADATA_KIND, &[&["d", "pizza"]]);
+        assert_eq!(status(m.rejection(&state, Some(ADMIN))), Some("blocked"));
+    }
+
+    #[test]
+    fn moderation_changes_group() {
+        let mut group = Group::new("pizza", ADMIN, 10);
+        group.closed = true;
+        // closed groups are not joined by asking
+        let join = group_event(MEMBER, JOIN_REQUEST_KIND, &[&["h", "pizza"]]);
+        assert_eq!(group.update(&join), None);
+        let put = group_event(ADMIN, PUT_USER_KIND, &[&["h", "pizza"], &["p", MEMBER]]);
+        assert_eq!(group.update(&put), Some(Change::Updated));
+        assert!(group.is_member(MEMBER));
+        assert!(!group.is_admin(MEMBER));
+        let edit = group_event(
+            ADMIN,
+            EDIT_METADATA_KIND,
+            &[&["h", "pizza"], &["name", "Pizza lovers"], &["open"]],
+        );
+        group.update(&edit);
+        assert_eq!(group.name, "Pizza lovers");
+        assert!(!group.closed);
+        let leave = group_event(MEMBER, LEAVE_REQUEST_KIND, &[&["h", "pizza"]]);
+        assert_eq!(group.update(&leave), Some(Change::Updated));
+        assert!(!group.is_member(MEMBER));
+        assert_eq!(group.update(&join), Some(Change::Updated));
+        assert!(group.is_member(MEMBER));
+    }
+
+    #[test]
+    fn private_reads_need_membership() {
+        let m = manager();
+        let post = group_event(ADMIN, 1, &[&["h", "pizza"]]);
+        assert!(!m.can_read(&post, None));
+        assert!(!m.can_read(&post, Some(MEMBER)));
+        assert!(m.can_read(&post, Some(ADMIN)));
+        let elsewhere = group_event(ADMIN, 1, &[&["h", "pasta"]]);
+        assert!(!m.can_read(&elsewhere, Some(ADMIN)));
+    }
+
+    #[test]
+    fn deleted_groups_stay_deleted() {
+        let m = manager();
+        m.groups.write().unwrap().remove("pizza");
+        m.deleted.write().unwrap().insert("pizza".to_owned());
+        // nobody may create the group again, and its events stay hidden
+        let create = group_event(MEMBER, CREATE_GROUP_KIND, &[&["h", "pizza"]]);
+        assert_eq!(status(m.rejection(&create, Some(MEMBER))), Some("invalid"));
+        let post = group_event(ADMIN, 1, &[&["h", "pizza"]]);
+        assert!(!m.can_read(&post, Some(ADMIN)));
+    }
+
+    #[tokio::test]
+    async fn apply_rechecks_membership() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let repo = crate::db::build_repo(&settings, crate::server::create_metrics().1).await;
+        let (bcast_tx, _bcast_rx) = broadcast::channel(16);
+        let m = manager();
+        m.groups
+            .write()
+            .unwrap()
+            .get_mut("pizza")
+            .unwrap()
+            .members
+            .insert(MEMBER.to_owned(), vec![]);
+        let mut post = group_event(MEMBER, 1, &[&["h", "pizza"]]);
+        post.id = "ab".repeat(32);
+        assert_eq!(status(m.rejection(&post, Some(MEMBER))), None);
+        // the member is removed before their event is stored
+        let remove = group_event(ADMIN, REMOVE_USER_KIND, &[&["h", "pizza"], &["p", MEMBER]]);
+        assert_eq!(status(m.apply(&remove, &repo, &bcast_tx).await), None);
+        repo.write_event(&post).await.unwrap();
+        assert_eq!(
+            status(m.apply(&post, &repo, &bcast_tx).await),
+            Some("restricted")
+        );
+        // and the event is hidden
+        let filter = ReqFilter {
+            ids: Some(vec![post.id.clone()]),
+            kinds: None,
+            since: None,
+            until: None,
+            authors: None,
+            limit: None,
+            tags: None,
+            force_no_match: false,
+        };
+        assert!(m.find(&repo, filter).await.is_empty());
+    }
+
+    #[test]
+    fn state_is_signed_by_relay() {
+        let m = manager();
+        let group = m.group("pizza").unwrap();
+        let tags = state_tags(&group);
+        assert_eq!(tags.len(), 4);
+        let (kind, metadata) = tags[0].clone();
+        let first = m.sign(kind, metadata.clone(), m.next_timestamp()).unwrap();
+        let second = m.sign(kind, metadata, m.next_timestamp()).unwrap();
+        assert_eq!(first.pubkey, m.relay_pubkey);
+        assert!(first.validate().is_ok());
+        // later state replaces earlier state
+        assert!(second.created_at > first.created_at);
+        assert_eq!(first.distinct_param(), Some("pizza".to_owned()));
+    }
+}
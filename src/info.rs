@@ -16,6 +16,12 @@ pub struct Limitation {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     restricted_writes: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_pow_difficulty: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at_lower_limit: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -68,6 +74,16 @@ impl From<Settings> for RelayInfo {
     fn from(c: Settings) -> Self {
         let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40];
 
+        if c.pow.min_difficulty.is_some() || c.pow.kind_difficulty.is_some() {
+            supported_nips.push(13);
+            supported_nips.sort();
+        }
+
+        if c.groups.enabled {
+            supported_nips.push(29);
+            supported_nips.sort();
+        }
+
         if c.authorization.nip42_auth {
             supported_nips.push(42);
             supported_nips.sort();
@@ -84,6 +100,8 @@ impl From<Settings> for RelayInfo {
                     || c.authorization.pubkey_whitelist.is_some()
                     || c.grpc.restricts_write,
             ),
+            min_pow_difficulty: c.pow.min_difficulty.filter(|d| *d > 0),
+            created_at_lower_limit: c.options.reject_past_seconds.map(|s| s as u64),
         };
 
         let (payments_url, fees) = if p.enabled {
@@ -0,0 +1,304 @@
+//! Invite codes
+//!
+//! With invites enabled, only admitted accounts (and whitelisted
+//! authors) may publish.  Admins mint codes on admin listeners, and
+//! members may mint them by publishing an invite request (kind
+//! 28935) over an authenticated connection.  Authors redeem a code on the `/join` page, or with a join
+//! request (kind 28934, NIP-43) carrying a `claim` tag, which admits
+//! their account.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::notice::Notice;
+use crate::repo::NostrRepo;
+use crate::utils::unix_time;
+use nostr::key::FromPkStr;
+use nostr::Keys;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Redeem an invite code
+pub const JOIN_REQUEST_KIND: u64 = 28934;
+/// Ask the relay to mint an invite code
+pub const INVITE_REQUEST_KIND: u64 = 28935;
+
+/// Length of minted codes
+const CODE_LEN: usize = 16;
+
+/// How long an author found not to be admitted is remembered
+const REFUSED_TTL: Duration = Duration::from_secs(30);
+
+/// Most authors remembered as not admitted
+const MAX_REFUSED: usize = 10_000;
+
+/// How far an invite request's timestamp may be from the present.
+/// Requests are never stored, so older ones could be replayed.
+const INVITE_REQUEST_WINDOW: u64 = 600;
+
+/// Period over which members' minted codes are limited
+const MINT_PERIOD: u64 = 86_400;
+
+/// An invite code, which admits the accounts that redeem it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Invite {
+    pub code: String,
+    /// Pubkey of the member who minted the code, or "admin"
+    pub created_by: String,
+    pub max_uses: u64,
+    pub uses: u64,
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Invite {
+    /// Mint a new code.
+    #[must_use]
+    pub fn new(created_by: &str, max_uses: u64, expires_at: Option<u64>) -> Invite {
+        let code = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(CODE_LEN)
+            .map(char::from)
+            .collect();
+        Invite {
+            code,
+            created_by: created_by.to_owned(),
+            max_uses,
+            uses: 0,
+            expires_at,
+            created_at: unix_time(),
+        }
+    }
+}
+
+/// The result of redeeming an invite code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redemption {
+    /// The account was admitted
+    Admitted,
+    /// The account was already admitted, so the code was not used
+    AlreadyAdmitted,
+    /// The code is unknown, used up, or expired
+    Invalid,
+}
+
+/// Has the author's account been admitted, according to the
+/// database?
+async fn account_admitted(repo: &Arc<dyn NostrRepo>, pubkey: &str) -> Result<bool> {
+    let key = Keys::from_pk_str(pubkey)?;
+    match repo.get_account_balance(&key).await {
+        Ok((admitted, _)) => Ok(admitted),
+        Err(
+            Error::SqlError(rusqlite::Error::QueryReturnedNoRows)
+            | Error::SqlxError(sqlx::Error::RowNotFound),
+        ) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Admission state of authors, cached so that every event does not
+/// read the database.  Admitted authors are remembered, since only
+/// redeeming a code changes their state; authors who are not admitted
+/// are checked again after a short while, in case another relay
+/// sharing the database admitted them.
+#[derive(Clone, Default)]
+pub struct Admissions {
+    admitted: Arc<RwLock<HashSet<String>>>,
+    refused: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Invite requests answered within the window, by id, with their
+    /// timestamps.  Locked while a member mints a code.
+    minted: Arc<tokio::sync::Mutex<HashMap<String, u64>>>,
+}
+
+impl Admissions {
+    /// Has the author's account been admitted?
+    pub async fn is_admitted(&self, repo: &Arc<dyn NostrRepo>, pubkey: &str) -> Result<bool> {
+        if self.admitted.read().unwrap().contains(pubkey) {
+            return Ok(true);
+        }
+        let refused = self.refused.lock().unwrap().get(pubkey).copied();
+        if refused.is_some_and(|at| at.elapsed() < REFUSED_TTL) {
+            return Ok(false);
+        }
+        let admitted = account_admitted(repo, pubkey).await?;
+        if admitted {
+            self.admit(pubkey);
+        } else {
+            self.refuse(pubkey);
+        }
+        Ok(admitted)
+    }
+
+    fn admit(&self, pubkey: &str) {
+        self.refused.lock().unwrap().remove(pubkey);
+        self.admitted.write().unwrap().insert(pubkey.to_owned());
+    }
+
+    fn refuse(&self, pubkey: &str) {
+        let mut refused = self.refused.lock().unwrap();
+        if refused.len() >= MAX_REFUSED {
+            refused.retain(|_, at| at.elapsed() < REFUSED_TTL);
+            if refused.len() >= MAX_REFUSED {
+                refused.clear();
+            }
+        }
+        refused.insert(pubkey.to_owned(), Instant::now());
+    }
+}
+
+/// Redeem an invite code for an author.
+pub async fn redeem(
+    repo: &Arc<dyn NostrRepo>,
+    admissions: &Admissions,
+    code: &str,
+    pubkey: &str,
+) -> Result<Redemption> {
+    // checked without the cache, so that a code is never spent on an
+    // admitted account
+    if account_admitted(repo, pubkey).await? {
+        admissions.admit(pubkey);
+        return Ok(Redemption::AlreadyAdmitted);
+    }
+    let key = Keys::from_pk_str(pubkey)?;
+    if repo.redeem_invite(code, &key).await? {
+        info!("admitted {:?} with an invite code", pubkey);
+        admissions.admit(pubkey);
+        Ok(Redemption::Admitted)
+    } else {
+        Ok(Redemption::Invalid)
+    }
+}
+
+/// May the author mint invite codes?  Moderators, whitelisted
+/// authors and admitted accounts may, if member invites are enabled.
+async fn is_member(
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+    admissions: &Admissions,
+    pubkey: &str,
+) -> Result<bool> {
+    let auth = &settings.authorization;
+    if auth.moderators.iter().any(|m| m == pubkey)
+        || auth
+            .pubkey_whitelist
+            .as_ref()
+            .is_some_and(|wl| wl.iter().any(|w| w == pubkey))
+    {
+        return Ok(true);
+    }
+    admissions.is_admitted(repo, pubkey).await
+}
+
+/// Handle a join or invite request, once it has passed the relay's
+/// other checks.  These are answered by the relay, and never stored
+/// or sent to subscribers.  Returns the reply, or None if the event
+/// is not an invite event.  `auth_pubkey` is the hex pubkey the
+/// connection authenticated as, if any.
+pub async fn handle_event(
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+    admissions: &Admissions,
+    event: &Event,
+    auth_pubkey: Option<&str>,
+) -> Option<Notice> {
+    if !settings.invites.enabled {
+        return None;
+    }
+    let id = event.id.clone();
+    match event.kind {
+        JOIN_REQUEST_KIND => {
+            let Some(code) = event.tag_values_by_name("claim").into_iter().next() else {
+                return Some(Notice::invalid(id, "join requests need a claim tag"));
+            };
+            Some(match redeem(repo, admissions, &code, &event.pubkey).await {
+                Ok(Redemption::Admitted | Redemption::AlreadyAdmitted) => Notice::saved(id),
+                Ok(Redemption::Invalid) => {
+                    Notice::restricted(id, "invite code is invalid, used up, or expired")
+                }
+                Err(e) => {
+                    warn!("could not redeem invite code: {:?}", e);
+                    Notice::error(id, "relay could not redeem the invite code")
+                }
+            })
+        }
+        INVITE_REQUEST_KIND => {
+            Some(mint_for_member(settings, repo, admissions, event, auth_pubkey).await)
+        }
+        _ => None,
+    }
+}
+
+/// Mint a code for the author of an invite request.  The request must
+/// be recent, not seen before, and sent over a connection
+/// authenticated as its author, so that a copy of it cannot be used
+/// to mint more codes.
+async fn mint_for_member(
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+    admissions: &Admissions,
+    event: &Event,
+    auth_pubkey: Option<&str>,
+) -> Notice {
+    let id = event.id.clone();
+    let invites = &settings.invites;
+    if !invites.member_invites {
+        return Notice::blocked(id, "members may not mint invite codes");
+    }
+    if auth_pubkey != Some(event.pubkey.as_str()) {
+        return Notice::auth_required(id, "authenticate as the author to mint invite codes");
+    }
+    let now = unix_time();
+    if now.abs_diff(event.created_at) > INVITE_REQUEST_WINDOW {
+        return Notice::invalid(id, "invite request is too old or too far in the future");
+    }
+    match is_member(settings, repo, admissions, &event.pubkey).await {
+        Ok(true) => {}
+        Ok(false) => return Notice::restricted(id, "only members may mint invite codes"),
+        Err(e) => {
+            warn!("could not check membership: {:?}", e);
+            return Notice::error(id, "relay could not check membership");
+        }
+    }
+    let mut minted = admissions.minted.lock().await;
+    minted.retain(|_, at| now.abs_diff(*at) <= INVITE_REQUEST_WINDOW);
+    if minted.contains_key(&id) {
+        return Notice::duplicate(id);
+    }
+    if invites.member_invites_per_day > 0 {
+        match repo
+            .count_invites_by(&event.pubkey, now.saturating_sub(MINT_PERIOD))
+            .await
+        {
+            Ok(count) if count >= invites.member_invites_per_day => {
+                return Notice::rate_limited(id, "too many invite codes minted today");
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("could not count minted invite codes: {:?}", e);
+                return Notice::error(id, "relay could not mint an invite code");
+            }
+        }
+    }
+    let expires_at = invites
+        .member_expiration_duration()
+        .map(|d| now + d.as_secs());
+    let invite = Invite::new(&event.pubkey, invites.member_max_uses, expires_at);
+    match repo.create_invite(&invite).await {
+        Ok(()) => {
+            minted.insert(id.clone(), event.created_at);
+            info!(
+                "member {:?} minted an invite code",
+                event.get_author_prefix()
+            );
+            Notice::accepted(id, &format!("invite: {}", invite.code))
+        }
+        Err(e) => {
+            warn!("could not store invite code: {:?}", e);
+            Notice::error(id, "relay could not mint an invite code")
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Admission of client connections by IP address
+//!
+//! Addresses may be allowed or denied by CIDR ranges, both in the
+//! config file and in a separate access file that is reloaded
+//! whenever it changes.  Connections are also capped per address,
+//! both in number and in rate; IPv6 addresses are grouped by a
+//! configurable prefix for this, since clients often hold a whole
+//! /64.
+use crate::cidr::{self, Cidr};
+use crate::config::Settings;
+use governor::clock::DefaultClock;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use hyper::StatusCode;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// How often to check if the access file has changed
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to forget the connection rate of quiet addresses
+const RATE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+type KeyedLimiter = RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>;
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The address is in a denied range
+    Denied,
+    /// The address is not in any allowed range
+    NotAllowed,
+    /// The address has too many open connections
+    TooManyConnections,
+    /// The address is connecting too often
+    RateLimited,
+}
+
+impl Rejection {
+    /// Label for metrics
+    #[must_use]
+    pub fn label(self) -> &'static str {
+        match self {
+            Rejection::Denied => "denied",
+            Rejection::NotAllowed => "not_allowed",
+            Rejection::TooManyConnections => "too_many_connections",
+            Rejection::RateLimited => "rate_limited",
+        }
+    }
+
+    /// HTTP status to refuse the connection with
+    #[must_use]
+    pub fn status(self) -> StatusCode {
+        match self {
+            Rejection::Denied | Rejection::NotAllowed => StatusCode::FORBIDDEN,
+            Rejection::TooManyConnections | Rejection::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Text of the HTTP response
+    #[must_use]
+    pub fn message(self) -> &'static str {
+        match self {
+            Rejection::Denied | Rejection::NotAllowed => {
+                "Connections from this address are not accepted"
+            }
+            Rejection::TooManyConnections => "Too many connections from this address",
+            Rejection::RateLimited => "Too many connection attempts from this address",
+        }
+    }
+}
+
+/// Allowed and denied ranges
+#[derive(Debug, Default)]
+struct Ranges {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Ranges {
+    /// Parse an access file.  Each line is a range, optionally
+    /// preceded by "allow" or "deny" (the default).  Invalid lines
+    /// are skipped.
+    fn parse(text: &str) -> Ranges {
+        let mut ranges = Ranges::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (list, range) = match line.split_once(char::is_whitespace) {
+                Some(("allow", r)) => (&mut ranges.allow, r),
+                Some(("deny", r)) => (&mut ranges.deny, r),
+                _ => (&mut ranges.deny, line),
+            };
+            match range.parse() {
+                Ok(c) => list.push(c),
+                Err(e) => warn!("skipping line {} of IP access file: {:?}", n + 1, e),
+            }
+        }
+        ranges
+    }
+
+    fn check(&self, ip: &IpAddr) -> Option<Rejection> {
+        if cidr::any_contains(&self.deny, ip) {
+            Some(Rejection::Denied)
+        } else if !self.allow.is_empty() && !cidr::any_contains(&self.allow, ip) {
+            Some(Rejection::NotAllowed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Ranges from a file, reloaded when it changes
+struct AccessFile {
+    path: PathBuf,
+    ranges: Ranges,
+    /// Modification time of the file that was loaded
+    modified: Option<SystemTime>,
+    /// Last time we checked for a modified file
+    last_reload_check: Option<Instant>,
+}
+
+impl AccessFile {
+    fn reload_if_modified(&mut self) {
+        if self
+            .last_reload_check
+            .is_some_and(|t| t.elapsed() < RELOAD_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_reload_check = Some(Instant::now());
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        // a missing file has no ranges, so it can be created later.
+        self.ranges = match std::fs::read_to_string(&self.path) {
+            Ok(text) => Ranges::parse(&text),
+            Err(_) => Ranges::default(),
+        };
+        info!(
+            "loaded IP access file: {:?} ({} allowed, {} denied)",
+            self.path,
+            self.ranges.allow.len(),
+            self.ranges.deny.len()
+        );
+    }
+}
+
+struct Inner {
+    configured: Ranges,
+    file: Option<Mutex<AccessFile>>,
+    max_conns: Option<usize>,
+    /// Leading bits of an IPv6 address that identify a client
+    ipv6_prefix_len: u8,
+    /// Open connections for each address
+    conns: Mutex<HashMap<IpAddr, usize>>,
+    rate: Option<KeyedLimiter>,
+    last_rate_prune: Mutex<Instant>,
+}
+
+/// Decides which client addresses may connect, and tracks their open
+/// connections.
+#[derive(Clone)]
+pub struct IpAccess {
+    inner: Arc<Inner>,
+}
+
+/// An admitted connection, counted against its address until dropped
+pub struct ConnectionSlot {
+    inner: Arc<Inner>,
+    /// The address the connection is counted against
+    key: IpAddr,
+}
+
+impl IpAccess {
+    #[must_use]
+    pub fn new(settings: &Settings) -> IpAccess {
+        let file = settings.authorization.ip_access_file.as_ref().map(|path| {
+            let mut file = AccessFile {
+                path: PathBuf::from(path),
+                ranges: Ranges::default(),
+                modified: None,
+                last_reload_check: None,
+            };
+            file.reload_if_modified();
+            Mutex::new(file)
+        });
+        let rate = settings
+            .limits
+            .conns_per_ip_per_min
+            .and_then(NonZeroU32::new)
+            .map(|n| RateLimiter::keyed(Quota::per_minute(n)));
+        IpAccess {
+            inner: Arc::new(Inner {
+                configured: Ranges {
+                    allow: settings.authorization.ip_allowlist.clone(),
+                    deny: settings.authorization.ip_denylist.clone(),
+                },
+                file,
+                max_conns: settings
+                    .limits
+                    .max_conns_per_ip
+                    .filter(|n| *n > 0)
+                    .map(|n| n as usize),
+                ipv6_prefix_len: settings.limits.ipv6_prefix_len.min(128),
+                conns: Mutex::new(HashMap::new()),
+                rate,
+                last_rate_prune: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Admit a connection from the address, or say why not.
+    pub fn admit(&self, ip: IpAddr) -> Result<ConnectionSlot, Rejection> {
+        let inner = &self.inner;
+        if let Some(r) = inner.configured.check(&ip) {
+            return Err(r);
+        }
+        if let Some(file) = &inner.file {
+            let mut file = file.lock().unwrap();
+            file.reload_if_modified();
+            if let Some(r) = file.ranges.check(&ip) {
+                return Err(r);
+            }
+        }
+        // connection limits apply to the address's prefix.
+        let key = limit_key(ip, inner.ipv6_prefix_len);
+        let mut conns = inner.conns.lock().unwrap();
+        let count = conns.get(&key).copied().unwrap_or(0);
+        if inner.max_conns.is_some_and(|max| count >= max) {
+            return Err(Rejection::TooManyConnections);
+        }
+        // only attempts that would otherwise be admitted count
+        // against the rate.
+        if let Some(rate) = &inner.rate {
+            {
+                let mut last = inner.last_rate_prune.lock().unwrap();
+                if last.elapsed() > RATE_PRUNE_INTERVAL {
+                    *last = Instant::now();
+                    rate.retain_recent();
+                    rate.shrink_to_fit();
+                }
+            }
+            if rate.check_key(&key).is_err() {
+                return Err(Rejection::RateLimited);
+            }
+        }
+        conns.insert(key, count + 1);
+        Ok(ConnectionSlot {
+            inner: inner.clone(),
+            key,
+        })
+    }
+}
+
+/// The address connection limits are counted against: IPv4 addresses
+/// as they are, and IPv6 addresses truncated to `prefix_len` bits.
+fn limit_key(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return IpAddr::V4(v4);
+            }
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut conns = self.inner.conns.lock().unwrap();
+        if let Some(count) = conns.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn access_file_ranges() {
+        let ranges = Ranges::parse(
+            "# comment\nallow 10.0.0.0/8\ndeny 10.6.6.0/24\n192.0.2.1 # bare ranges are denied\nbogus\n",
+        );
+        assert_eq!(ranges.allow.len(), 1);
+        assert_eq!(ranges.deny.len(), 2);
+        assert_eq!(ranges.check(&ip("10.1.2.3")), None);
+        assert_eq!(ranges.check(&ip("10.6.6.6")), Some(Rejection::Denied));
+        assert_eq!(
+            ranges.check(&ip("198.51.100.1")),
+            Some(Rejection::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn connection_cap() {
+        let mut settings = Settings::default();
+        settings.limits.max_conns_per_ip = Some(2);
+        let access = IpAccess::new(&settings);
+        let a = access.admit(ip("192.0.2.1")).unwrap();
+        let _b = access.admit(ip("192.0.2.1")).unwrap();
+        assert!(matches!(
+            access.admit(ip("192.0.2.1")),
+            Err(Rejection::TooManyConnections)
+        ));
+        assert!(access.admit(ip("192.0.2.2")).is_ok());
+        drop(a);
+        assert!(access.admit(ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn connection_rate() {
+        let mut settings = Settings::default();
+        settings.limits.conns_per_ip_per_min = Some(1);
+        let access = IpAccess::new(&settings);
+        assert!(access.admit(ip("192.0.2.1")).is_ok());
+        assert!(matches!(
+            access.admit(ip("192.0.2.1")),
+            Err(Rejection::RateLimited)
+        ));
+    }
+
+    #[test]
+    fn ipv6_limited_by_prefix() {
+        let mut settings = Settings::default();
+        settings.limits.max_conns_per_ip = Some(1);
+        let access = IpAccess::new(&settings);
+        let _a = access.admit(ip("2001:db8:1:2::1")).unwrap();
+        assert!(matches!(
+            access.admit(ip("2001:db8:1:2:ffff::9")),
+            Err(Rejection::TooManyConnections)
+        ));
+        assert!(access.admit(ip("2001:db8:1:3::1")).is_ok());
+
+        settings.limits.ipv6_prefix_len = 128;
+        let access = IpAccess::new(&settings);
+        let _a = access.admit(ip("2001:db8:1:2::1")).unwrap();
+        assert!(access.admit(ip("2001:db8:1:2::2")).is_ok());
+    }
+
+    #[test]
+    fn limit_keys() {
+        assert_eq!(limit_key(ip("192.0.2.1"), 64), ip("192.0.2.1"));
+        assert_eq!(limit_key(ip("::ffff:192.0.2.1"), 64), ip("192.0.2.1"));
+        assert_eq!(limit_key(ip("2001:db8::1"), 0), ip("::"));
+        assert_eq!(limit_key(ip("2001:db8::1"), 32), ip("2001:db8::"));
+        assert_eq!(limit_key(ip("2001:db8::1"), 128), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn refused_attempts_do_not_use_rate() {
+        let mut settings = Settings::default();
+        settings.limits.max_conns_per_ip = Some(1);
+        settings.limits.conns_per_ip_per_min = Some(2);
+        let access = IpAccess::new(&settings);
+        let a = access.admit(ip("192.0.2.1")).unwrap();
+        for _ in 0..5 {
+            assert!(matches!(
+                access.admit(ip("192.0.2.1")),
+                Err(Rejection::TooManyConnections)
+            ));
+        }
+        drop(a);
+        // the second of the two attempts allowed per minute.
+        assert!(access.admit(ip("192.0.2.1")).is_ok());
+    }
+}
@@ -1,18 +1,31 @@
+pub mod blocklist;
+pub mod cidr;
 pub mod cli;
 pub mod close;
 pub mod config;
 pub mod conn;
 pub mod db;
+pub mod deflate;
 pub mod delegation;
+pub mod duplicates;
 pub mod error;
 pub mod event;
+pub mod forwarded;
+pub mod groups;
 pub mod info;
+pub mod invite;
+pub mod ipaccess;
+pub mod listener;
 pub mod nauthz;
 pub mod nip05;
 pub mod notice;
+pub mod nstream;
+pub mod outbound;
+pub mod policy;
 pub mod repo;
 pub mod subscription;
 pub mod utils;
+pub mod verify;
 // Public API for creating relays programmatically
 pub mod payment;
 pub mod server;
@@ -0,0 +1,314 @@
+//! Accepting client connections on TCP and Unix domain sockets
+//!
+//! Connections from trusted load balancers may begin with a PROXY
+//! protocol header (version 1 or 2), which gives the address of the
+//! client they are forwarding.
+use crate::cidr::{self, Cidr};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Signature starting a version 2 PROXY header
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 PROXY header, including the line ending
+const PROXY_V1_MAX_LEN: usize = 107;
+
+/// Time a proxy has to send its header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepted connections waiting to be served
+const ACCEPT_QUEUE: usize = 64;
+
+/// Address given to clients on Unix domain sockets, which have none
+/// of their own
+const UNIX_CLIENT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// A socket accepting client connections
+pub enum SocketListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl SocketListener {
+    async fn accept(&self) -> io::Result<ClientStream> {
+        match self {
+            SocketListener::Tcp(l) => {
+                let (stream, remote_addr) = l.accept().await?;
+                Ok(ClientStream {
+                    socket: Socket::Tcp(stream),
+                    remote_addr,
+                })
+            }
+            SocketListener::Unix(l) => {
+                let (stream, _) = l.accept().await?;
+                Ok(ClientStream {
+                    socket: Socket::Unix(stream),
+                    remote_addr: UNIX_CLIENT_ADDR,
+                })
+            }
+        }
+    }
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// A client connection, and the address of the client
+pub struct ClientStream {
+    socket: Socket,
+    remote_addr: SocketAddr,
+}
+
+impl ClientStream {
+    /// The client's address, as given by a proxy if there was one
+    #[must_use]
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// May this peer send a PROXY header?  Local processes able to
+    /// reach a Unix domain socket are always trusted.
+    fn trusted(&self, trusted: &[Cidr]) -> bool {
+        match self.socket {
+            Socket::Tcp(_) => cidr::any_contains(trusted, &self.remote_addr.ip()),
+            Socket::Unix(_) => true,
+        }
+    }
+}
+
+/// Accept connections, reading PROXY headers from trusted peers if
+/// `proxy_protocol` is set.  Connections are delivered on the
+/// returned channel, until it is closed.
+#[must_use]
+pub fn accept_connections(
+    listener: SocketListener,
+    proxy_protocol: bool,
+    trusted: Vec<Cidr>,
+) -> mpsc::Receiver<io::Result<ClientStream>> {
+    let (tx, rx) = mpsc::channel(ACCEPT_QUEUE);
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                res = listener.accept() => res,
+            };
+            let mut stream = match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    // most likely out of file descriptors; wait for
+                    // some to be released.
+                    warn!("could not accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if !(proxy_protocol && stream.trusted(&trusted)) {
+                tx.send(Ok(stream)).await.ok();
+                continue;
+            }
+            // the header is read on its own task, so a slow proxy
+            // does not hold up other connections.
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let peer = stream.remote_addr;
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
+                    .await
+                {
+                    Ok(Ok(Some(addr))) => stream.remote_addr = addr,
+                    // the proxy's own connection, such as a health check
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => {
+                        debug!("invalid PROXY header from {}: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("timed out waiting for PROXY header from {}", peer);
+                        return;
+                    }
+                }
+                tx.send(Ok(stream)).await.ok();
+            });
+        }
+    });
+    rx
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Read a PROXY header, returning the client address it gives.  No
+/// address is returned for connections the proxy made itself.
+/// Exactly the header is consumed from the stream.
+async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // both versions are at least this long
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == PROXY_V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(header[0], header[1], &body)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= PROXY_V1_MAX_LEN {
+                return Err(invalid("PROXY header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("PROXY header not text"))?;
+        parse_v1(line)
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+/// Parse a version 1 header line, such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+/// Parse a version 2 header, given its version/command byte, its
+/// family/protocol byte, and the address block.
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY version"));
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL: the proxy's own connection
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY command")),
+    }
+    match family >> 4 {
+        // IPv4: source and destination addresses, then ports
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // unspecified or Unix addresses carry no client address
+        0x0 | 0x3 => Ok(None),
+        _ => Err(invalid("malformed PROXY address")),
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Socket::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Socket::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Socket::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Socket::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_headers() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1("PROXY TCP4 192.0.2.1\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_header_consumed_exactly() {
+        let mut input = PROXY_V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        input.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        input.extend_from_slice(&4321u16.to_be_bytes());
+        input.extend_from_slice(&443u16.to_be_bytes());
+        input.extend_from_slice(b"GET /");
+        let mut stream = input.as_slice();
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:4321".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_header_consumed_exactly() {
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..];
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+        let mut stream = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_proxy_header(&mut stream).await.is_err());
+    }
+
+    #[test]
+    fn v2_local() {
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+        assert!(parse_v2(0x31, 0x11, &[]).is_err());
+    }
+}
@@ -1,9 +1,23 @@
+use crate::config::Grpc;
 use crate::error::{Error, Result};
+use crate::subscription::{ReqFilter, Subscription};
 use crate::{event::Event, nip05::Nip05Name};
 use nauthz_grpc::authorization_client::AuthorizationClient;
+use nauthz_grpc::connection_request::Header;
 use nauthz_grpc::event::TagEntry;
-use nauthz_grpc::{Decision, Event as GrpcEvent, EventReply, EventRequest};
-use tracing::{info, warn};
+use nauthz_grpc::filter::TagFilter;
+use nauthz_grpc::{
+    ConnectionReply, ConnectionRequest, Decision, Event as GrpcEvent, EventReply, EventRequest,
+    Filter, ReqReply, ReqRequest,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tracing::{debug, info, warn};
+
+/// Longest wait between rounds of connection attempts
+const MAX_RECONNECT_HOLDOFF: Duration = Duration::from_secs(60);
 
 pub mod nauthz_grpc {
     tonic::include_proto!("nauthz");
@@ -13,9 +27,35 @@ pub mod nauthz_grpc {
 pub trait AuthzDecision: Send + Sync {
     fn permitted(&self) -> bool;
     fn message(&self) -> Option<String>;
+    /// Should the client be told the event was saved, while it is
+    /// discarded?
+    fn shadow_rejected(&self) -> bool {
+        false
+    }
 }
 
 impl AuthzDecision for EventReply {
+    fn permitted(&self) -> bool {
+        self.decision == Decision::Permit as i32
+    }
+    fn shadow_rejected(&self) -> bool {
+        self.decision == Decision::ShadowReject as i32
+    }
+    fn message(&self) -> Option<String> {
+        self.message.clone()
+    }
+}
+
+impl AuthzDecision for ReqReply {
+    fn permitted(&self) -> bool {
+        self.decision == Decision::Permit as i32
+    }
+    fn message(&self) -> Option<String> {
+        self.message.clone()
+    }
+}
+
+impl AuthzDecision for ConnectionReply {
     fn permitted(&self) -> bool {
         self.decision == Decision::Permit as i32
     }
@@ -24,10 +64,48 @@ impl AuthzDecision for EventReply {
     }
 }
 
-// A connection to an event admission GRPC server
+impl ReqReply {
+    /// Filters that replace the requested ones, if the server
+    /// narrowed the subscription.
+    #[must_use]
+    pub fn rewritten_filters(&self) -> Option<Vec<ReqFilter>> {
+        if self.filters.is_empty() {
+            None
+        } else {
+            Some(self.filters.iter().map(ReqFilter::from).collect())
+        }
+    }
+}
+
+/// Cached decisions by pubkey and kind, with when they expire
+type DecisionCache = HashMap<(String, u64), (Instant, EventReply)>;
+
+/// The state of the connection to the server
+#[derive(Default)]
+struct Connection {
+    client: Option<AuthorizationClient<Channel>>,
+    /// Rounds of connection attempts that have failed in a row
+    failures: u32,
+    /// Do not try connecting again before this time
+    next_attempt: Option<Instant>,
+}
+
+// A connection to an event admission GRPC server.  Copies share the
+// connection, its reconnect holdoff, and the decision cache.
+#[derive(Clone)]
 pub struct EventAuthzService {
     server_addr: String,
-    conn: Option<AuthorizationClient<tonic::transport::Channel>>,
+    /// Locked while connecting, so only one copy tries at a time
+    conn: Arc<tokio::sync::Mutex<Connection>>,
+    /// Deadline for connecting, and for each call
+    timeout: Duration,
+    /// Connection attempts after the first, in each round
+    connect_retries: u32,
+    /// Delay before the first retry, doubled for each retry
+    retry_backoff: Duration,
+    /// Decisions the server allowed us to reuse, by pubkey and kind
+    cache: Arc<Mutex<DecisionCache>>,
+    cache_size: usize,
 }
 
 // conversion of Nip05Names into GRPC type
@@ -47,30 +125,185 @@ fn tags_to_protobuf(tags: &[Vec<String>]) -> Vec<TagEntry> {
         .collect()
 }
 
+// conversion of events into GRPC type
+pub fn event_to_protobuf(event: &Event) -> Result<GrpcEvent> {
+    Ok(GrpcEvent {
+        id: hex::decode(&event.id)?,
+        pubkey: hex::decode(&event.pubkey)?,
+        sig: hex::decode(&event.sig)?,
+        created_at: event.created_at,
+        kind: event.kind,
+        content: event.content.clone(),
+        tags: tags_to_protobuf(&event.tags),
+    })
+}
+
+// conversion of request filters into GRPC type.  Lists that are not
+// present become empty.
+impl std::convert::From<&ReqFilter> for Filter {
+    fn from(f: &ReqFilter) -> Self {
+        Filter {
+            ids: f.ids.clone().unwrap_or_default(),
+            authors: f.authors.clone().unwrap_or_default(),
+            kinds: f.kinds.clone().unwrap_or_default(),
+            since: f.since,
+            until: f.until,
+            limit: f.limit,
+            tags: f
+                .tags
+                .iter()
+                .flatten()
+                .map(|(name, values)| TagFilter {
+                    name: name.to_string(),
+                    values: values.iter().cloned().collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// conversion of GRPC filters into request filters.  Empty lists
+// become absent, and tag queries that are not a single letter
+// prevent any match, as they do for client filters.
+impl std::convert::From<&Filter> for ReqFilter {
+    fn from(f: &Filter) -> Self {
+        let non_empty = |v: &Vec<String>| (!v.is_empty()).then(|| v.clone());
+        let mut force_no_match = false;
+        let mut tags: HashMap<char, HashSet<String>> = HashMap::new();
+        for t in &f.tags {
+            let mut chars = t.name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => {
+                    tags.entry(c).or_default().extend(t.values.iter().cloned());
+                }
+                _ => force_no_match = true,
+            }
+        }
+        ReqFilter {
+            ids: non_empty(&f.ids),
+            kinds: (!f.kinds.is_empty()).then(|| f.kinds.clone()),
+            since: f.since,
+            until: f.until,
+            authors: non_empty(&f.authors),
+            limit: f.limit,
+            tags: (!tags.is_empty()).then_some(tags),
+            force_no_match,
+        }
+    }
+}
+
 impl EventAuthzService {
-    pub async fn connect(server_addr: &str) -> EventAuthzService {
-        let mut eas = EventAuthzService {
-            server_addr: server_addr.to_string(),
-            conn: None,
-        };
+    pub async fn connect(settings: &Grpc) -> EventAuthzService {
+        let eas = EventAuthzService::new(settings);
         eas.ready_connection().await;
         eas
     }
 
-    pub async fn ready_connection(&mut self) {
-        if self.conn.is_none() {
-            let client = AuthorizationClient::connect(self.server_addr.to_string()).await;
-            if let Err(ref msg) = client {
-                warn!("could not connect to nostr authz GRPC server: {:?}", msg);
-            } else {
-                info!("connected to nostr authorization GRPC server");
+    fn new(settings: &Grpc) -> EventAuthzService {
+        EventAuthzService {
+            server_addr: settings.event_admission_server.clone().unwrap_or_default(),
+            conn: Arc::new(tokio::sync::Mutex::new(Connection::default())),
+            timeout: Duration::from_millis(settings.timeout_ms),
+            connect_retries: settings.connect_retries,
+            retry_backoff: Duration::from_millis(settings.retry_backoff_ms),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            cache_size: settings.decision_cache_size,
+        }
+    }
+
+    async fn try_connect(&self) -> std::result::Result<AuthorizationClient<Channel>, String> {
+        let mut endpoint =
+            Endpoint::from_shared(self.server_addr.clone()).map_err(|e| e.to_string())?;
+        if !self.timeout.is_zero() {
+            endpoint = endpoint.connect_timeout(self.timeout).timeout(self.timeout);
+        }
+        let channel = endpoint.connect().await.map_err(|e| e.to_string())?;
+        Ok(AuthorizationClient::new(channel))
+    }
+
+    /// Connect to the server if we are not already, returning the
+    /// client.  Failed attempts are retried with a growing delay; if
+    /// every attempt fails, we hold off trying again for a while, so
+    /// callers are not stalled by a server that is down.
+    pub async fn ready_connection(&self) -> Option<AuthorizationClient<Channel>> {
+        let mut conn = self.conn.lock().await;
+        if conn.client.is_some() {
+            return conn.client.clone();
+        }
+        if let Some(next) = conn.next_attempt {
+            if Instant::now() < next {
+                return None;
+            }
+        }
+        let mut backoff = self.retry_backoff;
+        for attempt in 0..=self.connect_retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            match self.try_connect().await {
+                Ok(client) => {
+                    info!("connected to nostr authorization GRPC server");
+                    conn.client = Some(client);
+                    conn.failures = 0;
+                    conn.next_attempt = None;
+                    return conn.client.clone();
+                }
+                Err(msg) => {
+                    warn!(
+                        "could not connect to nostr authz GRPC server (attempt {}): {:?}",
+                        attempt + 1,
+                        msg
+                    );
+                }
+            }
+        }
+        conn.failures = conn.failures.saturating_add(1);
+        let holdoff = self
+            .retry_backoff
+            .saturating_mul(2u32.saturating_pow(conn.failures.min(16)))
+            .min(MAX_RECONNECT_HOLDOFF);
+        conn.next_attempt = Some(Instant::now() + holdoff);
+        None
+    }
+
+    /// Find a cached decision for this author and kind
+    fn cached_decision(&self, pubkey: &str, kind: u64) -> Option<EventReply> {
+        let key = (pubkey.to_owned(), kind);
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(&key) {
+            Some((expires, reply)) if Instant::now() < *expires => Some(reply.clone()),
+            Some(_) => {
+                cache.remove(&key);
+                None
             }
-            self.conn = client.ok();
+            None => None,
         }
     }
 
+    /// Remember a decision, if the server permitted caching it
+    fn cache_decision(&self, pubkey: &str, kind: u64, reply: &EventReply) {
+        let ttl = reply.cache_ttl_seconds.unwrap_or(0);
+        if ttl == 0 || self.cache_size == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.cache_size {
+            cache.retain(|_, (expires, _)| now < *expires);
+            if cache.len() >= self.cache_size {
+                debug!("GRPC decision cache is full");
+                return;
+            }
+        }
+        cache.insert(
+            (pubkey.to_owned(), kind),
+            (now + Duration::from_secs(u64::from(ttl)), reply.clone()),
+        );
+    }
+
     pub async fn admit_event(
-        &mut self,
+        &self,
         event: &Event,
         ip: &str,
         origin: Option<String>,
@@ -78,20 +311,12 @@ impl EventAuthzService {
         nip05: Option<Nip05Name>,
         auth_pubkey: Option<Vec<u8>>,
     ) -> Result<Box<dyn AuthzDecision>> {
-        self.ready_connection().await;
-        let id_blob = hex::decode(&event.id)?;
-        let pubkey_blob = hex::decode(&event.pubkey)?;
-        let sig_blob = hex::decode(&event.sig)?;
-        if let Some(ref mut c) = self.conn {
-            let gevent = GrpcEvent {
-                id: id_blob,
-                pubkey: pubkey_blob,
-                sig: sig_blob,
-                created_at: event.created_at,
-                kind: event.kind,
-                content: event.content.clone(),
-                tags: tags_to_protobuf(&event.tags),
-            };
+        if let Some(reply) = self.cached_decision(&event.pubkey, event.kind) {
+            return Ok(Box::new(reply));
+        }
+        let client = self.ready_connection().await;
+        let gevent = event_to_protobuf(event)?;
+        if let Some(mut c) = client {
             let svr_res = c
                 .event_admit(EventRequest {
                     event: Some(gevent),
@@ -103,9 +328,156 @@ impl EventAuthzService {
                 })
                 .await?;
             let reply = svr_res.into_inner();
+            self.cache_decision(&event.pubkey, event.kind, &reply);
             Ok(Box::new(reply))
         } else {
             Err(Error::AuthzError)
         }
     }
+
+    pub async fn admit_req(
+        &self,
+        sub: &Subscription,
+        ip: &str,
+        origin: Option<String>,
+        user_agent: Option<String>,
+        auth_pubkey: Option<Vec<u8>>,
+    ) -> Result<ReqReply> {
+        if let Some(mut c) = self.ready_connection().await {
+            let svr_res = c
+                .req_admit(ReqRequest {
+                    sub_id: sub.id.clone(),
+                    filters: sub.filters.iter().map(Filter::from).collect(),
+                    ip_addr: Some(ip.to_string()),
+                    origin,
+                    user_agent,
+                    auth_pubkey,
+                })
+                .await?;
+            Ok(svr_res.into_inner())
+        } else {
+            Err(Error::AuthzError)
+        }
+    }
+
+    pub async fn admit_connection(
+        &self,
+        ip: &str,
+        origin: Option<String>,
+        user_agent: Option<String>,
+        headers: Vec<(String, String)>,
+    ) -> Result<ConnectionReply> {
+        if let Some(mut c) = self.ready_connection().await {
+            let svr_res = c
+                .connection_admit(ConnectionRequest {
+                    ip_addr: Some(ip.to_string()),
+                    origin,
+                    user_agent,
+                    headers: headers
+                        .into_iter()
+                        .map(|(name, value)| Header { name, value })
+                        .collect(),
+                })
+                .await?;
+            Ok(svr_res.into_inner())
+        } else {
+            Err(Error::AuthzError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_round_trip() -> Result<()> {
+        let s: Subscription = serde_json::from_str(
+            r##"["REQ","sub",{"authors":["abc"],"kinds":[1,7],"since":10,"#e":["ff"]}]"##,
+        )?;
+        let f = &s.filters[0];
+        let g = Filter::from(f);
+        assert!(g.ids.is_empty());
+        assert_eq!(g.tags.len(), 1);
+        assert_eq!(&ReqFilter::from(&g), f);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_long_tag_name() {
+        let g = Filter {
+            tags: vec![TagFilter {
+                name: "long".to_owned(),
+                values: vec!["x".to_owned()],
+            }],
+            ..Default::default()
+        };
+        let f = ReqFilter::from(&g);
+        assert!(f.force_no_match);
+        assert_eq!(f.tags, None);
+    }
+
+    fn grpc_settings() -> Grpc {
+        let mut settings = crate::config::Settings::default().grpc;
+        // nothing listens on the discard port
+        settings.event_admission_server = Some("http://127.0.0.1:9".to_owned());
+        settings.connect_retries = 0;
+        settings.decision_cache_size = 1;
+        settings
+    }
+
+    fn reply(ttl: Option<u32>) -> EventReply {
+        EventReply {
+            decision: Decision::Deny as i32,
+            message: Some("no".to_owned()),
+            cache_ttl_seconds: ttl,
+        }
+    }
+
+    #[test]
+    fn decision_cache() {
+        let eas = EventAuthzService::new(&grpc_settings());
+        // no ttl, no caching
+        eas.cache_decision("abc", 1, &reply(None));
+        assert!(eas.cached_decision("abc", 1).is_none());
+        eas.cache_decision("abc", 1, &reply(Some(60)));
+        let cached = eas.cached_decision("abc", 1).unwrap();
+        assert!(!cached.permitted());
+        assert!(eas.cached_decision("abc", 7).is_none());
+        // the cache is full
+        eas.cache_decision("def", 1, &reply(Some(60)));
+        assert!(eas.cached_decision("def", 1).is_none());
+    }
+
+    #[test]
+    fn shadow_reject_decision() {
+        let mut r = reply(None);
+        assert!(!r.shadow_rejected());
+        r.decision = Decision::ShadowReject as i32;
+        assert!(r.shadow_rejected());
+        assert!(!r.permitted());
+    }
+
+    #[tokio::test]
+    async fn reconnect_holdoff() {
+        let eas = EventAuthzService::connect(&grpc_settings()).await;
+        let next = eas.conn.lock().await.next_attempt.unwrap();
+        // a second attempt is not made until the holdoff passes, by
+        // this or any other copy
+        let copy = eas.clone();
+        assert!(copy.ready_connection().await.is_none());
+        assert!(eas.ready_connection().await.is_none());
+        let conn = eas.conn.lock().await;
+        assert!(conn.client.is_none());
+        assert_eq!(conn.next_attempt, Some(next));
+        assert_eq!(conn.failures, 1);
+    }
+
+    #[test]
+    fn copies_share_decisions() {
+        let eas = EventAuthzService::new(&grpc_settings());
+        let copy = eas.clone();
+        eas.cache_decision("abc", 1, &reply(Some(60)));
+        assert!(copy.cached_decision("abc", 1).is_some());
+    }
 }
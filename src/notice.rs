@@ -1,3 +1,5 @@
+use crate::event::Event;
+
 pub enum EventResultStatus {
     Saved,
     Duplicate,
@@ -6,6 +8,9 @@ pub enum EventResultStatus {
     RateLimited,
     Error,
     Restricted,
+    Pow,
+    Pending,
+    AuthRequired,
 }
 
 pub struct EventResult {
@@ -17,6 +22,8 @@ pub struct EventResult {
 pub enum Notice {
     Message(String),
     EventResult(EventResult),
+    /// An event reported to its author as saved, but discarded
+    ShadowRejected(Box<Event>),
     AuthChallenge(String),
 }
 
@@ -24,10 +31,14 @@ impl EventResultStatus {
     #[must_use]
     pub fn to_bool(&self) -> bool {
         match self {
-            Self::Duplicate | Self::Saved => true,
-            Self::Invalid | Self::Blocked | Self::RateLimited | Self::Error | Self::Restricted => {
-                false
-            }
+            Self::Duplicate | Self::Saved | Self::Pending => true,
+            Self::Invalid
+            | Self::Blocked
+            | Self::RateLimited
+            | Self::Error
+            | Self::Restricted
+            | Self::Pow
+            | Self::AuthRequired => false,
         }
     }
 
@@ -41,6 +52,9 @@ impl EventResultStatus {
             Self::RateLimited => "rate-limited",
             Self::Error => "error",
             Self::Restricted => "restricted",
+            Self::Pow => "pow",
+            Self::Pending => "pending",
+            Self::AuthRequired => "auth-required",
         }
     }
 }
@@ -90,6 +104,30 @@ impl Notice {
         Notice::prefixed(id, msg, EventResultStatus::Restricted)
     }
 
+    #[must_use]
+    pub fn auth_required(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::AuthRequired)
+    }
+
+    #[must_use]
+    pub fn pow(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Pow)
+    }
+
+    #[must_use]
+    pub fn pending(id: String) -> Notice {
+        Notice::prefixed(
+            id,
+            "awaiting moderator approval",
+            EventResultStatus::Pending,
+        )
+    }
+
+    #[must_use]
+    pub fn shadow_rejected(event: Event) -> Notice {
+        Notice::ShadowRejected(Box::new(event))
+    }
+
     #[must_use]
     pub fn saved(id: String) -> Notice {
         Notice::EventResult(EventResult {
@@ -98,4 +136,14 @@ impl Notice {
             status: EventResultStatus::Saved,
         })
     }
+
+    /// An event was accepted, with a message for the client.
+    #[must_use]
+    pub fn accepted(id: String, msg: &str) -> Notice {
+        Notice::EventResult(EventResult {
+            id,
+            msg: msg.into(),
+            status: EventResultStatus::Saved,
+        })
+    }
 }
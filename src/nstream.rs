@@ -0,0 +1,232 @@
+//! GRPC service streaming accepted events to downstream consumers
+use crate::event::Event;
+use crate::nauthz::event_to_protobuf;
+use crate::repo::NostrRepo;
+use crate::subscription::{ReqFilter, Subscription};
+use nstream_grpc::event_stream_server::{EventStream, EventStreamServer};
+use nstream_grpc::{Cursor, StreamEvent, StreamRequest};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::{debug, info, warn};
+
+// the stream messages refer to types from the nauthz package
+use crate::nauthz::nauthz_grpc as nauthz;
+
+pub mod nstream_grpc {
+    tonic::include_proto!("nstream");
+}
+
+/// Events buffered for each consumer before replay is slowed
+const STREAM_BUFFER: usize = 1024;
+
+/// Stored events read at a time during replay
+const REPLAY_PAGE: u64 = 500;
+
+/// Streams stored and newly accepted events over GRPC
+pub struct EventStreamService {
+    repo: Arc<dyn NostrRepo>,
+    broadcast: Sender<Event>,
+}
+
+impl EventStreamService {
+    #[must_use]
+    pub fn new(repo: Arc<dyn NostrRepo>, broadcast: Sender<Event>) -> EventStreamService {
+        EventStreamService { repo, broadcast }
+    }
+}
+
+/// Build the stream message for an event, if it can be represented
+fn stream_event(event: &Event, live: bool) -> Option<StreamEvent> {
+    let gevent = event_to_protobuf(event)
+        .map_err(|e| warn!("could not stream event {:?}: {:?}", event.id, e))
+        .ok()?;
+    Some(StreamEvent {
+        cursor: Some(Cursor {
+            created_at: event.created_at,
+            id: gevent.id.clone(),
+        }),
+        event: Some(gevent),
+        live,
+    })
+}
+
+/// Replay stored events after the cursor, then forward live events,
+/// until the consumer goes away.
+async fn run_stream(
+    repo: Arc<dyn NostrRepo>,
+    sub: Subscription,
+    cursor: Option<(u64, String)>,
+    mut bcast_rx: Receiver<Event>,
+    tx: mpsc::Sender<Result<StreamEvent, Status>>,
+) {
+    // stored events are read a page at a time, in (created_at, id)
+    // order, each page starting after the last event sent.
+    let mut after = cursor;
+    loop {
+        let page = match repo
+            .replay_events(&sub.filters, after.clone(), REPLAY_PAGE)
+            .await
+        {
+            Ok(page) => page,
+            Err(e) => {
+                tx.send(Err(Status::internal(format!("replay failed: {e}"))))
+                    .await
+                    .ok();
+                return;
+            }
+        };
+        let last_page = (page.len() as u64) < REPLAY_PAGE;
+        for event in page {
+            after = Some((event.created_at, event.id.clone()));
+            if let Some(se) = stream_event(&event, false) {
+                if tx.send(Ok(se)).await.is_err() {
+                    debug!("event stream consumer went away during replay");
+                    return;
+                }
+            }
+        }
+        if last_page {
+            break;
+        }
+    }
+    loop {
+        tokio::select! {
+            _ = tx.closed() => {
+                debug!("event stream consumer went away");
+                return;
+            },
+            res = bcast_rx.recv() => {
+                match res {
+                    Ok(event) => {
+                        if !sub.interested_in_event(&event) {
+                            continue;
+                        }
+                        // events stored while replaying were already
+                        // sent, if they sort at or before the last one
+                        if after.as_ref().is_some_and(|(created_at, id)| {
+                            (event.created_at, &event.id) <= (*created_at, id)
+                        }) {
+                            continue;
+                        }
+                        if let Some(se) = stream_event(&event, true) {
+                            if tx.send(Ok(se)).await.is_err() {
+                                return;
+                            }
+                        }
+                    },
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("event stream consumer lagged, dropped {} events", n);
+                        tx.send(Err(Status::data_loss(format!("consumer lagged, {n} events were dropped")))).await.ok();
+                        return;
+                    },
+                    Err(RecvError::Closed) => {
+                        return;
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl EventStream for EventStreamService {
+    type StreamEventsStream = ReceiverStream<Result<StreamEvent, Status>>;
+
+    async fn stream_events(
+        &self,
+        request: Request<StreamRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let req = request.into_inner();
+        let cursor = req.resume_after.map(|c| (c.created_at, hex::encode(c.id)));
+        let mut filters: Vec<ReqFilter> = req.filters.iter().map(ReqFilter::from).collect();
+        if filters.is_empty() {
+            filters.push(ReqFilter::from(&nauthz::Filter::default()));
+        }
+        for f in &mut filters {
+            // limits do not apply to a stream
+            f.limit = None;
+            if let Some((created_at, _)) = cursor {
+                f.since = Some(f.since.map_or(created_at, |s| s.max(created_at)));
+            }
+        }
+        let sub = Subscription {
+            id: "grpc-stream".to_owned(),
+            filters,
+        };
+        debug!("event stream requested (filters: {})", sub.filters.len());
+        // subscribe before replaying, so no events are missed in
+        // between.
+        let bcast_rx = self.broadcast.subscribe();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(run_stream(self.repo.clone(), sub, cursor, bcast_rx, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Serve the event stream until shutdown is requested
+pub async fn serve(
+    addr: SocketAddr,
+    repo: Arc<dyn NostrRepo>,
+    broadcast: Sender<Event>,
+    mut shutdown: Receiver<()>,
+) {
+    info!("GRPC event stream listening on: {}", addr);
+    let svc = EventStreamServer::new(EventStreamService::new(repo, broadcast));
+    let res = tonic::transport::Server::builder()
+        .add_service(svc)
+        .serve_with_shutdown(addr, async move {
+            shutdown.recv().await.ok();
+        })
+        .await;
+    if let Err(e) = res {
+        warn!("GRPC event stream server error: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    fn event(id: char, created_at: u64) -> Event {
+        let mut event = Event::simple_event();
+        event.id = id.to_string().repeat(64);
+        event.pubkey = "a".repeat(64);
+        event.sig = "b".repeat(128);
+        event.kind = 1;
+        event.created_at = created_at;
+        event
+    }
+
+    #[tokio::test]
+    async fn replayed_events_not_sent_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let repo = crate::db::build_repo(&settings, crate::server::create_metrics().1).await;
+        let (bcast_tx, bcast_rx) = tokio::sync::broadcast::channel(16);
+        // stored after the stream subscribed, so both replayed and
+        // broadcast
+        repo.write_event(&event('2', 20)).await.unwrap();
+        bcast_tx.send(event('2', 20)).unwrap();
+        // a later event that was not stored in time to be replayed
+        bcast_tx.send(event('3', 30)).unwrap();
+        let sub = Subscription {
+            id: "test".to_owned(),
+            filters: vec![ReqFilter::from(&nauthz::Filter::default())],
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+        tokio::spawn(run_stream(repo, sub, None, bcast_rx, tx));
+        let replayed = rx.recv().await.unwrap().unwrap();
+        assert_eq!(replayed.cursor.unwrap().created_at, 20);
+        assert!(!replayed.live);
+        let live = rx.recv().await.unwrap().unwrap();
+        assert_eq!(live.cursor.unwrap().created_at, 30);
+        assert!(live.live);
+    }
+}
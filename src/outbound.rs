@@ -0,0 +1,267 @@
+//! Per-connection queue of messages waiting to be sent to a client
+//!
+//! Messages are written to the websocket by a separate task, so that a
+//! slow client does not hold up the connection's own processing.  The
+//! queue has a byte budget; live events that would exceed it are
+//! refused, and the connection decides what to do about that.  Other
+//! replies may run over the budget, but not past twice it; a client
+//! that lets them pile up that far is marked as overflowed.
+use crate::deflate::MessageDeflater;
+use futures::{Sink, SinkExt};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::debug;
+use tungstenite::protocol::Message;
+
+/// Time allowed for each remaining message to be written once the
+/// connection is done with the queue
+const CLOSING_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message waiting to be sent
+struct Queued {
+    msg: Message,
+    /// Live events may be dropped when the queue is full
+    live: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Queued>,
+    bytes: usize,
+    /// No more messages will be sent
+    closed: bool,
+    /// A reply was refused because the queue had grown too large
+    overflowed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, msg: Message, live: bool) {
+        if self.closed {
+            return;
+        }
+        self.bytes += msg.len();
+        self.messages.push_back(Queued { msg, live });
+    }
+
+    /// Drop the oldest queued live event, returning false if there
+    /// was none.
+    fn drop_oldest_live(&mut self) -> bool {
+        match self.messages.iter().position(|q| q.live) {
+            Some(i) => {
+                if let Some(q) = self.messages.remove(i) {
+                    self.bytes -= q.msg.len();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled when messages are queued, or the queue is closed
+    ready: Notify,
+    /// Signalled when a message has been written
+    drained: Notify,
+}
+
+/// The sending half of a client connection
+pub struct Outbound {
+    shared: Arc<Shared>,
+    budget: usize,
+}
+
+impl Outbound {
+    /// Start writing queued messages to a websocket sink, compressing
+    /// them if a deflater is given.  Up to `budget` bytes of live
+    /// events may be queued.
+    pub fn new<S>(sink: S, budget: usize, deflater: Option<MessageDeflater>) -> Outbound
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: Debug,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            ready: Notify::new(),
+            drained: Notify::new(),
+        });
+        tokio::spawn(write_messages(shared.clone(), sink, deflater));
+        Outbound { shared, budget }
+    }
+
+    /// Queue a message that is not a live event.  These may use up
+    /// to twice the budget; past that the message is discarded and
+    /// the queue is marked as overflowed.
+    pub fn push(&self, msg: Message) {
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.bytes + msg.len() > self.budget.saturating_mul(2) {
+                queue.overflowed = true;
+                return;
+            }
+            queue.push(msg, false);
+        }
+        self.shared.ready.notify_one();
+    }
+
+    /// Queue a live event if it fits within the budget.  Returns
+    /// false if it was refused.
+    pub fn push_live(&self, msg: Message) -> bool {
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.bytes + msg.len() > self.budget {
+                return false;
+            }
+            queue.push(msg, true);
+        }
+        self.shared.ready.notify_one();
+        true
+    }
+
+    /// Queue a live event, dropping the oldest queued live events to
+    /// make room.  Returns the number of events dropped, which
+    /// includes this one if it could not fit at all.
+    pub fn push_live_dropping_oldest(&self, msg: Message) -> u64 {
+        let mut dropped = 0;
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            while queue.bytes + msg.len() > self.budget {
+                if !queue.drop_oldest_live() {
+                    return dropped + 1;
+                }
+                dropped += 1;
+            }
+            queue.push(msg, true);
+        }
+        self.shared.ready.notify_one();
+        dropped
+    }
+
+    /// Has the queue used up its budget?
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.shared.queue.lock().unwrap().bytes >= self.budget
+    }
+
+    /// Has a reply been discarded because the client stopped reading?
+    /// The connection should be closed, since the client has missed
+    /// messages it was owed.
+    #[must_use]
+    pub fn overflowed(&self) -> bool {
+        self.shared.queue.lock().unwrap().overflowed
+    }
+
+    /// Wait for a queued message to be written.
+    pub async fn drained(&self) {
+        self.shared.drained.notified().await;
+    }
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        // the writer sends anything still queued, then closes.
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+/// Write queued messages to the sink, until the queue is closed and
+/// empty, or the sink fails.
+async fn write_messages<S>(shared: Arc<Shared>, mut sink: S, deflater: Option<MessageDeflater>)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: Debug,
+{
+    loop {
+        let (next, closed) = {
+            let mut queue = shared.queue.lock().unwrap();
+            match queue.messages.pop_front() {
+                Some(q) => {
+                    queue.bytes -= q.msg.len();
+                    (Some(q.msg), queue.closed)
+                }
+                None if queue.closed => break,
+                None => (None, false),
+            }
+        };
+        match next {
+            Some(msg) => {
+                let msg = match &deflater {
+                    Some(d) => d.encode(msg),
+                    None => msg,
+                };
+                let res = if closed {
+                    // a client that stopped reading should not keep
+                    // this task around.
+                    match tokio::time::timeout(CLOSING_WRITE_TIMEOUT, sink.send(msg)).await {
+                        Ok(res) => res,
+                        Err(_) => return,
+                    }
+                } else {
+                    sink.send(msg).await
+                };
+                shared.drained.notify_one();
+                if let Err(e) = res {
+                    debug!("could not write to client: {:?}", e);
+                    let mut queue = shared.queue.lock().unwrap();
+                    queue.closed = true;
+                    queue.messages.clear();
+                    queue.bytes = 0;
+                    return;
+                }
+            }
+            None => shared.ready.notified().await,
+        }
+    }
+    sink.close().await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(len: usize) -> Message {
+        Message::Text("x".repeat(len))
+    }
+
+    #[test]
+    fn drop_oldest_live_keeps_control_messages() {
+        let mut queue = Queue::default();
+        queue.push(text(10), false);
+        queue.push(text(20), true);
+        queue.push(text(30), true);
+        assert!(queue.drop_oldest_live());
+        assert_eq!(queue.bytes, 40);
+        assert!(queue.drop_oldest_live());
+        assert!(!queue.drop_oldest_live());
+        assert_eq!(queue.bytes, 10);
+    }
+
+    #[tokio::test]
+    async fn live_events_respect_budget() {
+        // a sink that never accepts messages
+        let (tx, _rx) = futures::channel::mpsc::channel::<Message>(0);
+        let outbound = Outbound::new(tx, 100, None);
+        outbound.push(text(50));
+        assert!(outbound.push_live(text(40)));
+        assert!(!outbound.push_live(text(40)));
+        assert_eq!(outbound.push_live_dropping_oldest(text(40)), 1);
+        assert_eq!(outbound.push_live_dropping_oldest(text(200)), 2);
+    }
+
+    #[tokio::test]
+    async fn replies_limited_to_twice_budget() {
+        let (tx, _rx) = futures::channel::mpsc::channel::<Message>(0);
+        let outbound = Outbound::new(tx, 100, None);
+        outbound.push(text(150));
+        assert!(!outbound.overflowed());
+        outbound.push(text(40));
+        assert!(!outbound.overflowed());
+        outbound.push(text(20));
+        assert!(outbound.overflowed());
+    }
+}
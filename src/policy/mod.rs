@@ -0,0 +1,178 @@
+//! Event admission policies evaluated by the relay itself
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::nip05::Nip05Name;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+pub mod plugin;
+pub mod rules;
+pub mod script;
+
+/// How often to check if a policy file has changed
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What should be done with an event a policy has examined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// Admit this event for further processing
+    Accept,
+    /// Deny this event, informing the client
+    Reject,
+    /// Tell the client this event was saved, but discard it
+    ShadowReject,
+    /// Admit this event, but log it for moderators
+    Flag,
+}
+
+impl FromStr for PolicyAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "accept" => Ok(PolicyAction::Accept),
+            "reject" => Ok(PolicyAction::Reject),
+            "shadowReject" => Ok(PolicyAction::ShadowReject),
+            "flag" => Ok(PolicyAction::Flag),
+            _ => Err(Error::CustomError(format!("unknown policy action: {s}"))),
+        }
+    }
+}
+
+/// A decision from a policy, with an optional message for the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub action: PolicyAction,
+    pub message: Option<String>,
+}
+
+/// Event data and metadata for policy decisions.  This carries the
+/// same information as the GRPC `EventRequest`.
+pub struct PolicyRequest<'a> {
+    /// The event to be admitted for further relay processing
+    pub event: &'a Event,
+    /// IP address of the client that submitted the event
+    pub ip_addr: &'a str,
+    /// HTTP origin header from the client, if one exists
+    pub origin: Option<&'a str>,
+    /// HTTP user-agent header from the client, if one exists
+    pub user_agent: Option<&'a str>,
+    /// The public key associated with a NIP-42 AUTH'd session
+    pub auth_pubkey: Option<&'a [u8]>,
+    /// Validated NIP-05 address associated with the event pubkey
+    pub nip05: Option<&'a Nip05Name>,
+}
+
+/// A policy file, watched for changes so that it can be reloaded.
+/// The file is checked at most once a second.
+pub struct FileWatch {
+    path: PathBuf,
+    /// Modification time of the file that was loaded
+    modified: Option<SystemTime>,
+    /// Last time we checked for a modified file
+    last_check: Instant,
+}
+
+impl FileWatch {
+    /// Watch a file, as it is being loaded.
+    #[must_use]
+    pub fn new(path: PathBuf) -> FileWatch {
+        let modified = modified_time(&path);
+        FileWatch {
+            path,
+            modified,
+            last_check: Instant::now(),
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Note that the file is being loaded again.
+    pub fn loaded(&mut self) {
+        self.modified = modified_time(&self.path);
+    }
+
+    /// Has the file changed since it was loaded?  A change is only
+    /// reported once.
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < RELOAD_CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempPath;
+
+    /// Settings for a policy read from a file
+    pub trait PolicyFixture {
+        /// Suffix of the policy file
+        const SUFFIX: &'static str;
+
+        /// Settings using the policy file at `path`
+        fn with_path(path: String) -> Self;
+    }
+
+    /// Write a policy file, and settings that use it.  The file is
+    /// deleted when the returned path is dropped.
+    pub fn fixture<S: PolicyFixture>(source: &str) -> (S, TempPath) {
+        let mut f = tempfile::Builder::new()
+            .prefix("nostr-rs-relay-policy-")
+            .suffix(S::SUFFIX)
+            .tempfile()
+            .unwrap();
+        f.write_all(source.as_bytes()).unwrap();
+        // plugins are run directly
+        f.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let path = f.into_temp_path();
+        (S::with_path(path.to_string_lossy().to_string()), path)
+    }
+
+    /// A request to admit an event from a local client
+    pub fn request(event: &Event) -> PolicyRequest<'_> {
+        PolicyRequest {
+            event,
+            ip_addr: "127.0.0.1",
+            origin: None,
+            user_agent: Some("test"),
+            auth_pubkey: None,
+            nip05: None,
+        }
+    }
+
+    #[test]
+    fn file_watch_sees_changes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut watch = FileWatch::new(file.path().to_path_buf());
+        let later = SystemTime::now() + Duration::from_secs(60);
+        file.as_file().set_modified(later).unwrap();
+        // not checked again within the interval
+        assert!(!watch.changed());
+        watch.last_check -= RELOAD_CHECK_INTERVAL;
+        assert!(watch.changed());
+        // and each change is reported once
+        watch.last_check -= RELOAD_CHECK_INTERVAL;
+        assert!(!watch.changed());
+    }
+}
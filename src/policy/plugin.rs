@@ -0,0 +1,241 @@
+//! Write-policy plugins, speaking the strfry plugin protocol
+//!
+//! The plugin is a long-running process that reads one JSON request
+//! per line on stdin, and writes one JSON response per line on
+//! stdout, so existing strfry plugins can be used unchanged.
+use crate::config::WritePolicy;
+use crate::error::{Error, Result};
+use crate::policy::{FileWatch, PolicyDecision, PolicyRequest};
+use crate::utils::unix_time;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::{info, warn};
+
+/// A plugin response to a single event
+#[derive(Deserialize, Debug)]
+struct PluginReply {
+    id: String,
+    action: String,
+    msg: Option<String>,
+}
+
+/// A running plugin process
+struct PluginProcess {
+    // held so the process is killed when dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+/// A supervised write-policy plugin.  The process is started on
+/// demand, restarted if it exits, fails or times out, and restarted
+/// when the plugin file changes.
+pub struct PluginPolicy {
+    watch: FileWatch,
+    timeout: Duration,
+    process: Option<PluginProcess>,
+}
+
+impl PluginPolicy {
+    #[must_use]
+    pub fn new(settings: &WritePolicy) -> PluginPolicy {
+        PluginPolicy {
+            watch: FileWatch::new(settings.plugin.clone().unwrap_or_default().into()),
+            timeout: Duration::from_millis(settings.timeout_ms),
+            process: None,
+        }
+    }
+
+    /// Stop the plugin if the file has changed, so it will be
+    /// restarted with the new version.
+    fn reload_if_modified(&mut self) {
+        if self.process.is_some() && self.watch.changed() {
+            info!(
+                "write policy plugin changed, restarting: {:?}",
+                self.watch.path()
+            );
+            self.process = None;
+        }
+    }
+
+    fn start(&mut self) -> Result<&mut PluginProcess> {
+        if self.process.is_none() {
+            let mut child = Command::new(self.watch.path())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let stdin = child.stdin.take().ok_or(Error::ChannelClosed)?;
+            let stdout = child.stdout.take().ok_or(Error::ChannelClosed)?;
+            info!("started write policy plugin: {:?}", self.watch.path());
+            self.watch.loaded();
+            self.process = Some(PluginProcess {
+                _child: child,
+                stdin,
+                stdout: BufReader::new(stdout).lines(),
+            });
+        }
+        self.process.as_mut().ok_or(Error::ChannelClosed)
+    }
+
+    /// Ask the plugin for a decision on an event
+    pub async fn admit_event(&mut self, req: &PolicyRequest<'_>) -> Result<PolicyDecision> {
+        self.reload_if_modified();
+        let line = format!("{}\n", request_json(req));
+        let id = req.event.id.clone();
+        let timeout = self.timeout;
+        let process = self.start()?;
+        let res = tokio::time::timeout(timeout, exchange(process, &line, &id)).await;
+        match res {
+            Ok(Ok(reply)) => Ok(PolicyDecision {
+                action: reply.action.parse()?,
+                message: reply.msg,
+            }),
+            Ok(Err(e)) => {
+                // the plugin is broken or exited; restart it next time
+                warn!("write policy plugin failed: {:?}", e);
+                self.process = None;
+                Err(e)
+            }
+            Err(_) => {
+                // a late reply would still be waiting in the pipe;
+                // start over with a fresh process.
+                warn!("write policy plugin timed out, restarting");
+                self.process = None;
+                Err(Error::CustomError(format!(
+                    "write policy plugin timed out after {timeout:?}"
+                )))
+            }
+        }
+    }
+}
+
+/// Send a request, and wait for the reply for this event.  Replies
+/// for other events (late answers to requests that timed out) are
+/// discarded.
+async fn exchange(process: &mut PluginProcess, line: &str, id: &str) -> Result<PluginReply> {
+    process.stdin.write_all(line.as_bytes()).await?;
+    process.stdin.flush().await?;
+    loop {
+        let reply_line = process
+            .stdout
+            .next_line()
+            .await?
+            .ok_or_else(|| Error::CustomError("write policy plugin exited".into()))?;
+        let reply: PluginReply = serde_json::from_str(&reply_line)?;
+        if reply.id == id {
+            return Ok(reply);
+        }
+        warn!("discarding write policy reply for event: {:?}", reply.id);
+    }
+}
+
+/// Build a strfry-style plugin request
+fn request_json(req: &PolicyRequest) -> serde_json::Value {
+    let source_type = match req.ip_addr.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => "IP6",
+        _ => "IP4",
+    };
+    json!({
+        "type": "new",
+        "event": req.event,
+        "receivedAt": unix_time(),
+        "sourceType": source_type,
+        "sourceInfo": req.ip_addr,
+        "origin": req.origin,
+        "userAgent": req.user_agent,
+        "authPubkey": req.auth_pubkey.map(hex::encode),
+        "nip05": req.nip05.map(|n| n.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::policy::tests::{fixture, request, PolicyFixture};
+    use crate::policy::PolicyAction;
+
+    impl PolicyFixture for WritePolicy {
+        const SUFFIX: &'static str = ".sh";
+
+        fn with_path(path: String) -> Self {
+            WritePolicy {
+                plugin: Some(path),
+                timeout_ms: 1000,
+                fail_open: true,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plugin_decisions() -> Result<()> {
+        // reject everything, echoing the event id back
+        let (settings, _file) = fixture::<WritePolicy>(
+            r#"#!/bin/sh
+while read -r line; do
+  id=$(echo "$line" | sed 's/.*"id":"\([0-9a-f]*\)".*/\1/')
+  echo "{\"id\":\"$id\",\"action\":\"reject\",\"msg\":\"blocked: no\"}"
+done
+"#,
+        );
+        let mut policy = PluginPolicy::new(&settings);
+        let mut event = Event::simple_event();
+        event.id = "abcd".to_owned();
+        let decision = policy.admit_event(&request(&event)).await?;
+        assert_eq!(decision.action, PolicyAction::Reject);
+        assert_eq!(decision.message, Some("blocked: no".to_owned()));
+        // the same process answers later requests
+        event.id = "1234".to_owned();
+        let decision = policy.admit_event(&request(&event)).await?;
+        assert_eq!(decision.action, PolicyAction::Reject);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plugin_timeout() {
+        let (mut settings, _file) = fixture::<WritePolicy>("#!/bin/sh\nsleep 10\n");
+        settings.timeout_ms = 100;
+        let mut policy = PluginPolicy::new(&settings);
+        let event = Event::simple_event();
+        assert!(policy.admit_event(&request(&event)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn plugin_restarted_after_timeout() -> Result<()> {
+        // the first process hangs; later ones accept everything
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("started");
+        let (mut settings, _file) = fixture::<WritePolicy>(&format!(
+            r#"#!/bin/sh
+if [ ! -e {marker} ]; then touch {marker}; sleep 10; fi
+while read -r line; do
+  id=$(echo "$line" | sed 's/.*"id":"\([0-9a-f]*\)".*/\1/')
+  echo "{{\"id\":\"$id\",\"action\":\"accept\"}}"
+done
+"#,
+            marker = marker.display()
+        ));
+        settings.timeout_ms = 200;
+        let mut policy = PluginPolicy::new(&settings);
+        let mut event = Event::simple_event();
+        event.id = "abcd".to_owned();
+        assert!(policy.admit_event(&request(&event)).await.is_err());
+        let decision = policy.admit_event(&request(&event)).await?;
+        assert_eq!(decision.action, PolicyAction::Accept);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plugin_exited() {
+        let (settings, _file) = fixture::<WritePolicy>("#!/bin/sh\nexit 0\n");
+        let mut policy = PluginPolicy::new(&settings);
+        let event = Event::simple_event();
+        assert!(policy.admit_event(&request(&event)).await.is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Event admission decisions made by content-matching rules
+//!
+//! Rules are read from a TOML file, which is reloaded whenever it
+//! changes.  The file may also hold sample events, each naming the
+//! rule it should match; a rule set is only used once every sample
+//! gives the expected result.
+use crate::config::ContentRules;
+use crate::error::{Error, Result};
+use crate::policy::{FileWatch, PolicyAction, PolicyDecision, PolicyRequest};
+use config::{Config, File, FileFormat};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// Message for events rejected by a rule without one
+const DEFAULT_MESSAGE: &str = "content is not allowed";
+
+lazy_static! {
+    static ref URL_RE: Regex = Regex::new(r"(?i)\bhttps?://\S+").unwrap();
+    static ref MENTION_RE: Regex = Regex::new(r"\bnostr:n(?:pub|profile)1[a-z0-9]+").unwrap();
+}
+
+/// Contents of a rules file
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+    #[serde(default)]
+    sample: Vec<Sample>,
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    name: String,
+    kinds: Option<Vec<u64>>,
+    content: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    tags: Vec<TagPatternConfig>,
+    max_urls: Option<usize>,
+    max_mentions: Option<usize>,
+    action: String,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagPatternConfig {
+    name: String,
+    pattern: String,
+}
+
+/// An event for testing rules, and the rule it should match
+#[derive(Deserialize)]
+struct Sample {
+    #[serde(default = "default_sample_kind")]
+    kind: u64,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tags: Vec<Vec<String>>,
+    expect: Option<String>,
+}
+
+fn default_sample_kind() -> u64 {
+    1
+}
+
+/// A rule matches an event if every condition it has holds.
+struct Rule {
+    name: String,
+    kinds: Option<Vec<u64>>,
+    content: Option<Regex>,
+    /// Lowercase words, any of which may appear in the content
+    keywords: Vec<String>,
+    /// A tag with this name must have a value matching the pattern
+    tags: Vec<(String, Regex)>,
+    max_urls: Option<usize>,
+    max_mentions: Option<usize>,
+    action: PolicyAction,
+    message: Option<String>,
+}
+
+fn compile_regex(rule: &str, pattern: &str) -> Result<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| Error::CustomError(format!("rule {rule:?} has an invalid pattern: {e}")))
+}
+
+impl Rule {
+    fn compile(c: RuleConfig) -> Result<Rule> {
+        if c.content.is_none()
+            && c.keywords.is_empty()
+            && c.tags.is_empty()
+            && c.max_urls.is_none()
+            && c.max_mentions.is_none()
+        {
+            return Err(Error::CustomError(format!(
+                "rule {:?} has no conditions",
+                c.name
+            )));
+        }
+        let action = PolicyAction::from_str(&c.action)?;
+        let content = match &c.content {
+            Some(p) => Some(compile_regex(&c.name, p)?),
+            None => None,
+        };
+        let mut tags = vec![];
+        for t in &c.tags {
+            tags.push((t.name.clone(), compile_regex(&c.name, &t.pattern)?));
+        }
+        Ok(Rule {
+            keywords: c.keywords.iter().map(|k| k.to_lowercase()).collect(),
+            name: c.name,
+            kinds: c.kinds,
+            content,
+            tags,
+            max_urls: c.max_urls,
+            max_mentions: c.max_mentions,
+            action,
+            message: c.message,
+        })
+    }
+
+    fn matches(&self, kind: u64, content: &str, tags: &[Vec<String>]) -> bool {
+        if self.kinds.as_ref().is_some_and(|k| !k.contains(&kind)) {
+            return false;
+        }
+        if self
+            .content
+            .as_ref()
+            .is_some_and(|re| !re.is_match(content))
+        {
+            return false;
+        }
+        if !self.keywords.is_empty() {
+            let lower = content.to_lowercase();
+            if !self.keywords.iter().any(|k| lower.contains(k.as_str())) {
+                return false;
+            }
+        }
+        for (name, re) in &self.tags {
+            let found = tags
+                .iter()
+                .any(|t| t.len() > 1 && &t[0] == name && re.is_match(&t[1]));
+            if !found {
+                return false;
+            }
+        }
+        if let Some(max) = self.max_urls {
+            if URL_RE.find_iter(content).count() <= max {
+                return false;
+            }
+        }
+        if let Some(max) = self.max_mentions {
+            let p_tags = tags.iter().filter(|t| t.len() > 1 && t[0] == "p").count();
+            if p_tags + MENTION_RE.find_iter(content).count() <= max {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The first rule matching an event
+fn first_match<'a>(
+    rules: &'a [Rule],
+    kind: u64,
+    content: &str,
+    tags: &[Vec<String>],
+) -> Option<&'a Rule> {
+    rules.iter().find(|r| r.matches(kind, content, tags))
+}
+
+/// Read and compile a rules file, checking it against its samples.
+fn load_rules(path: &Path) -> Result<Vec<Rule>> {
+    let file: RuleFile = Config::builder()
+        .add_source(File::from(path).format(FileFormat::Toml))
+        .build()
+        .and_then(Config::try_deserialize)
+        .map_err(|e| Error::CustomError(format!("could not read content rules: {e}")))?;
+    let rules = file
+        .rule
+        .into_iter()
+        .map(Rule::compile)
+        .collect::<Result<Vec<Rule>>>()?;
+    for (n, s) in file.sample.iter().enumerate() {
+        let matched = first_match(&rules, s.kind, &s.content, &s.tags).map(|r| r.name.as_str());
+        if matched != s.expect.as_deref() {
+            return Err(Error::CustomError(format!(
+                "content rules sample {} matched {:?}, expected {:?}",
+                n + 1,
+                matched,
+                s.expect
+            )));
+        }
+    }
+    Ok(rules)
+}
+
+/// Content rules, reloaded whenever the file changes
+pub struct RulesPolicy {
+    watch: FileWatch,
+    rules: Vec<Rule>,
+}
+
+impl RulesPolicy {
+    /// Load the rules at the configured path
+    pub fn load(settings: &ContentRules) -> Result<RulesPolicy> {
+        let watch = FileWatch::new(PathBuf::from(settings.path.clone().unwrap_or_default()));
+        let rules = load_rules(watch.path())?;
+        info!("loaded {} content rules: {:?}", rules.len(), watch.path());
+        Ok(RulesPolicy { watch, rules })
+    }
+
+    /// Reload the rules if they have changed on disk.  Rules that can
+    /// not be loaded, or fail their samples, are reported, and the
+    /// previous ones kept.
+    fn reload_if_modified(&mut self) {
+        if !self.watch.changed() {
+            return;
+        }
+        match load_rules(self.watch.path()) {
+            Ok(rules) => {
+                info!(
+                    "reloaded {} content rules: {:?}",
+                    rules.len(),
+                    self.watch.path()
+                );
+                self.rules = rules;
+            }
+            Err(e) => {
+                warn!("content rules could not be reloaded: {:?}", e);
+            }
+        }
+    }
+
+    /// Decide on an event, using the first rule that matches it
+    pub fn admit_event(&mut self, req: &PolicyRequest) -> PolicyDecision {
+        self.reload_if_modified();
+        let e = req.event;
+        match first_match(&self.rules, e.kind, &e.content, &e.tags) {
+            Some(rule) => PolicyDecision {
+                action: rule.action,
+                message: Some(match rule.action {
+                    // flagged events are logged with the rule name
+                    PolicyAction::Flag => rule.name.clone(),
+                    _ => rule
+                        .message
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_MESSAGE.to_owned()),
+                }),
+            },
+            None => PolicyDecision {
+                action: PolicyAction::Accept,
+                message: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::policy::tests::{fixture, request, PolicyFixture};
+
+    impl PolicyFixture for ContentRules {
+        const SUFFIX: &'static str = ".toml";
+
+        fn with_path(path: String) -> Self {
+            ContentRules { path: Some(path) }
+        }
+    }
+
+    const RULES: &str = r#"
+        [[rule]]
+        name = "scam-links"
+        content = '(?i)https?://(www\.)?scam\.example'
+        action = "reject"
+        message = "known scam link"
+
+        [[rule]]
+        name = "airdrop"
+        kinds = [1]
+        keywords = ["Free Bitcoin"]
+        tags = [{ name = "t", pattern = "^airdrop$" }]
+        action = "shadowReject"
+
+        [[rule]]
+        name = "link-spam"
+        max_urls = 2
+        action = "flag"
+
+        [[sample]]
+        content = "see https://scam.example/login"
+        expect = "scam-links"
+
+        [[sample]]
+        content = "free bitcoin for everyone"
+        tags = [["t", "airdrop"]]
+        expect = "airdrop"
+
+        [[sample]]
+        kind = 30023
+        content = "free bitcoin for everyone"
+        tags = [["t", "airdrop"]]
+
+        [[sample]]
+        content = "gm"
+    "#;
+
+    #[test]
+    fn rule_decisions() -> Result<()> {
+        let (settings, _file) = fixture::<ContentRules>(RULES);
+        let mut policy = RulesPolicy::load(&settings)?;
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        event.content = "https://SCAM.example".to_owned();
+        let decision = policy.admit_event(&request(&event));
+        assert_eq!(decision.action, PolicyAction::Reject);
+        assert_eq!(decision.message, Some("known scam link".to_owned()));
+        event.content = "https://a.example https://b.example https://c.example".to_owned();
+        assert_eq!(
+            policy.admit_event(&request(&event)).action,
+            PolicyAction::Flag
+        );
+        event.content = "https://a.example https://b.example".to_owned();
+        assert_eq!(
+            policy.admit_event(&request(&event)).action,
+            PolicyAction::Accept
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mentions_counted() -> Result<()> {
+        let rule = Rule::compile(RuleConfig {
+            name: "mentions".to_owned(),
+            kinds: None,
+            content: None,
+            keywords: vec![],
+            tags: vec![],
+            max_urls: None,
+            max_mentions: Some(2),
+            action: "reject".to_owned(),
+            message: None,
+        })?;
+        let p = |pk: &str| vec!["p".to_owned(), pk.to_owned()];
+        assert!(!rule.matches(1, "hi nostr:npub1abc", &[p("a")]));
+        assert!(rule.matches(1, "hi nostr:npub1abc", &[p("a"), p("b")]));
+        Ok(())
+    }
+
+    #[test]
+    fn failing_samples_rejected() {
+        let source = format!("{RULES}\n[[sample]]\ncontent = \"gm\"\nexpect = \"airdrop\"\n");
+        let (settings, _file) = fixture::<ContentRules>(&source);
+        assert!(RulesPolicy::load(&settings).is_err());
+        let source = "[[rule]]\nname = \"empty\"\naction = \"reject\"\n";
+        let (settings, _file) = fixture::<ContentRules>(source);
+        assert!(RulesPolicy::load(&settings).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Event admission decisions made by an embedded Rhai script
+use crate::config::PolicyScript;
+use crate::error::{Error, Result};
+use crate::policy::{FileWatch, PolicyAction, PolicyDecision, PolicyRequest};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Name of the function a policy script must define
+const ADMIT_FN: &str = "admit";
+
+/// A compiled policy script, reloaded whenever the file changes
+pub struct ScriptPolicy {
+    watch: FileWatch,
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+    /// Start of the current evaluation, in millis since `epoch`
+    eval_start: Arc<AtomicU64>,
+    epoch: Instant,
+}
+
+impl ScriptPolicy {
+    /// Compile the policy script at the configured path
+    pub fn load(settings: &PolicyScript) -> Result<ScriptPolicy> {
+        let watch = FileWatch::new(PathBuf::from(settings.path.clone().unwrap_or_default()));
+        let epoch = Instant::now();
+        let eval_start = Arc::new(AtomicU64::new(0));
+        let mut engine = Engine::new();
+        if settings.max_operations > 0 {
+            engine.set_max_operations(settings.max_operations);
+        }
+        if settings.timeout_ms > 0 {
+            let timeout = settings.timeout_ms;
+            let start = eval_start.clone();
+            engine.on_progress(move |ops| {
+                // checking the clock on every operation is costly
+                if ops & 0x3ff == 0 {
+                    let elapsed =
+                        epoch.elapsed().as_millis() as u64 - start.load(Ordering::Relaxed);
+                    if elapsed > timeout {
+                        return Some(Dynamic::from("timeout"));
+                    }
+                }
+                None
+            });
+        }
+        let ast = compile(&engine, watch.path())?;
+        info!("loaded policy script: {:?}", watch.path());
+        Ok(ScriptPolicy {
+            watch,
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+            eval_start,
+            epoch,
+        })
+    }
+
+    /// Recompile the script if it has changed on disk.  A script that
+    /// fails to compile is reported, and the previous one kept.
+    fn reload_if_modified(&mut self) {
+        if !self.watch.changed() {
+            return;
+        }
+        match compile(&self.engine, self.watch.path()) {
+            Ok(ast) => {
+                info!("reloaded policy script: {:?}", self.watch.path());
+                self.ast = Arc::new(ast);
+            }
+            Err(e) => {
+                warn!("policy script could not be reloaded: {:?}", e);
+            }
+        }
+    }
+
+    /// Ask the script for a decision on an event.  The script runs
+    /// on a blocking thread, since it may take up to its time budget.
+    pub async fn admit_event(&mut self, req: &PolicyRequest<'_>) -> Result<PolicyDecision> {
+        self.reload_if_modified();
+        let engine = self.engine.clone();
+        let ast = self.ast.clone();
+        let eval_start = self.eval_start.clone();
+        let epoch = self.epoch;
+        let request = request_map(req);
+        let res: Dynamic = tokio::task::spawn_blocking(move || {
+            eval_start.store(epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+            engine.call_fn(&mut Scope::new(), &ast, ADMIT_FN, (request,))
+        })
+        .await?
+        .map_err(|e| Error::CustomError(format!("policy script failed: {e}")))?;
+        decision_from_dynamic(res)
+    }
+}
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST> {
+    let ast = engine
+        .compile_file(path.to_path_buf())
+        .map_err(|e| Error::CustomError(format!("policy script failed to compile: {e}")))?;
+    if !ast.iter_functions().any(|f| f.name == ADMIT_FN) {
+        return Err(Error::CustomError(format!(
+            "policy script does not define {ADMIT_FN}(request)"
+        )));
+    }
+    Ok(ast)
+}
+
+fn opt_string(s: Option<&str>) -> Dynamic {
+    s.map_or(Dynamic::UNIT, |s| Dynamic::from(s.to_owned()))
+}
+
+/// Convert a request into an object map for the script
+fn request_map(req: &PolicyRequest) -> Map {
+    let e = req.event;
+    let tags: Array = e
+        .tags
+        .iter()
+        .map(|t| Dynamic::from_array(t.iter().map(|v| Dynamic::from(v.clone())).collect()))
+        .collect();
+    let mut event = Map::new();
+    event.insert("id".into(), Dynamic::from(e.id.clone()));
+    event.insert("pubkey".into(), Dynamic::from(e.pubkey.clone()));
+    event.insert("created_at".into(), Dynamic::from(e.created_at as i64));
+    event.insert("kind".into(), Dynamic::from(e.kind as i64));
+    event.insert("tags".into(), Dynamic::from_array(tags));
+    event.insert("content".into(), Dynamic::from(e.content.clone()));
+    event.insert("sig".into(), Dynamic::from(e.sig.clone()));
+    let mut m = Map::new();
+    m.insert("event".into(), Dynamic::from_map(event));
+    m.insert("ip_addr".into(), Dynamic::from(req.ip_addr.to_owned()));
+    m.insert("origin".into(), opt_string(req.origin));
+    m.insert("user_agent".into(), opt_string(req.user_agent));
+    m.insert(
+        "auth_pubkey".into(),
+        opt_string(req.auth_pubkey.map(hex::encode).as_deref()),
+    );
+    let nip05 = req.nip05.map_or(Dynamic::UNIT, |n| {
+        let mut nm = Map::new();
+        nm.insert("local".into(), Dynamic::from(n.local.clone()));
+        nm.insert("domain".into(), Dynamic::from(n.domain.clone()));
+        Dynamic::from_map(nm)
+    });
+    m.insert("nip05".into(), nip05);
+    m
+}
+
+/// Interpret a script result, which is either an action string, or a
+/// map with `action` and (optionally) `msg` fields.
+fn decision_from_dynamic(res: Dynamic) -> Result<PolicyDecision> {
+    if res.is_string() {
+        let action = res.into_string().unwrap_or_default().parse()?;
+        return Ok(PolicyDecision {
+            action,
+            message: None,
+        });
+    }
+    if let Some(m) = res.try_cast::<Map>() {
+        let action: PolicyAction = m
+            .get("action")
+            .and_then(|a| a.clone().into_string().ok())
+            .ok_or_else(|| Error::CustomError("policy script result has no action".into()))?
+            .parse()?;
+        let message = m.get("msg").and_then(|a| a.clone().into_string().ok());
+        return Ok(PolicyDecision { action, message });
+    }
+    Err(Error::CustomError(
+        "policy script must return an action or a map".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::policy::tests::{fixture, request, PolicyFixture};
+
+    impl PolicyFixture for PolicyScript {
+        const SUFFIX: &'static str = ".rhai";
+
+        fn with_path(path: String) -> Self {
+            PolicyScript {
+                path: Some(path),
+                max_operations: 100_000,
+                timeout_ms: 100,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn script_decisions() -> Result<()> {
+        let (settings, _file) = fixture::<PolicyScript>(
+            r#"
+            fn admit(req) {
+                if req.event.kind == 1 { return "accept"; }
+                if req.event.kind == 7 { return #{ action: "shadowReject" }; }
+                #{ action: "reject", msg: `kind ${req.event.kind} from ${req.user_agent}` }
+            }"#,
+        );
+        let mut policy = ScriptPolicy::load(&settings)?;
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        assert_eq!(
+            policy.admit_event(&request(&event)).await?.action,
+            PolicyAction::Accept
+        );
+        event.kind = 7;
+        assert_eq!(
+            policy.admit_event(&request(&event)).await?.action,
+            PolicyAction::ShadowReject
+        );
+        event.kind = 4;
+        let decision = policy.admit_event(&request(&event)).await?;
+        assert_eq!(decision.action, PolicyAction::Reject);
+        assert_eq!(decision.message, Some("kind 4 from test".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn script_missing_admit() {
+        let (settings, _file) = fixture::<PolicyScript>("fn other(req) { true }");
+        assert!(ScriptPolicy::load(&settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn script_operation_budget() -> Result<()> {
+        let (settings, _file) = fixture::<PolicyScript>("fn admit(req) { loop {} }");
+        let mut policy = ScriptPolicy::load(&settings)?;
+        let event = Event::simple_event();
+        assert!(policy.admit_event(&request(&event)).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn script_time_budget() -> Result<()> {
+        let (mut settings, _file) = fixture::<PolicyScript>("fn admit(req) { loop {} }");
+        settings.max_operations = 0;
+        let mut policy = ScriptPolicy::load(&settings)?;
+        let event = Event::simple_event();
+        let start = Instant::now();
+        assert!(policy.admit_event(&request(&event)).await.is_err());
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        Ok(())
+    }
+}
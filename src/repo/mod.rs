@@ -1,9 +1,11 @@
 use crate::db::QueryResult;
 use crate::error::Result;
 use crate::event::Event;
+use crate::groups::Group;
+use crate::invite::Invite;
 use crate::nip05::VerificationRecord;
 use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::subscription::Subscription;
+use crate::subscription::{ReqFilter, Subscription};
 use crate::utils::unix_time;
 use async_trait::async_trait;
 use nostr::Keys;
@@ -14,6 +16,28 @@ pub mod postgres_migration;
 pub mod sqlite;
 pub mod sqlite_migration;
 
+/// Outcome of persisting a single event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// The event was stored
+    Saved,
+    /// The event was already stored
+    Duplicate,
+    /// The event was stored, but hidden by an earlier deletion
+    Hidden,
+    /// A newer version of this replaceable event exists, so it was
+    /// not stored
+    Blocked,
+}
+
+impl WriteOutcome {
+    /// Rows added for this event, as reported by `write_event`
+    #[must_use]
+    pub fn rows(self) -> u64 {
+        u64::from(self == WriteOutcome::Saved)
+    }
+}
+
 #[async_trait]
 pub trait NostrRepo: Send + Sync {
     /// Start the repository (any initialization or maintenance tasks can be kicked off here)
@@ -25,6 +49,47 @@ pub trait NostrRepo: Send + Sync {
     /// Persist event to database
     async fn write_event(&self, e: &Event) -> Result<u64>;
 
+    /// Persist several events to the database in a single
+    /// transaction, returning the outcome for each, in order.
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<WriteOutcome>>;
+
+    /// Replace the authors whose stored events are left out of query
+    /// results.
+    async fn set_muted_authors(&self, pubkeys: Vec<String>) -> Result<()>;
+
+    /// Store an event held for moderation, hidden until it is
+    /// approved.  Returns false if the event was already stored.
+    async fn write_pending_event(&self, e: &Event) -> Result<bool>;
+
+    /// Events held for moderation, oldest first.
+    async fn pending_events(&self, limit: u64) -> Result<Vec<Event>>;
+
+    /// Publish an event held for moderation, writing it as if it had
+    /// just arrived.  Returns the event and the outcome of writing
+    /// it, or None if no such event is held.
+    async fn approve_pending_event(&self, id: &str) -> Result<Option<(Event, WriteOutcome)>>;
+
+    /// Discard an event held for moderation.  Returns false if no
+    /// such event is held.
+    async fn reject_pending_event(&self, id: &str) -> Result<bool>;
+
+    /// Has the author published any events that are not hidden?
+    async fn author_has_events(&self, pubkey: &str) -> Result<bool>;
+
+    /// Hide stored events by id, returning how many were hidden.
+    async fn hide_events(&self, ids: &[String]) -> Result<u64>;
+
+    /// Load every group hosted by the relay, including deleted ones.
+    async fn load_groups(&self) -> Result<Vec<Group>>;
+
+    /// Store a group's metadata and members, replacing any earlier
+    /// state.
+    async fn save_group(&self, group: &Group) -> Result<()>;
+
+    /// Mark a group deleted, and remove its members.  The group is
+    /// kept, so that its id is not used again.
+    async fn delete_group(&self, id: &str) -> Result<()>;
+
     /// Perform a database query using a subscription.
     ///
     /// The [`Subscription`] is converted into a SQL query.  Each result
@@ -39,6 +104,17 @@ pub trait NostrRepo: Send + Sync {
         mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
     ) -> Result<()>;
 
+    /// Stored events matching any of the filters, in (`created_at`,
+    /// id) order, after the `after` position.  At most `limit` events
+    /// are returned; callers page through every event by passing the
+    /// position of the last one.  Filter limits are ignored.
+    async fn replay_events(
+        &self,
+        filters: &[ReqFilter],
+        after: Option<(u64, String)>,
+        limit: u64,
+    ) -> Result<Vec<Event>>;
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()>;
 
@@ -86,6 +162,20 @@ pub trait NostrRepo: Send + Sync {
     /// Get the most recent invoice for a given pubkey
     /// invoice must be unpaid and not expired
     async fn get_unpaid_invoice(&self, pubkey: &Keys) -> Result<Option<InvoiceInfo>>;
+
+    /// Store a newly minted invite code
+    async fn create_invite(&self, invite: &Invite) -> Result<()>;
+
+    /// Use an invite code to admit an account, creating the account
+    /// if needed.  Returns false if the code is unknown, used up, or
+    /// expired.
+    async fn redeem_invite(&self, code: &str, pubkey: &Keys) -> Result<bool>;
+
+    /// Count the codes minted by a member since a time.
+    async fn count_invites_by(&self, created_by: &str, since: u64) -> Result<u64>;
+
+    /// Invite codes, newest first
+    async fn invites(&self, limit: u64) -> Result<Vec<Invite>>;
 }
 
 // Current time, with a slight forward jitter in seconds
@@ -1,16 +1,18 @@
 use crate::db::QueryResult;
 use crate::error::Result;
 use crate::event::{single_char_tagname, Event};
+use crate::groups::Group;
+use crate::invite::Invite;
 use crate::nip05::{Nip05Name, VerificationRecord};
 use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::repo::{now_jitter, NostrRepo};
+use crate::repo::{now_jitter, NostrRepo, WriteOutcome};
 use crate::subscription::{ReqFilter, Subscription};
 use async_std::stream::StreamExt;
 use async_trait::async_trait;
 use chrono::{DateTime, TimeZone, Utc};
 use sqlx::postgres::PgRow;
 use sqlx::Error::RowNotFound;
-use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row};
+use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row, Transaction};
 use std::time::{Duration, Instant};
 
 use crate::error;
@@ -77,6 +79,216 @@ async fn delete_expired(conn: PostgresPool) -> Result<u64> {
     Ok(update_count)
 }
 
+/// Persist an event within a transaction
+async fn persist_event(tx: &mut Transaction<'_, Postgres>, e: &Event) -> Result<WriteOutcome> {
+    // get relevant fields from event and convert to blobs.
+    let id_blob = hex::decode(&e.id).ok();
+    let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
+    let delegator_blob: Option<Vec<u8>> = e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
+    let event_str = serde_json::to_string(&e).unwrap();
+
+    // determine if this event would be shadowed by an existing
+    // replaceable event or parameterized replaceable event.
+    if e.is_replaceable() {
+        let repl_count = sqlx::query(
+            "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND e.created_at >= $3 LIMIT 1;")
+            .bind(&pubkey_blob)
+            .bind(e.kind as i64)
+            .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+            .fetch_optional(&mut *tx)
+            .await?;
+        if repl_count.is_some() {
+            return Ok(WriteOutcome::Blocked);
+        }
+    }
+    if let Some(d_tag) = e.distinct_param() {
+        let repl_count: i64 = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+            sqlx::query_scalar(
+                "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND e.created_at >= $4 LIMIT 1;")
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(e.kind as i64)
+                .bind(hex::decode(d_tag).ok())
+                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                .fetch_one(&mut *tx)
+                .await?
+        } else {
+            sqlx::query_scalar(
+                "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND e.created_at >= $4 LIMIT 1;")
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(e.kind as i64)
+                .bind(d_tag.as_bytes())
+                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                .fetch_one(&mut *tx)
+                .await?
+        };
+        // if any rows were returned, then some newer event with
+        // the same author/kind/tag value exist, and we can ignore
+        // this event.
+        if repl_count > 0 {
+            return Ok(WriteOutcome::Blocked);
+        }
+    }
+    // ignore if the event hash is a duplicate.
+    let ins_count = sqlx::query(
+        r#"INSERT INTO "event"
+(id, pub_key, created_at, expires_at, kind, "content", delegated_by)
+VALUES($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT (id) DO NOTHING"#,
+    )
+    .bind(&id_blob)
+    .bind(&pubkey_blob)
+    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+    .bind(
+        e.expiration()
+            .and_then(|x| Utc.timestamp_opt(x as i64, 0).latest()),
+    )
+    .bind(e.kind as i64)
+    .bind(event_str.into_bytes())
+    .bind(delegator_blob)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if ins_count == 0 {
+        // if the event was a duplicate, no need to insert event or
+        // pubkey references.
+        return Ok(WriteOutcome::Duplicate);
+    }
+
+    // add all tags to the tag table
+    for tag in e.tags.iter() {
+        // ensure we have 2 values.
+        if tag.len() >= 2 {
+            let tag_name = &tag[0];
+            let tag_val = &tag[1];
+            // only single-char tags are searchable
+            let tag_char_opt = single_char_tagname(tag_name);
+            if tag_char_opt.is_some() {
+                // if tag value is lowercase hex;
+                if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
+                    sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
+                                 ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
+                            .bind(&id_blob)
+                        .bind(tag_name)
+                        .bind(hex::decode(tag_val).ok())
+                        .execute(&mut *tx)
+                        .await?;
+                } else {
+                    sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
+                                 ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
+                            .bind(&id_blob)
+                        .bind(tag_name)
+                        .bind(tag_val.as_bytes())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+        }
+    }
+    if e.is_replaceable() {
+        let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
+            .bind(e.kind as i64)
+            .bind(hex::decode(&e.pubkey).ok())
+            .execute(&mut *tx)
+            .await?.rows_affected();
+        if update_count > 0 {
+            info!(
+                "hid {} older replaceable kind {} events for author: {:?}",
+                update_count,
+                e.kind,
+                e.get_author_prefix()
+            );
+        }
+    }
+    // parameterized replaceable events
+    // check for parameterized replaceable events that would be hidden; don't insert these either.
+    if let Some(d_tag) = e.distinct_param() {
+        let update_count = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+            sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY created_at DESC OFFSET 1);")
+                .bind(e.kind as i64)
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(hex::decode(d_tag).ok())
+                .execute(&mut *tx)
+                .await?.rows_affected()
+        } else {
+            sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
+                .bind(e.kind as i64)
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(d_tag.as_bytes())
+                .execute(&mut *tx)
+                .await?.rows_affected()
+        };
+        if update_count > 0 {
+            info!(
+                "removed {} older parameterized replaceable kind {} events for author: {:?}",
+                update_count,
+                e.kind,
+                e.get_author_prefix()
+            );
+        }
+    }
+    // if this event is a deletion, hide the referenced events from the same author.
+    if e.kind == 5 {
+        let event_candidates = e.tag_values_by_name("e");
+        let pub_keys: Vec<Vec<u8>> = event_candidates
+            .iter()
+            .filter(|x| is_hex(x) && x.len() == 64)
+            .filter_map(|x| hex::decode(x).ok())
+            .collect();
+
+        let mut builder = QueryBuilder::new(
+            "UPDATE \"event\" SET hidden = 1::bit(1) WHERE kind != 5 AND pub_key = ",
+        );
+        builder.push_bind(hex::decode(&e.pubkey).ok());
+        builder.push(" AND id IN (");
+
+        let mut sep = builder.separated(", ");
+        for pk in pub_keys {
+            sep.push_bind(pk);
+        }
+        sep.push_unseparated(")");
+
+        let update_count = builder.build().execute(&mut *tx).await?.rows_affected();
+        info!(
+            "hid {} deleted events for author {:?}",
+            update_count,
+            e.get_author_prefix()
+        );
+    } else {
+        // check if a deletion has already been recorded for this event.
+        // Only relevant for non-deletion events
+        let del_count = sqlx::query(
+            "SELECT e.id FROM \"event\" e \
+        LEFT JOIN tag t ON e.id = t.event_id \
+        WHERE e.pub_key = $1 AND t.\"name\" = 'e' AND e.kind = 5 AND t.value = $2 LIMIT 1",
+        )
+        .bind(&pubkey_blob)
+        .bind(&id_blob)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        // check if a the query returned a result, meaning we should
+        // hid the current event
+        if del_count.is_some() {
+            // a deletion already existed, mark original event as hidden.
+            info!(
+                "hid event: {:?} due to existing deletion by author: {:?}",
+                e.get_event_id_prefix(),
+                e.get_author_prefix()
+            );
+            sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
+                .bind(&id_blob)
+                .execute(&mut *tx)
+                .await?;
+            // event was deleted, so let caller know nothing new
+            // arrived, preventing this from being sent to active
+            // subscriptions
+            return Ok(WriteOutcome::Hidden);
+        }
+    }
+    Ok(WriteOutcome::Saved)
+}
+
 #[async_trait]
 impl NostrRepo for PostgresRepo {
     async fn start(&self) -> Result<()> {
@@ -93,218 +305,224 @@ impl NostrRepo for PostgresRepo {
         // start transaction
         let mut tx = self.conn_write.begin().await?;
         let start = Instant::now();
+        let outcome = persist_event(&mut tx, e).await?;
+        tx.commit().await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        Ok(outcome.rows())
+    }
+
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<WriteOutcome>> {
+        let mut tx = self.conn_write.begin().await?;
+        let start = Instant::now();
+        let mut outcomes = Vec::with_capacity(events.len());
+        for e in events {
+            outcomes.push(persist_event(&mut tx, e).await?);
+        }
+        tx.commit().await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        Ok(outcomes)
+    }
 
-        // get relevant fields from event and convert to blobs.
-        let id_blob = hex::decode(&e.id).ok();
-        let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
-        let delegator_blob: Option<Vec<u8>> =
-            e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
-        let event_str = serde_json::to_string(&e).unwrap();
-
-        // determine if this event would be shadowed by an existing
-        // replaceable event or parameterized replaceable event.
-        if e.is_replaceable() {
-            let repl_count = sqlx::query(
-                "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND e.created_at >= $3 LIMIT 1;")
-                .bind(&pubkey_blob)
-                .bind(e.kind as i64)
-                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                .fetch_optional(&mut tx)
+    async fn set_muted_authors(&self, pubkeys: Vec<String>) -> Result<()> {
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query("DELETE FROM muted_author")
+            .execute(&mut tx)
+            .await?;
+        for pubkey in pubkeys {
+            sqlx::query("INSERT INTO muted_author (pub_key) VALUES ($1) ON CONFLICT DO NOTHING")
+                .bind(hex::decode(pubkey)?)
+                .execute(&mut tx)
                 .await?;
-            if repl_count.is_some() {
-                return Ok(0);
-            }
         }
-        if let Some(d_tag) = e.distinct_param() {
-            let repl_count: i64 = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND e.created_at >= $4 LIMIT 1;")
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(e.kind as i64)
-                    .bind(hex::decode(d_tag).ok())
-                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
-                    .await?
-            } else {
-                sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND e.created_at >= $4 LIMIT 1;")
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(e.kind as i64)
-                    .bind(d_tag.as_bytes())
-                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
-                    .await?
-            };
-            // if any rows were returned, then some newer event with
-            // the same author/kind/tag value exist, and we can ignore
-            // this event.
-            if repl_count > 0 {
-                return Ok(0);
-            }
-        }
-        // ignore if the event hash is a duplicate.
-        let mut ins_count = sqlx::query(
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn write_pending_event(&self, e: &Event) -> Result<bool> {
+        let res = sqlx::query(
             r#"INSERT INTO "event"
-(id, pub_key, created_at, expires_at, kind, "content", delegated_by)
-VALUES($1, $2, $3, $4, $5, $6, $7)
+(id, pub_key, created_at, expires_at, kind, "content", delegated_by, hidden, pending)
+VALUES($1, $2, $3, $4, $5, $6, $7, 1::bit(1), TRUE)
 ON CONFLICT (id) DO NOTHING"#,
         )
-        .bind(&id_blob)
-        .bind(&pubkey_blob)
+        .bind(hex::decode(&e.id).ok())
+        .bind(hex::decode(&e.pubkey).ok())
         .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
         .bind(
             e.expiration()
                 .and_then(|x| Utc.timestamp_opt(x as i64, 0).latest()),
         )
         .bind(e.kind as i64)
-        .bind(event_str.into_bytes())
-        .bind(delegator_blob)
-        .execute(&mut tx)
-        .await?
-        .rows_affected();
+        .bind(serde_json::to_string(&e)?.into_bytes())
+        .bind(e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok()))
+        .execute(&self.conn_write)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
 
-        if ins_count == 0 {
-            // if the event was a duplicate, no need to insert event or
-            // pubkey references.  This will abort the txn.
+    async fn pending_events(&self, limit: u64) -> Result<Vec<Event>> {
+        let rows: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT \"content\" FROM \"event\" WHERE pending ORDER BY first_seen LIMIT $1",
+        )
+        .bind(limit as i64)
+        .fetch_all(&self.conn_write)
+        .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|c| serde_json::from_slice(c).ok())
+            .collect())
+    }
+
+    async fn approve_pending_event(&self, id: &str) -> Result<Option<(Event, WriteOutcome)>> {
+        let id_blob = hex::decode(id)?;
+        let mut tx = self.conn_write.begin().await?;
+        // the held copy is replaced by a normal write, so that
+        // replacement and deletion take effect now.
+        let content: Option<Vec<u8>> = sqlx::query_scalar(
+            "DELETE FROM \"event\" WHERE id = $1 AND pending RETURNING \"content\"",
+        )
+        .bind(&id_blob)
+        .fetch_optional(&mut tx)
+        .await?;
+        let Some(content) = content else {
+            return Ok(None);
+        };
+        let event: Event = serde_json::from_slice(&content)?;
+        let outcome = persist_event(&mut tx, &event).await?;
+        tx.commit().await?;
+        Ok(Some((event, outcome)))
+    }
+
+    async fn reject_pending_event(&self, id: &str) -> Result<bool> {
+        let res = sqlx::query("DELETE FROM \"event\" WHERE id = $1 AND pending")
+            .bind(hex::decode(id)?)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn author_has_events(&self, pubkey: &str) -> Result<bool> {
+        let found = sqlx::query(
+            "SELECT 1 FROM \"event\" WHERE pub_key = $1 AND hidden != 1::bit(1) LIMIT 1",
+        )
+        .bind(hex::decode(pubkey)?)
+        .fetch_optional(&self.conn_write)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    async fn hide_events(&self, ids: &[String]) -> Result<u64> {
+        let ids: Vec<Vec<u8>> = ids
+            .iter()
+            .filter(|x| is_hex(x) && x.len() == 64)
+            .filter_map(|x| hex::decode(x).ok())
+            .collect();
+        if ids.is_empty() {
             return Ok(0);
         }
+        let mut builder = QueryBuilder::new(
+            "UPDATE \"event\" SET hidden = 1::bit(1) WHERE hidden != 1::bit(1) AND id IN (",
+        );
+        let mut sep = builder.separated(", ");
+        for id in ids {
+            sep.push_bind(id);
+        }
+        sep.push_unseparated(")");
+        let res = builder.build().execute(&self.conn_write).await?;
+        Ok(res.rows_affected())
+    }
 
-        // add all tags to the tag table
-        for tag in e.tags.iter() {
-            // ensure we have 2 values.
-            if tag.len() >= 2 {
-                let tag_name = &tag[0];
-                let tag_val = &tag[1];
-                // only single-char tags are searchable
-                let tag_char_opt = single_char_tagname(tag_name);
-                if tag_char_opt.is_some() {
-                    // if tag value is lowercase hex;
-                    if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
-                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
-                                     ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                            .bind(tag_name)
-                            .bind(hex::decode(tag_val).ok())
-                            .execute(&mut tx)
-                            .await?;
-                    } else {
-                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
-                                     ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                            .bind(tag_name)
-                            .bind(tag_val.as_bytes())
-                            .execute(&mut tx)
-                            .await?;
-                    }
+    async fn load_groups(&self) -> Result<Vec<Group>> {
+        let rows = sqlx::query(
+            "SELECT id, \"name\", about, picture, private, closed, deleted, created_at FROM relay_group ORDER BY id",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        let mut groups = rows
+            .iter()
+            .map(|row| {
+                let created_at: DateTime<Utc> = row.get("created_at");
+                Group {
+                    id: row.get("id"),
+                    name: row.get("name"),
+                    about: row.get("about"),
+                    picture: row.get("picture"),
+                    private: row.get("private"),
+                    closed: row.get("closed"),
+                    deleted: row.get("deleted"),
+                    created_at: created_at.timestamp() as u64,
+                    ..Default::default()
                 }
+            })
+            .collect::<Vec<Group>>();
+        let members: Vec<(String, String, String)> =
+            sqlx::query_as("SELECT group_id, pubkey, roles FROM relay_group_member")
+                .fetch_all(&self.conn)
+                .await?;
+        for (group_id, pubkey, roles) in members {
+            if let Some(group) = groups.iter_mut().find(|g| g.id == group_id) {
+                let roles = roles
+                    .split(',')
+                    .filter(|r| !r.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                group.members.insert(pubkey, roles);
             }
         }
-        if e.is_replaceable() {
-            let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
-                .bind(e.kind as i64)
-                .bind(hex::decode(&e.pubkey).ok())
-                .execute(&mut tx)
-                .await?.rows_affected();
-            if update_count > 0 {
-                info!(
-                    "hid {} older replaceable kind {} events for author: {:?}",
-                    update_count,
-                    e.kind,
-                    e.get_author_prefix()
-                );
-            }
-        }
-        // parameterized replaceable events
-        // check for parameterized replaceable events that would be hidden; don't insert these either.
-        if let Some(d_tag) = e.distinct_param() {
-            let update_count = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY created_at DESC OFFSET 1);")
-                    .bind(e.kind as i64)
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(hex::decode(d_tag).ok())
-                    .execute(&mut tx)
-                    .await?.rows_affected()
-            } else {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
-                    .bind(e.kind as i64)
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(d_tag.as_bytes())
-                    .execute(&mut tx)
-                    .await?.rows_affected()
-            };
-            if update_count > 0 {
-                info!(
-                    "removed {} older parameterized replaceable kind {} events for author: {:?}",
-                    update_count,
-                    e.kind,
-                    e.get_author_prefix()
-                );
-            }
-        }
-        // if this event is a deletion, hide the referenced events from the same author.
-        if e.kind == 5 {
-            let event_candidates = e.tag_values_by_name("e");
-            let pub_keys: Vec<Vec<u8>> = event_candidates
-                .iter()
-                .filter(|x| is_hex(x) && x.len() == 64)
-                .filter_map(|x| hex::decode(x).ok())
-                .collect();
-
-            let mut builder = QueryBuilder::new(
-                "UPDATE \"event\" SET hidden = 1::bit(1) WHERE kind != 5 AND pub_key = ",
-            );
-            builder.push_bind(hex::decode(&e.pubkey).ok());
-            builder.push(" AND id IN (");
-
-            let mut sep = builder.separated(", ");
-            for pk in pub_keys {
-                sep.push_bind(pk);
-            }
-            sep.push_unseparated(")");
+        Ok(groups)
+    }
 
-            let update_count = builder.build().execute(&mut tx).await?.rows_affected();
-            info!(
-                "hid {} deleted events for author {:?}",
-                update_count,
-                e.get_author_prefix()
-            );
-        } else {
-            // check if a deletion has already been recorded for this event.
-            // Only relevant for non-deletion events
-            let del_count = sqlx::query(
-                "SELECT e.id FROM \"event\" e \
-            LEFT JOIN tag t ON e.id = t.event_id \
-            WHERE e.pub_key = $1 AND t.\"name\" = 'e' AND e.kind = 5 AND t.value = $2 LIMIT 1",
+    async fn save_group(&self, group: &Group) -> Result<()> {
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO relay_group (id, "name", about, picture, private, closed, created_at)
+VALUES ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT (id) DO UPDATE SET "name" = EXCLUDED."name", about = EXCLUDED.about,
+picture = EXCLUDED.picture, private = EXCLUDED.private, closed = EXCLUDED.closed"#,
+        )
+        .bind(&group.id)
+        .bind(&group.name)
+        .bind(&group.about)
+        .bind(&group.picture)
+        .bind(group.private)
+        .bind(group.closed)
+        .bind(Utc.timestamp_opt(group.created_at as i64, 0).unwrap())
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM relay_group_member WHERE group_id = $1")
+            .bind(&group.id)
+            .execute(&mut tx)
+            .await?;
+        for (pubkey, roles) in &group.members {
+            sqlx::query(
+                "INSERT INTO relay_group_member (group_id, pubkey, roles) VALUES ($1, $2, $3)",
             )
-            .bind(&pubkey_blob)
-            .bind(&id_blob)
-            .fetch_optional(&mut tx)
+            .bind(&group.id)
+            .bind(pubkey)
+            .bind(roles.join(","))
+            .execute(&mut tx)
             .await?;
-
-            // check if a the query returned a result, meaning we should
-            // hid the current event
-            if del_count.is_some() {
-                // a deletion already existed, mark original event as hidden.
-                info!(
-                    "hid event: {:?} due to existing deletion by author: {:?}",
-                    e.get_event_id_prefix(),
-                    e.get_author_prefix()
-                );
-                sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
-                    .bind(&id_blob)
-                    .execute(&mut tx)
-                    .await?;
-                // event was deleted, so let caller know nothing new
-                // arrived, preventing this from being sent to active
-                // subscriptions
-                ins_count = 0;
-            }
         }
         tx.commit().await?;
-        self.metrics
-            .write_events
-            .observe(start.elapsed().as_secs_f64());
-        Ok(ins_count)
+        Ok(())
+    }
+
+    async fn delete_group(&self, id: &str) -> Result<()> {
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query("DELETE FROM relay_group_member WHERE group_id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE relay_group SET deleted = TRUE WHERE id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
     }
 
     async fn query_subscription(
@@ -444,6 +662,41 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(())
     }
 
+    async fn replay_events(
+        &self,
+        filters: &[ReqFilter],
+        after: Option<(u64, String)>,
+        limit: u64,
+    ) -> Result<Vec<Event>> {
+        let after = match after {
+            Some((created_at, id)) => Some((created_at, hex::decode(id)?)),
+            None => None,
+        };
+        // the first events after the position, for each filter, are
+        // merged, so the page is in order across every filter.
+        let mut rows: Vec<(u64, Vec<u8>, Vec<u8>)> = vec![];
+        for filter in filters {
+            let Some(mut query) = replay_query_from_filter(filter, after.as_ref(), limit) else {
+                continue;
+            };
+            let filter_rows = query.build().fetch_all(&self.conn).await?;
+            for row in filter_rows {
+                let created_at = row.get::<'_, DateTime<Utc>, &str>("created_at");
+                rows.push((
+                    created_at.timestamp() as u64,
+                    row.get("id"),
+                    row.get("content"),
+                ));
+            }
+        }
+        rows.sort_unstable_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        rows.dedup_by(|a, b| a.1 == b.1);
+        rows.truncate(limit as usize);
+        rows.into_iter()
+            .map(|(_, _, content)| Ok(serde_json::from_slice(&content)?))
+            .collect()
+    }
+
     async fn optimize_db(&self) -> Result<()> {
         // Not implemented
         Ok(())
@@ -709,6 +962,84 @@ LIMIT 1;
             None => Ok(None),
         }
     }
+
+    async fn create_invite(&self, invite: &Invite) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO invite (code, created_by, max_uses, uses, expires_at, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(&invite.code)
+        .bind(&invite.created_by)
+        .bind(invite.max_uses as i64)
+        .bind(invite.uses as i64)
+        .bind(
+            invite
+                .expires_at
+                .and_then(|x| Utc.timestamp_opt(x as i64, 0).latest()),
+        )
+        .bind(Utc.timestamp_opt(invite.created_at as i64, 0).unwrap())
+        .execute(&self.conn_write)
+        .await?;
+        Ok(())
+    }
+
+    async fn redeem_invite(&self, code: &str, pubkey: &Keys) -> Result<bool> {
+        let pub_key = pubkey.public_key().to_string();
+        let mut tx = self.conn_write.begin().await?;
+        let used = sqlx::query(
+            "UPDATE invite SET uses = uses + 1 WHERE code = $1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > now())",
+        )
+        .bind(code)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        if used == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            "INSERT INTO account (pubkey, is_admitted, balance, tos_accepted_at) VALUES ($1, TRUE, 0, now()) \
+            ON CONFLICT (pubkey) DO UPDATE SET is_admitted = TRUE, tos_accepted_at = now()",
+        )
+        .bind(pub_key)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn count_invites_by(&self, created_by: &str, since: u64) -> Result<u64> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM invite WHERE created_by = $1 AND created_at >= $2",
+        )
+        .bind(created_by)
+        .bind(Utc.timestamp_opt(since as i64, 0).unwrap())
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(count as u64)
+    }
+
+    async fn invites(&self, limit: u64) -> Result<Vec<Invite>> {
+        let rows = sqlx::query(
+            "SELECT code, created_by, max_uses, uses, expires_at, created_at FROM invite ORDER BY created_at DESC LIMIT $1",
+        )
+        .bind(limit as i64)
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let expires_at: Option<DateTime<Utc>> = row.get("expires_at");
+                let created_at: DateTime<Utc> = row.get("created_at");
+                Invite {
+                    code: row.get("code"),
+                    created_by: row.get("created_by"),
+                    max_uses: row.get::<i64, _>("max_uses") as u64,
+                    uses: row.get::<i64, _>("uses") as u64,
+                    expires_at: expires_at.map(|t| t.timestamp() as u64),
+                    created_at: created_at.timestamp() as u64,
+                }
+            })
+            .collect())
+    }
 }
 
 /// Create a dynamic SQL query and params from a subscription filter.
@@ -719,7 +1050,54 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
     }
 
     let mut query = QueryBuilder::new("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+    push_filter_conditions(&mut query, f)?;
+
+    // Apply per-filter limit to this query.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        query.push(" ORDER BY e.created_at DESC LIMIT ");
+        query.push(lim.min(1000));
+    } else {
+        query.push(" ORDER BY e.created_at ASC LIMIT ");
+        query.push(1000);
+    }
+    Some(query)
+}
+
+/// Build a query for at most `limit` events matching a filter, after
+/// the (`created_at`, id) position, in that order.  The filter's own
+/// limit is ignored.
+fn replay_query_from_filter<'a>(
+    f: &'a ReqFilter,
+    after: Option<&(u64, Vec<u8>)>,
+    limit: u64,
+) -> Option<QueryBuilder<'a, Postgres>> {
+    if f.force_no_match {
+        return None;
+    }
+    let mut query =
+        QueryBuilder::new("SELECT e.\"content\", e.created_at, e.id FROM \"event\" e WHERE ");
+    push_filter_conditions(&mut query, f)?;
+    if let Some((created_at, id)) = after {
+        query
+            .push(" AND (e.created_at, e.id) > (")
+            .push_bind(Utc.timestamp_opt(*created_at as i64, 0).unwrap())
+            .push(", ")
+            .push_bind(id.clone())
+            .push(")");
+    }
+    query.push(" ORDER BY e.created_at ASC, e.id ASC LIMIT ");
+    query.push(limit);
+    Some(query)
+}
 
+/// Add the conditions of a filter to a query, along with those that
+/// exclude hidden and expired events.  Returns None if the filter
+/// cannot match any events.
+fn push_filter_conditions<'a>(
+    query: &mut QueryBuilder<'a, Postgres>,
+    f: &'a ReqFilter,
+) -> Option<()> {
     // This tracks whether we need to push a prefix AND before adding another clause
     let mut push_and = false;
     // Query for "authors", allowing prefix matches
@@ -858,25 +1236,16 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
             .push_bind(Utc.timestamp_opt(f.until.unwrap() as i64, 0).unwrap());
     }
 
-    // never display hidden events
+    // never display hidden events, or those of muted authors
     if push_and {
         query.push(" AND e.hidden != 1::bit(1)");
     } else {
         query.push("e.hidden != 1::bit(1)");
     }
+    query.push(" AND NOT EXISTS (SELECT 1 FROM muted_author m WHERE m.pub_key = e.pub_key)");
     // never display expired events
     query.push(" AND (e.expires_at IS NULL OR e.expires_at > now())");
-
-    // Apply per-filter limit to this query.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        query.push(" ORDER BY e.created_at DESC LIMIT ");
-        query.push(lim.min(1000));
-    } else {
-        query.push(" ORDER BY e.created_at ASC LIMIT ");
-        query.push(1000);
-    }
-    Some(query)
+    Some(())
 }
 
 impl FromRow<'_, PgRow> for VerificationRecord {
@@ -927,7 +1296,7 @@ mod tests {
         };
 
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value_hex in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value_hex in ($5)))) AND e.hidden != 1::bit(1) AND NOT EXISTS (SELECT 1 FROM muted_author m WHERE m.pub_key = e.pub_key) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
     }
 
     #[test]
@@ -946,7 +1315,7 @@ mod tests {
         };
 
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5)))) AND e.hidden != 1::bit(1) AND NOT EXISTS (SELECT 1 FROM muted_author m WHERE m.pub_key = e.pub_key) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
     }
 
     #[test]
@@ -971,7 +1340,7 @@ mod tests {
         };
 
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5) OR value_hex in ($6)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5) OR value_hex in ($6)))) AND e.hidden != 1::bit(1) AND NOT EXISTS (SELECT 1 FROM muted_author m WHERE m.pub_key = e.pub_key) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
     }
 
     #[test]
@@ -990,7 +1359,7 @@ mod tests {
             force_no_match: false,
         };
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE e.kind in ($1) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $2 AND (value in ($3))) OR (t.\"name\" = $4 AND (value in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE e.kind in ($1) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $2 AND (value in ($3))) OR (t.\"name\" = $4 AND (value in ($5)))) AND e.hidden != 1::bit(1) AND NOT EXISTS (SELECT 1 FROM muted_author m WHERE m.pub_key = e.pub_key) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
     }
 
     #[test]
@@ -1007,4 +1376,117 @@ mod tests {
         };
         assert!(query_from_filter(&filter).is_none());
     }
+
+    /// Connect to the test database named by `NOSTR_TEST_POSTGRES`
+    /// (a connection string), migrating it if needed.  Tests that need
+    /// a database are skipped when it is not set.
+    async fn test_repo() -> Option<PostgresRepo> {
+        static MIGRATED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+        let Ok(url) = std::env::var("NOSTR_TEST_POSTGRES") else {
+            eprintln!("NOSTR_TEST_POSTGRES is not set, skipping");
+            return None;
+        };
+        let pool = sqlx::pool::PoolOptions::new()
+            .max_connections(4)
+            .connect(&url)
+            .await
+            .unwrap();
+        MIGRATED
+            .get_or_init(|| async {
+                run_migrations(&pool).await.unwrap();
+            })
+            .await;
+        let (_, metrics) = crate::server::create_metrics();
+        Some(PostgresRepo::new(pool.clone(), pool, metrics))
+    }
+
+    #[tokio::test]
+    async fn groups_round_trip() {
+        let Some(repo) = test_repo().await else {
+            return;
+        };
+        let id = format!("test-{}", rand::random::<u32>());
+        let mut group = Group::new(&id, &"a".repeat(64), 1_700_000_000);
+        group.private = true;
+        group.members.insert("b".repeat(64), vec![]);
+        repo.save_group(&group).await.unwrap();
+        let loaded = repo.load_groups().await.unwrap();
+        assert_eq!(loaded.iter().find(|g| g.id == id), Some(&group));
+        repo.delete_group(&id).await.unwrap();
+        let loaded = repo.load_groups().await.unwrap();
+        let deleted = loaded.iter().find(|g| g.id == id).unwrap();
+        assert!(deleted.deleted);
+        assert!(deleted.members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_pages_in_order() {
+        let Some(repo) = test_repo().await else {
+            return;
+        };
+        let author = format!("{:064x}", rand::random::<u128>());
+        let suffix = format!("{:063x}", rand::random::<u128>());
+        let event = |id: char, kind: u64, created_at: u64| {
+            let mut e = Event::simple_event();
+            e.id = format!("{id}{suffix}");
+            e.pubkey = author.clone();
+            e.kind = kind;
+            e.created_at = created_at;
+            e
+        };
+        let events = vec![
+            event('3', 1, 20),
+            event('1', 1, 20),
+            event('2', 7, 20),
+            event('4', 1, 10),
+            event('5', 7, 30),
+        ];
+        repo.write_events(&events).await.unwrap();
+        let filter = |k: u64| -> ReqFilter {
+            serde_json::from_str(&format!(
+                "{{\"authors\":[\"{author}\"],\"kinds\":[{k}],\"limit\":1}}"
+            ))
+            .unwrap()
+        };
+        let filters = vec![filter(1), filter(7)];
+        let ids = |page: Vec<Event>| -> Vec<char> {
+            page.iter().map(|e| e.id.chars().next().unwrap()).collect()
+        };
+        // both filters, in (created_at, id) order, ignoring limits
+        let first = repo.replay_events(&filters, None, 2).await.unwrap();
+        assert_eq!(ids(first), vec!['4', '1']);
+        // events in the same second as the cursor are not skipped
+        let after = Some((20, format!("1{suffix}")));
+        let second = repo.replay_events(&filters, after, 2).await.unwrap();
+        assert_eq!(ids(second), vec!['2', '3']);
+        let after = Some((20, format!("3{suffix}")));
+        let last = repo.replay_events(&filters, after, 2).await.unwrap();
+        assert_eq!(ids(last), vec!['5']);
+    }
+
+    #[tokio::test]
+    async fn invites_round_trip() {
+        let Some(repo) = test_repo().await else {
+            return;
+        };
+        let expires_at = utils::unix_time() + 3600;
+        let invite = Invite::new("admin", 1, Some(expires_at));
+        repo.create_invite(&invite).await.unwrap();
+        let listed = repo.invites(100).await.unwrap();
+        assert_eq!(listed.iter().find(|i| i.code == invite.code), Some(&invite));
+        let keys = Keys::generate();
+        assert!(repo.redeem_invite(&invite.code, &keys).await.unwrap());
+        // the only use is spent
+        assert!(!repo.redeem_invite(&invite.code, &keys).await.unwrap());
+        let (admitted, _) = repo.get_account_balance(&keys).await.unwrap();
+        assert!(admitted);
+        // codes are counted by who minted them
+        let member = Keys::generate().public_key().to_string();
+        repo.create_invite(&Invite::new(&member, 1, None))
+            .await
+            .unwrap();
+        let now = utils::unix_time();
+        assert_eq!(repo.count_invites_by(&member, now - 60).await.unwrap(), 1);
+        assert_eq!(repo.count_invites_by(&member, now + 60).await.unwrap(), 0);
+    }
 }
@@ -37,6 +37,10 @@ pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
     run_migration(m003::migration(), db).await;
     run_migration(m004::migration(), db).await;
     run_migration(m005::migration(), db).await;
+    run_migration(m006::migration(), db).await;
+    run_migration(m007::migration(), db).await;
+    run_migration(m008::migration(), db).await;
+    run_migration(m009::migration(), db).await;
     Ok(current_version(db).await as usize)
 }
 
@@ -318,3 +322,108 @@ CREATE TABLE "invoice" (
         }
     }
 }
+
+mod m006 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Mark events held for moderation (these are also hidden)
+ALTER TABLE "event" ADD COLUMN pending BOOLEAN NOT NULL DEFAULT FALSE;
+-- Index held events, oldest first
+CREATE INDEX event_pending_idx ON "event" (first_seen) WHERE pending;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- NIP-29 groups hosted by the relay
+CREATE TABLE "relay_group" (
+    id varchar NOT NULL,
+    "name" varchar NOT NULL DEFAULT '',
+    about varchar NOT NULL DEFAULT '',
+    picture varchar NOT NULL DEFAULT '',
+    private BOOLEAN NOT NULL DEFAULT FALSE,
+    closed BOOLEAN NOT NULL DEFAULT FALSE,
+    -- deleted groups are kept, so that their ids are not reused
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT relay_group_pkey PRIMARY KEY (id)
+);
+
+-- Group members, and their roles (empty for plain members)
+CREATE TABLE "relay_group_member" (
+    group_id varchar NOT NULL,
+    pubkey varchar NOT NULL,
+    roles varchar NOT NULL DEFAULT '',
+    CONSTRAINT relay_group_member_pkey PRIMARY KEY (group_id, pubkey),
+    CONSTRAINT relay_group_member_group_fkey FOREIGN KEY (group_id) REFERENCES relay_group (id) ON DELETE CASCADE
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Invite codes, which admit the accounts that redeem them
+CREATE TABLE "invite" (
+    code varchar NOT NULL,
+    created_by varchar NOT NULL,
+    max_uses BIGINT NOT NULL,
+    uses BIGINT NOT NULL DEFAULT 0,
+    expires_at timestamp with time zone,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT invite_pkey PRIMARY KEY (code)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m009 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 9;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Authors muted by moderators, whose events are left out of queries
+CREATE TABLE "muted_author" (
+    pub_key bytea NOT NULL,
+    CONSTRAINT muted_author_pkey PRIMARY KEY (pub_key)
+);
+        "#,
+            ],
+        }
+    }
+}
@@ -4,6 +4,8 @@ use crate::config::Settings;
 use crate::db::QueryResult;
 use crate::error::{Error::SqlError, Result};
 use crate::event::{single_char_tagname, Event};
+use crate::groups::Group;
+use crate::invite::Invite;
 use crate::nip05::{Nip05Name, VerificationRecord};
 use crate::payment::{InvoiceInfo, InvoiceStatus};
 use crate::repo::sqlite_migration::{upgrade_db, STARTUP_SQL};
@@ -17,6 +19,8 @@ use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::params;
 use rusqlite::types::ToSql;
 use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+use rusqlite::Transaction;
 use std::fmt::Write as _;
 use std::path::Path;
 use std::sync::Arc;
@@ -27,7 +31,7 @@ use tokio::sync::{Mutex, MutexGuard, Semaphore};
 use tokio::task;
 use tracing::{debug, info, trace, warn};
 
-use crate::repo::{now_jitter, NostrRepo};
+use crate::repo::{now_jitter, NostrRepo, WriteOutcome};
 use nostr::key::Keys;
 
 pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
@@ -108,6 +112,165 @@ impl SqliteRepo {
 
         // start transaction
         let tx = conn.transaction()?;
+        let outcome = SqliteRepo::persist_event_tx(&tx, e)?;
+        tx.commit()?;
+        Ok(outcome.rows())
+    }
+
+    /// Persist several events to the database in a single
+    /// transaction, returning the outcome for each.
+    pub fn persist_events(
+        conn: &mut PooledConnection,
+        events: &[Event],
+    ) -> Result<Vec<WriteOutcome>> {
+        // enable auto vacuum
+        conn.execute_batch("pragma auto_vacuum = FULL")?;
+
+        let tx = conn.transaction()?;
+        let outcomes = events
+            .iter()
+            .map(|e| SqliteRepo::persist_event_tx(&tx, e))
+            .collect::<Result<Vec<WriteOutcome>>>()?;
+        tx.commit()?;
+        Ok(outcomes)
+    }
+
+    /// Store an event held for moderation, hidden until it is
+    /// published.  Returns false if the event was already stored.
+    pub fn persist_pending_event(conn: &mut PooledConnection, e: &Event) -> Result<bool> {
+        let id_blob = hex::decode(&e.id).ok();
+        let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
+        let delegator_blob: Option<Vec<u8>> =
+            e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
+        let event_str = serde_json::to_string(&e).ok();
+        let ins_count = conn.execute(
+            "INSERT OR IGNORE INTO event (event_hash, created_at, expires_at, kind, author, delegated_by, content, first_seen, hidden, pending) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s','now'), TRUE, TRUE);",
+            params![id_blob, e.created_at, e.expiration(), e.kind, pubkey_blob, delegator_blob, event_str]
+        )?;
+        Ok(ins_count > 0)
+    }
+
+    /// Publish an event held for moderation.  The held copy is
+    /// replaced by a normal write, so that replacement and deletion
+    /// take effect now.
+    pub fn publish_pending_event(
+        conn: &mut PooledConnection,
+        id: &[u8],
+    ) -> Result<Option<(Event, WriteOutcome)>> {
+        let tx = conn.transaction()?;
+        let content = tx
+            .query_row(
+                "DELETE FROM event WHERE event_hash=? AND pending=TRUE RETURNING content",
+                params![id],
+                |row| row.get::<usize, String>(0),
+            )
+            .optional()?;
+        let Some(content) = content else {
+            return Ok(None);
+        };
+        let event: Event = serde_json::from_str(&content)?;
+        let outcome = SqliteRepo::persist_event_tx(&tx, &event)?;
+        tx.commit()?;
+        Ok(Some((event, outcome)))
+    }
+
+    /// Store a group's metadata and members, replacing any earlier
+    /// state.
+    pub fn persist_group(conn: &mut PooledConnection, group: &Group) -> Result<()> {
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO relay_group (id, name, about, picture, private, closed, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) ON CONFLICT(id) DO UPDATE SET name=excluded.name, about=excluded.about, picture=excluded.picture, private=excluded.private, closed=excluded.closed",
+            params![group.id, group.name, group.about, group.picture, group.private, group.closed, group.created_at],
+        )?;
+        tx.execute(
+            "DELETE FROM relay_group_member WHERE group_id=?",
+            params![group.id],
+        )?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO relay_group_member (group_id, pubkey, roles) VALUES (?1, ?2, ?3)",
+            )?;
+            for (pubkey, roles) in &group.members {
+                stmt.execute(params![group.id, pubkey, roles.join(",")])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Every stored group, with its members.
+    pub fn read_groups(conn: &PooledConnection) -> Result<Vec<Group>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, name, about, picture, private, closed, deleted, created_at FROM relay_group ORDER BY id",
+        )?;
+        let mut groups = stmt
+            .query_map([], |row| {
+                Ok(Group {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    about: row.get(2)?,
+                    picture: row.get(3)?,
+                    private: row.get(4)?,
+                    closed: row.get(5)?,
+                    deleted: row.get(6)?,
+                    created_at: row.get(7)?,
+                    ..Default::default()
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Group>>>()?;
+        let mut stmt = conn.prepare("SELECT group_id, pubkey, roles FROM relay_group_member")?;
+        let members = stmt.query_map([], |row| {
+            Ok((
+                row.get::<usize, String>(0)?,
+                row.get::<usize, String>(1)?,
+                row.get::<usize, String>(2)?,
+            ))
+        })?;
+        for member in members {
+            let (group_id, pubkey, roles) = member?;
+            if let Some(group) = groups.iter_mut().find(|g| g.id == group_id) {
+                let roles = roles
+                    .split(',')
+                    .filter(|r| !r.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                group.members.insert(pubkey, roles);
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Use an invite code to admit an account, creating it if
+    /// needed.  Returns false if the code is unknown, used up, or
+    /// expired.
+    pub fn consume_invite(
+        conn: &mut PooledConnection,
+        code: &str,
+        pubkey: &str,
+        now: u64,
+    ) -> Result<bool> {
+        let tx = conn.transaction()?;
+        let used = tx.execute(
+            "UPDATE invite SET uses=uses+1 WHERE code=?1 AND uses<max_uses AND (expires_at IS NULL OR expires_at>?2)",
+            params![code, now],
+        )?;
+        if used == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT OR IGNORE INTO account (pubkey, is_admitted, balance) VALUES (?1, FALSE, 0)",
+            params![pubkey],
+        )?;
+        tx.execute(
+            "UPDATE account SET is_admitted=TRUE, tos_accepted_at=?2 WHERE pubkey=?1",
+            params![pubkey, now],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Persist an event within a transaction.
+    fn persist_event_tx(tx: &Transaction, e: &Event) -> Result<WriteOutcome> {
         // get relevant fields from event and convert to blobs.
         let id_blob = hex::decode(&e.id).ok();
         let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
@@ -120,7 +283,7 @@ impl SqliteRepo {
                 "SELECT e.id FROM event e INDEXED BY author_index WHERE e.author=? AND e.kind=? AND e.created_at >= ? LIMIT 1;",
                 params![pubkey_blob, e.kind, e.created_at], |row| row.get::<usize, usize>(0));
             if repl_count.ok().is_some() {
-                return Ok(0);
+                return Ok(WriteOutcome::Blocked);
             }
         }
         // check for parameterized replaceable events that would be hidden; don't insert these either.
@@ -132,19 +295,18 @@ impl SqliteRepo {
             // the same author/kind/tag value exist, and we can ignore
             // this event.
             if repl_count.ok().is_some() {
-                return Ok(0);
+                return Ok(WriteOutcome::Blocked);
             }
         }
         // ignore if the event hash is a duplicate.
-        let mut ins_count = tx.execute(
+        let ins_count = tx.execute(
             "INSERT OR IGNORE INTO event (event_hash, created_at, expires_at, kind, author, delegated_by, content, first_seen, hidden) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s','now'), FALSE);",
             params![id_blob, e.created_at, e.expiration(), e.kind, pubkey_blob, delegator_blob, event_str]
         )? as u64;
         if ins_count == 0 {
             // if the event was a duplicate, no need to insert event or
             // pubkey references.
-            tx.rollback().ok();
-            return Ok(ins_count);
+            return Ok(WriteOutcome::Duplicate);
         }
         // remember primary key of the event most recently inserted.
         let ev_id = tx.last_insert_rowid();
@@ -238,11 +400,10 @@ impl SqliteRepo {
                 // event was deleted, so let caller know nothing new
                 // arrived, preventing this from being sent to active
                 // subscriptions
-                ins_count = 0;
+                return Ok(WriteOutcome::Hidden);
             }
         }
-        tx.commit()?;
-        Ok(ins_count)
+        Ok(WriteOutcome::Saved)
     }
 }
 
@@ -313,6 +474,208 @@ impl NostrRepo for SqliteRepo {
         event_count
     }
 
+    /// Persist several events to database, in one transaction
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<WriteOutcome>> {
+        let start = Instant::now();
+        let max_write_attempts = 10;
+        let mut attempts = 0;
+        let _write_guard = self.write_in_progress.lock().await;
+        let pool = self.write_pool.clone();
+        let events = events.to_vec();
+        let outcomes = task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            // this could fail because the database was busy; try
+            // multiple times before giving up.
+            loop {
+                attempts += 1;
+                let wr = SqliteRepo::persist_events(&mut conn, &events);
+                match wr {
+                    Err(SqlError(rusqlite::Error::SqliteFailure(e, _))) => {
+                        info!(
+                            "event batch write failed, DB locked (attempt: {}); sqlite err: {}",
+                            attempts, e.extended_code
+                        );
+                    }
+                    _ => {
+                        return wr;
+                    }
+                }
+                if attempts >= max_write_attempts {
+                    return wr;
+                }
+            }
+        })
+        .await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        outcomes
+    }
+
+    async fn set_muted_authors(&self, pubkeys: Vec<String>) -> Result<()> {
+        let authors = pubkeys
+            .iter()
+            .map(hex::decode)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM muted_author", [])?;
+            {
+                let mut stmt =
+                    tx.prepare("INSERT OR IGNORE INTO muted_author (author) VALUES (?)")?;
+                for author in authors {
+                    stmt.execute(params![author])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn write_pending_event(&self, e: &Event) -> Result<bool> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let e = e.clone();
+        task::spawn_blocking(move || SqliteRepo::persist_pending_event(&mut conn, &e)).await?
+    }
+
+    async fn replay_events(
+        &self,
+        filters: &[ReqFilter],
+        after: Option<(u64, String)>,
+        limit: u64,
+    ) -> Result<Vec<Event>> {
+        if filters.is_empty() {
+            return Ok(vec![]);
+        }
+        let after = match after {
+            Some((created_at, id)) => Some((created_at, hex::decode(id)?)),
+            None => None,
+        };
+        let filters = filters.to_vec();
+        let conn = self.read_pool.get()?;
+        let contents = task::spawn_blocking(move || {
+            let (query, params) = replay_query(&filters, after.as_ref(), limit);
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+                row.get::<usize, String>(0)
+            })?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })
+        .await??;
+        contents
+            .iter()
+            .map(|c| Ok(serde_json::from_str(c)?))
+            .collect()
+    }
+
+    async fn pending_events(&self, limit: u64) -> Result<Vec<Event>> {
+        let conn = self.read_pool.get()?;
+        let contents = task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT content FROM event WHERE pending=TRUE ORDER BY first_seen LIMIT ?",
+            )?;
+            let rows = stmt.query_map(params![limit], |row| row.get::<usize, String>(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })
+        .await??;
+        Ok(contents
+            .iter()
+            .filter_map(|c| serde_json::from_str(c).ok())
+            .collect())
+    }
+
+    async fn approve_pending_event(&self, id: &str) -> Result<Option<(Event, WriteOutcome)>> {
+        let id_blob = hex::decode(id)?;
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        task::spawn_blocking(move || SqliteRepo::publish_pending_event(&mut conn, &id_blob)).await?
+    }
+
+    async fn reject_pending_event(&self, id: &str) -> Result<bool> {
+        let id_blob = hex::decode(id)?;
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let count = task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM event WHERE event_hash=? AND pending=TRUE",
+                params![id_blob],
+            )
+        })
+        .await??;
+        Ok(count > 0)
+    }
+
+    async fn author_has_events(&self, pubkey: &str) -> Result<bool> {
+        let author = hex::decode(pubkey)?;
+        let conn = self.read_pool.get()?;
+        let found = task::spawn_blocking(move || {
+            conn.query_row(
+                "SELECT 1 FROM event INDEXED BY author_index WHERE author=? AND hidden!=TRUE LIMIT 1",
+                params![author],
+                |_| Ok(()),
+            )
+            .optional()
+        })
+        .await??;
+        Ok(found.is_some())
+    }
+
+    async fn hide_events(&self, ids: &[String]) -> Result<u64> {
+        let params: Vec<Vec<u8>> = ids
+            .iter()
+            .filter(|x| is_hex(x) && x.len() == 64)
+            .filter_map(|x| hex::decode(x).ok())
+            .collect();
+        if params.is_empty() {
+            return Ok(0);
+        }
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let count = task::spawn_blocking(move || {
+            let query = format!(
+                "UPDATE event SET hidden=TRUE WHERE hidden!=TRUE AND event_hash IN ({})",
+                repeat_vars(params.len())
+            );
+            conn.execute(&query, rusqlite::params_from_iter(params))
+        })
+        .await??;
+        Ok(count as u64)
+    }
+
+    async fn load_groups(&self) -> Result<Vec<Group>> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || SqliteRepo::read_groups(&conn)).await?
+    }
+
+    async fn save_group(&self, group: &Group) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let group = group.clone();
+        task::spawn_blocking(move || SqliteRepo::persist_group(&mut conn, &group)).await?
+    }
+
+    async fn delete_group(&self, id: &str) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let id = id.to_owned();
+        task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM relay_group_member WHERE group_id=?",
+                params![id],
+            )?;
+            conn.execute(
+                "UPDATE relay_group SET deleted=TRUE WHERE id=?",
+                params![id],
+            )
+        })
+        .await??;
+        Ok(())
+    }
+
     /// Perform a database query using a subscription.
     ///
     /// The [`Subscription`] is converted into a SQL query.  Each result
@@ -922,6 +1285,67 @@ LIMIT 1;
             confirmed_at: None,
         }))
     }
+
+    async fn create_invite(&self, invite: &Invite) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let invite = invite.clone();
+        task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT INTO invite (code, created_by, max_uses, uses, expires_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![invite.code, invite.created_by, invite.max_uses, invite.uses, invite.expires_at, invite.created_at],
+            )
+        })
+        .await??;
+        Ok(())
+    }
+
+    async fn redeem_invite(&self, code: &str, pubkey: &Keys) -> Result<bool> {
+        let pubkey = pubkey.public_key().to_string();
+        let code = code.to_owned();
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        task::spawn_blocking(move || {
+            SqliteRepo::consume_invite(&mut conn, &code, &pubkey, unix_time())
+        })
+        .await?
+    }
+
+    async fn count_invites_by(&self, created_by: &str, since: u64) -> Result<u64> {
+        let conn = self.read_pool.get()?;
+        let created_by = created_by.to_owned();
+        let count = task::spawn_blocking(move || {
+            conn.query_row(
+                "SELECT COUNT(*) FROM invite WHERE created_by=? AND created_at>=?",
+                params![created_by, since],
+                |row| row.get(0),
+            )
+        })
+        .await??;
+        Ok(count)
+    }
+
+    async fn invites(&self, limit: u64) -> Result<Vec<Invite>> {
+        let conn = self.read_pool.get()?;
+        let invites = task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT code, created_by, max_uses, uses, expires_at, created_at FROM invite ORDER BY created_at DESC LIMIT ?",
+            )?;
+            let rows = stmt.query_map(params![limit], |row| {
+                Ok(Invite {
+                    code: row.get(0)?,
+                    created_by: row.get(1)?,
+                    max_uses: row.get(2)?,
+                    uses: row.get(3)?,
+                    expires_at: row.get(4)?,
+                    created_at: row.get(5)?,
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<Invite>>>()
+        })
+        .await??;
+        Ok(invites)
+    }
 }
 
 /// Decide if there is an index that should be used explicitly
@@ -960,6 +1384,24 @@ fn override_index(f: &ReqFilter) -> Option<String> {
 
 /// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
 fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
+    let (mut query, params, idx_name) = filter_conditions(f, None);
+    // Apply per-filter limit to this subquery.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
+    } else {
+        query.push_str(" ORDER BY e.created_at ASC");
+    }
+    (query, params, idx_name)
+}
+
+/// Create a SQL query selecting the events matching a filter, and
+/// only those after the (`created_at`, id) position if one is given.
+/// The query is left unordered.
+fn filter_conditions(
+    f: &ReqFilter,
+    after: Option<&(u64, Vec<u8>)>,
+) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
     // build a dynamic SQL query.  all user-input is either an integer
     // (sqli-safe), or a string that is filtered to only contain
     // hexadecimal characters.  Strings that require escaping (tag
@@ -967,7 +1409,8 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
 
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
-        let empty_query = "SELECT e.content FROM event e WHERE 1=0".to_owned();
+        let empty_query =
+            "SELECT e.content, e.created_at, e.event_hash FROM event e WHERE 1=0".to_owned();
         // query parameters for SQLite
         let empty_params: Vec<Box<dyn ToSql>> = vec![];
         return (empty_query, empty_params, None);
@@ -978,7 +1421,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     let idx_stmt = idx_name
         .as_ref()
         .map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
-    let mut query = format!("SELECT e.content FROM event e {idx_stmt}");
+    let mut query = format!("SELECT e.content, e.created_at, e.event_hash FROM event e {idx_stmt}");
     // query parameters for SQLite
     let mut params: Vec<Box<dyn ToSql>> = vec![];
 
@@ -1078,8 +1521,16 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         let until_clause = format!("created_at <= {}", f.until.unwrap());
         filter_components.push(until_clause);
     }
-    // never display hidden events
-    query.push_str(" WHERE hidden!=TRUE");
+    // resume after the last event returned
+    if let Some((created_at, id)) = after {
+        filter_components.push("(e.created_at, e.event_hash) > (?, ?)".to_owned());
+        params.push(Box::new(*created_at));
+        params.push(Box::new(id.clone()));
+    }
+    // never display hidden events, or those of muted authors
+    query.push_str(
+        " WHERE hidden!=TRUE AND NOT EXISTS (SELECT 1 FROM muted_author m WHERE m.author=e.author)",
+    );
     // never display hidden events
     filter_components.push("(expires_at IS NULL OR expires_at > ?)".to_string());
     params.push(Box::new(unix_time()));
@@ -1088,16 +1539,35 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         query.push_str(" AND ");
         query.push_str(&filter_components.join(" AND "));
     }
-    // Apply per-filter limit to this subquery.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
-    } else {
-        query.push_str(" ORDER BY e.created_at ASC");
-    }
     (query, params, idx_name)
 }
 
+/// Create a SQL query and params for at most `limit` events matching
+/// any of the filters, after the (`created_at`, id) position, in that
+/// order.  Filter limits are ignored.
+fn replay_query(
+    filters: &[ReqFilter],
+    after: Option<&(u64, Vec<u8>)>,
+    limit: u64,
+) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut subqueries: Vec<String> = vec![];
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    for f in filters {
+        // each filter stops at the page size too, so a page never
+        // reads more than it could return
+        let (f_subquery, mut f_params, _) = filter_conditions(f, after);
+        params.append(&mut f_params);
+        subqueries.push(format!(
+            "SELECT content, created_at, event_hash FROM ({f_subquery} ORDER BY e.created_at, e.event_hash LIMIT {limit})"
+        ));
+    }
+    let query = format!(
+        "{} ORDER BY created_at, event_hash LIMIT {limit}",
+        subqueries.join(" UNION ")
+    );
+    (query, params)
+}
+
 /// Create a dynamic SQL query string and params from a subscription.
 fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<String>) {
     // build a dynamic SQL query for an entire subscription, based on
@@ -1351,3 +1821,183 @@ fn _pool_at_capacity(pool: &SqlitePool) -> bool {
     let state: r2d2::State = pool.state();
     state.idle_connections == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_conn() -> PooledConnection {
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        let mut conn = pool.get().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        conn
+    }
+
+    fn event(id: char, kind: u64, created_at: u64) -> Event {
+        let mut e = Event::simple_event();
+        e.id = id.to_string().repeat(64);
+        e.pubkey = "a".repeat(64);
+        e.kind = kind;
+        e.created_at = created_at;
+        e
+    }
+
+    #[test]
+    fn persist_events_outcomes() {
+        let mut conn = memory_conn();
+        let events = vec![
+            event('1', 1, 10),
+            event('1', 1, 10),
+            event('2', 0, 20),
+            event('3', 0, 15),
+        ];
+        let outcomes = SqliteRepo::persist_events(&mut conn, &events).unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                WriteOutcome::Saved,
+                WriteOutcome::Duplicate,
+                WriteOutcome::Saved,
+                WriteOutcome::Blocked,
+            ]
+        );
+        // the batch was committed
+        let outcomes = SqliteRepo::persist_events(&mut conn, &events[..1]).unwrap();
+        assert_eq!(outcomes, vec![WriteOutcome::Duplicate]);
+    }
+
+    /// Run a replay query, returning the ids of the events
+    fn replay_ids(
+        conn: &PooledConnection,
+        filters: &[ReqFilter],
+        after: Option<(u64, char)>,
+        limit: u64,
+    ) -> Vec<char> {
+        let after = after
+            .map(|(created_at, id)| (created_at, hex::decode(id.to_string().repeat(64)).unwrap()));
+        let (q, p) = replay_query(filters, after.as_ref(), limit);
+        let mut stmt = conn.prepare(&q).unwrap();
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(p), |row| {
+                row.get::<usize, String>(0)
+            })
+            .unwrap();
+        rows.map(|r| {
+            let e: Event = serde_json::from_str(&r.unwrap()).unwrap();
+            e.id.chars().next().unwrap()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn replay_pages_in_order() {
+        let mut conn = memory_conn();
+        let events = vec![
+            event('3', 1, 20),
+            event('1', 1, 20),
+            event('2', 7, 20),
+            event('4', 1, 10),
+            event('5', 7, 30),
+        ];
+        SqliteRepo::persist_events(&mut conn, &events).unwrap();
+        let kinds = |k: u64| -> ReqFilter {
+            serde_json::from_str(&format!("{{\"kinds\":[{k}],\"limit\":1}}")).unwrap()
+        };
+        // both filters, in (created_at, id) order, ignoring limits
+        let filters = vec![kinds(1), kinds(7)];
+        let first = replay_ids(&conn, &filters, None, 2);
+        assert_eq!(first, vec!['4', '1']);
+        // events in the same second as the cursor are not skipped
+        let second = replay_ids(&conn, &filters, Some((20, '1')), 2);
+        assert_eq!(second, vec!['2', '3']);
+        let last = replay_ids(&conn, &filters, Some((20, '3')), 2);
+        assert_eq!(last, vec!['5']);
+    }
+
+    #[test]
+    fn muted_authors_left_out() {
+        let mut conn = memory_conn();
+        let mut muted = event('2', 1, 20);
+        muted.pubkey = "b".repeat(64);
+        SqliteRepo::persist_events(&mut conn, &[event('1', 1, 10), muted]).unwrap();
+        let filters: Vec<ReqFilter> = vec![serde_json::from_str("{}").unwrap()];
+        conn.execute(
+            "INSERT INTO muted_author (author) VALUES (?)",
+            params![hex::decode("b".repeat(64)).unwrap()],
+        )
+        .unwrap();
+        assert_eq!(replay_ids(&conn, &filters, None, 10), vec!['1']);
+        // un-muting shows the events again
+        conn.execute("DELETE FROM muted_author", []).unwrap();
+        assert_eq!(replay_ids(&conn, &filters, None, 10), vec!['1', '2']);
+    }
+
+    #[test]
+    fn pending_events_published() {
+        let mut conn = memory_conn();
+        let held = event('1', 1, 10);
+        assert!(SqliteRepo::persist_pending_event(&mut conn, &held).unwrap());
+        assert!(!SqliteRepo::persist_pending_event(&mut conn, &held).unwrap());
+        let visible: i64 = conn
+            .query_row("SELECT COUNT(*) FROM event WHERE hidden!=TRUE", [], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_eq!(visible, 0);
+        let id = hex::decode(&held.id).unwrap();
+        let (event, outcome) = SqliteRepo::publish_pending_event(&mut conn, &id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.id, held.id);
+        assert_eq!(outcome, WriteOutcome::Saved);
+        // it is no longer held
+        assert!(SqliteRepo::publish_pending_event(&mut conn, &id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn groups_round_trip() {
+        let mut conn = memory_conn();
+        let mut group = Group::new("pizza", &"a".repeat(64), 10);
+        group.private = true;
+        group.members.insert("b".repeat(64), vec![]);
+        SqliteRepo::persist_group(&mut conn, &group).unwrap();
+        assert_eq!(SqliteRepo::read_groups(&conn).unwrap(), vec![group.clone()]);
+        // saving again replaces the members
+        group.members.remove(&"b".repeat(64));
+        group.name = "Pizza lovers".to_owned();
+        SqliteRepo::persist_group(&mut conn, &group).unwrap();
+        assert_eq!(SqliteRepo::read_groups(&conn).unwrap(), vec![group]);
+    }
+
+    #[test]
+    fn invites_admit_accounts() {
+        let mut conn = memory_conn();
+        let invite = Invite::new("admin", 1, Some(100));
+        conn.execute(
+            "INSERT INTO invite (code, created_by, max_uses, uses, expires_at, created_at) VALUES (?1, ?2, ?3, 0, ?4, 0)",
+            params![invite.code, invite.created_by, invite.max_uses, invite.expires_at],
+        )
+        .unwrap();
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        // expired codes are not redeemed
+        assert!(!SqliteRepo::consume_invite(&mut conn, &invite.code, &a, 100).unwrap());
+        assert!(SqliteRepo::consume_invite(&mut conn, &invite.code, &a, 50).unwrap());
+        // the code is used up
+        assert!(!SqliteRepo::consume_invite(&mut conn, &invite.code, &b, 50).unwrap());
+        assert!(!SqliteRepo::consume_invite(&mut conn, "unknown", &b, 50).unwrap());
+        let admitted: bool = conn
+            .query_row(
+                "SELECT is_admitted FROM account WHERE pubkey=?",
+                params![a],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert!(admitted);
+    }
+}
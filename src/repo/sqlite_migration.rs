@@ -23,7 +23,7 @@ pragma mmap_size = 0; -- disable mmap (default)
 "##;
 
 /// Latest database version
-pub const DB_VERSION: usize = 18;
+pub const DB_VERSION: usize = 22;
 
 /// Schema definition
 const INIT_SQL: &str = formatcp!(
@@ -48,6 +48,7 @@ author BLOB NOT NULL, -- author pubkey
 delegated_by BLOB, -- delegator pubkey (NIP-26)
 kind INTEGER NOT NULL, -- event kind
 hidden INTEGER, -- relevant for queries
+pending INTEGER NOT NULL DEFAULT 0, -- held for moderation (and hidden)
 content TEXT NOT NULL -- serialized json of event object
 );
 
@@ -63,6 +64,7 @@ CREATE INDEX IF NOT EXISTS kind_created_at_index ON event(kind,created_at);
 CREATE INDEX IF NOT EXISTS author_created_at_index ON event(author,created_at);
 CREATE INDEX IF NOT EXISTS author_kind_index ON event(author,kind);
 CREATE INDEX IF NOT EXISTS event_expiration ON event(expires_at);
+CREATE INDEX IF NOT EXISTS event_pending_index ON event(first_seen) WHERE pending=TRUE;
 
 -- Tag Table
 -- Tag values are stored as either a BLOB (if they come in as a
@@ -124,6 +126,41 @@ CONSTRAINT invoice_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey)
 -- Create invoice index
 CREATE INDEX IF NOT EXISTS invoice_pubkey_index ON invoice(pubkey);
 
+-- NIP-29 Groups
+CREATE TABLE IF NOT EXISTS relay_group (
+id TEXT PRIMARY KEY,
+name TEXT NOT NULL DEFAULT '',
+about TEXT NOT NULL DEFAULT '',
+picture TEXT NOT NULL DEFAULT '',
+private INTEGER NOT NULL DEFAULT 0, -- only members may read
+closed INTEGER NOT NULL DEFAULT 0, -- only admins may add members
+deleted INTEGER NOT NULL DEFAULT 0, -- kept so the id is not reused
+created_at INTEGER NOT NULL
+);
+
+-- NIP-29 Group Members
+CREATE TABLE IF NOT EXISTS relay_group_member (
+group_id TEXT NOT NULL,
+pubkey TEXT NOT NULL,
+roles TEXT NOT NULL DEFAULT '', -- comma-separated, empty for plain members
+PRIMARY KEY(group_id, pubkey),
+FOREIGN KEY(group_id) REFERENCES relay_group(id) ON DELETE CASCADE
+);
+
+-- Invite codes
+CREATE TABLE IF NOT EXISTS invite (
+code TEXT PRIMARY KEY,
+created_by TEXT NOT NULL, -- pubkey of the member who minted it, or "admin"
+max_uses INTEGER NOT NULL,
+uses INTEGER NOT NULL DEFAULT 0,
+expires_at INTEGER, -- when the code can no longer be redeemed
+created_at INTEGER NOT NULL
+);
+
+-- Authors muted by moderators, whose events are left out of queries
+CREATE TABLE IF NOT EXISTS muted_author (
+author BLOB PRIMARY KEY
+);
 
 "##,
     DB_VERSION
@@ -245,6 +282,18 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
             if curr_version == 17 {
                 curr_version = mig_17_to_18(conn)?;
             }
+            if curr_version == 18 {
+                curr_version = mig_18_to_19(conn)?;
+            }
+            if curr_version == 19 {
+                curr_version = mig_19_to_20(conn)?;
+            }
+            if curr_version == 20 {
+                curr_version = mig_20_to_21(conn)?;
+            }
+            if curr_version == 21 {
+                curr_version = mig_21_to_22(conn)?;
+            }
 
             if curr_version == DB_VERSION {
                 info!(
@@ -839,3 +888,106 @@ PRAGMA user_version = 18;
     }
     Ok(18)
 }
+
+fn mig_18_to_19(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 18->19");
+    let upgrade_sql = r##"
+ALTER TABLE event ADD COLUMN pending INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS event_pending_index ON event(first_seen) WHERE pending=TRUE;
+PRAGMA user_version = 19;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v18 -> v19");
+        }
+        Err(err) => {
+            error!("update (v18->v19) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(19)
+}
+
+fn mig_19_to_20(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 19->20");
+    let upgrade_sql = r##"
+-- NIP-29 Groups
+CREATE TABLE IF NOT EXISTS relay_group (
+id TEXT PRIMARY KEY,
+name TEXT NOT NULL DEFAULT '',
+about TEXT NOT NULL DEFAULT '',
+picture TEXT NOT NULL DEFAULT '',
+private INTEGER NOT NULL DEFAULT 0, -- only members may read
+closed INTEGER NOT NULL DEFAULT 0, -- only admins may add members
+deleted INTEGER NOT NULL DEFAULT 0, -- kept so the id is not reused
+created_at INTEGER NOT NULL
+);
+
+-- NIP-29 Group Members
+CREATE TABLE IF NOT EXISTS relay_group_member (
+group_id TEXT NOT NULL,
+pubkey TEXT NOT NULL,
+roles TEXT NOT NULL DEFAULT '', -- comma-separated, empty for plain members
+PRIMARY KEY(group_id, pubkey),
+FOREIGN KEY(group_id) REFERENCES relay_group(id) ON DELETE CASCADE
+);
+PRAGMA user_version = 20;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v19 -> v20");
+        }
+        Err(err) => {
+            error!("update (v19->v20) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(20)
+}
+
+fn mig_20_to_21(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 20->21");
+    let upgrade_sql = r##"
+-- Invite codes
+CREATE TABLE IF NOT EXISTS invite (
+code TEXT PRIMARY KEY,
+created_by TEXT NOT NULL, -- pubkey of the member who minted it, or "admin"
+max_uses INTEGER NOT NULL,
+uses INTEGER NOT NULL DEFAULT 0,
+expires_at INTEGER, -- when the code can no longer be redeemed
+created_at INTEGER NOT NULL
+);
+PRAGMA user_version = 21;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v20 -> v21");
+        }
+        Err(err) => {
+            error!("update (v20->v21) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(21)
+}
+
+fn mig_21_to_22(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 21->22");
+    let upgrade_sql = r##"
+-- Authors muted by moderators, whose events are left out of queries
+CREATE TABLE IF NOT EXISTS muted_author (
+author BLOB PRIMARY KEY
+);
+PRAGMA user_version = 22;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v21 -> v22");
+        }
+        Err(err) => {
+            error!("update (v21->v22) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(22)
+}
@@ -1,17 +1,27 @@
 //! Server process
 use crate::close::Close;
 use crate::close::CloseCmd;
-use crate::config::{Settings, VerifiedUsersMode};
+use crate::config::{LagPolicy, ListenerRole, Settings, VerifiedUsersMode};
 use crate::conn;
 use crate::db;
 use crate::db::SubmittedEvent;
+use crate::deflate::{self, DeflateStream, MessageDeflater};
+use crate::duplicates::DuplicateDetector;
 use crate::error::{Error, Result};
 use crate::event::Event;
 use crate::event::EventCmd;
 use crate::event::EventWrapper;
+use crate::forwarded;
+use crate::groups::GroupManager;
 use crate::info::RelayInfo;
+use crate::invite::{self, Invite, Redemption};
+use crate::ipaccess::IpAccess;
+use crate::listener::{accept_connections, ClientStream, SocketListener};
+use crate::nauthz::{self, AuthzDecision};
 use crate::nip05;
 use crate::notice::Notice;
+use crate::nstream;
+use crate::outbound::Outbound;
 use crate::payment;
 use crate::payment::InvoiceInfo;
 use crate::payment::PaymentMessage;
@@ -19,16 +29,20 @@ use crate::repo::NostrRepo;
 use crate::server::Error::CommandUnknownError;
 use crate::server::EventWrapper::{WrappedAuth, WrappedEvent};
 use crate::subscription::Subscription;
-use futures::SinkExt;
+use crate::utils::{is_hex, unix_time};
+use crate::verify::VerifyPool;
+use futures::future::join_all;
+use futures::stream::FuturesOrdered;
 use futures::StreamExt;
 use governor::{Jitter, Quota, RateLimiter};
-use http::header::HeaderMap;
-use hyper::body::to_bytes;
+use http::header::{HeaderMap, HeaderValue};
+use hyper::body::{to_bytes, HttpBody};
 use hyper::header::ACCEPT;
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::upgrade::Upgraded;
 use hyper::{
-    header, server::conn::AddrStream, upgrade, Body, Request, Response, Server, StatusCode,
+    header, upgrade, Body, Method, Request, Response, Server, StatusCode,
 };
 use nostr::key::FromPkStr;
 use nostr::key::Keys;
@@ -42,15 +56,20 @@ use serde_json::json;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fs::File;
+use std::future::Future;
 use std::io::BufReader;
 use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::Receiver as MpscReceiver;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::runtime::Builder;
 use tokio::sync::broadcast::{self, Receiver, Sender};
 use tokio::sync::mpsc;
@@ -65,6 +84,9 @@ use tungstenite::protocol::WebSocketConfig;
 use tera::{Context, Tera};
 use hyper_staticfile::Static;
 
+/// Most bytes read from a submitted form
+const MAX_FORM_BYTES: usize = 4096;
+
 fn status_and_text(status: StatusCode, msg: &'static str) -> Response<Body> {
     Response::builder()
         .status(status)
@@ -103,6 +125,7 @@ async fn handle_web_request(
     repo: Arc<dyn NostrRepo>,
     settings: Settings,
     remote_addr: SocketAddr,
+    role: ListenerRole,
     broadcast: Sender<Event>,
     event_tx: tokio::sync::mpsc::Sender<SubmittedEvent>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
@@ -111,7 +134,17 @@ async fn handle_web_request(
     metrics: NostrMetrics,
     tera: Arc<Tera>,
     static_: Static,
+    authz: Option<nauthz::EventAuthzService>,
+    verifier: VerifyPool,
+    ip_access: IpAccess,
+    duplicates: DuplicateDetector,
+    groups: GroupManager,
+    moderation_tx: mpsc::Sender<db::Moderation>,
+    admissions: invite::Admissions,
 ) -> Result<Response<Body>, Infallible> {
+    if !role.serves(request.uri().path()) {
+        return Ok(status_and_text(StatusCode::NOT_FOUND, "Not found"));
+    }
     match (
         request.uri().path(),
         request.headers().contains_key(header::UPGRADE),
@@ -119,11 +152,45 @@ async fn handle_web_request(
         // Request for / as websocket
         ("/", true) => {
             trace!("websocket with upgrade request");
+            let remote_ip = client_ip(request.headers(), &settings, remote_addr);
+            let ip_slot = match ip_access.admit(remote_ip) {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    info!(
+                        "connection rejected: {} (IP: {})",
+                        reason.label(),
+                        remote_ip
+                    );
+                    metrics
+                        .rejected_connections
+                        .with_label_values(&[reason.label()])
+                        .inc();
+                    return Ok(status_and_text(reason.status(), reason.message()));
+                }
+            };
+            if settings.grpc.connection_admission {
+                if let Some(ref a) = authz {
+                    if let Some(resp) =
+                        connection_rejection(&request, &settings, remote_addr, a).await
+                    {
+                        return Ok(resp);
+                    }
+                }
+            }
+            // compress messages, if the client supports it
+            let compression =
+                settings.network.compression && deflate::offer_acceptable(request.headers());
             //assume request is a handshake, so create the handshake response
             let response = match handshake::server::create_response_with_body(&request, || {
                 Body::empty()
             }) {
-                Ok(response) => {
+                Ok(mut response) => {
+                    if compression {
+                        response.headers_mut().insert(
+                            "sec-websocket-extensions",
+                            HeaderValue::from_static(deflate::DEFLATE_RESPONSE),
+                        );
+                    }
                     //in case the handshake response creation succeeds,
                     //spawn a task to handle the websocket connection
                     tokio::spawn(async move {
@@ -138,6 +205,16 @@ async fn handle_web_request(
                                     max_frame_size: settings.limits.max_ws_frame_bytes,
                                     ..Default::default()
                                 };
+                                // compressed messages from the client are
+                                // inflated before the websocket sees them.
+                                let upgraded = DeflateStream::new(
+                                    upgraded,
+                                    compression,
+                                    settings.limits.max_ws_message_bytes,
+                                    settings.limits.max_ws_frame_bytes,
+                                    metrics.ws_raw_bytes.clone(),
+                                    metrics.ws_compressed_bytes.clone(),
+                                );
                                 //create a websocket stream from the upgraded object
                                 let ws_stream = WebSocketStream::from_raw_socket(
                                     //pass the upgraded object
@@ -149,31 +226,32 @@ async fn handle_web_request(
                                 .await;
                                 let origin = get_header_string("origin", request.headers());
                                 let user_agent = get_header_string("user-agent", request.headers());
-                                // determine the remote IP from headers if the exist
-                                let header_ip = settings
-                                    .network
-                                    .remote_ip_header
-                                    .as_ref()
-                                    .and_then(|x| get_header_string(x, request.headers()));
-                                // use the socket addr as a backup
-                                let remote_ip =
-                                    header_ip.unwrap_or_else(|| remote_addr.ip().to_string());
                                 let client_info = ClientInfo {
-                                    remote_ip,
+                                    remote_ip: remote_ip.to_string(),
                                     user_agent,
                                     origin,
+                                    compression,
                                 };
-                                // spawn a nostr server with our websocket
-                                tokio::spawn(nostr_server(
-                                    repo,
-                                    client_info,
-                                    settings,
-                                    ws_stream,
-                                    broadcast,
-                                    event_tx,
-                                    shutdown,
-                                    metrics,
-                                ));
+                                // spawn a nostr server with our websocket.
+                                // The connection counts against the
+                                // client's address until it finishes.
+                                tokio::spawn(async move {
+                                    nostr_server(
+                                        repo,
+                                        client_info,
+                                        settings,
+                                        ws_stream,
+                                        broadcast,
+                                        event_tx,
+                                        shutdown,
+                                        metrics,
+                                        authz,
+                                        verifier,
+                                        groups,
+                                    )
+                                    .await;
+                                    drop(ip_slot);
+                                });
                             }
                             // todo: trace, don't print...
                             Err(e) => println!(
@@ -253,6 +331,40 @@ async fn handle_web_request(
                 .body(Body::from(buffer))
                 .unwrap())
         }
+        // content posted by the most authors, for moderators
+        ("/admin/duplicates", false) => {
+            let offenders = duplicates.top_offenders(20);
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&offenders).unwrap()))
+                .unwrap())
+        }
+        // events held for moderation, oldest first
+        ("/admin/quarantine", false) => match repo.pending_events(100).await {
+            Ok(events) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&events).unwrap()))
+                .unwrap()),
+            Err(e) => {
+                warn!("could not list held events: {:?}", e);
+                Ok(status_and_text(StatusCode::INTERNAL_SERVER_ERROR, "Could not list held events"))
+            }
+        },
+        ("/admin/quarantine/approve", false) => {
+            Ok(moderate_pending(&request, &settings, &moderation_tx, true).await)
+        }
+        ("/admin/quarantine/reject", false) => {
+            Ok(moderate_pending(&request, &settings, &moderation_tx, false).await)
+        }
+        // invite codes, newest first, or mint one with a POST
+        ("/admin/invites", false) => {
+            if request.method() == Method::POST {
+                return Ok(mint_invite(&request, &settings, &repo).await);
+            }
+            Ok(list_invites(&request, &settings, &repo).await)
+        }
         // LN bits callback endpoint for paid invoices
         ("/lnbits", false) => {
             let callback: payment::lnbits::LNBitsCallback =
@@ -275,11 +387,52 @@ async fn handle_web_request(
         // Endpoint to allow users to sign up
         ("/join", false) => {
             // Stops sign ups if disabled
-            if !settings.pay_to_relay.sign_ups {
+            if !settings.pay_to_relay.sign_ups && !settings.invites.enabled {
                 return Ok(status_and_text(StatusCode::UNAUTHORIZED, "Sorry, joining is not allowed at the moment"));
             }
 
-            Ok(template(&tera, "join.html", &Context::default()))
+            let mut ctx = Context::new();
+            ctx.insert("sign_ups", &settings.pay_to_relay.sign_ups);
+            ctx.insert("invites", &settings.invites.enabled);
+            Ok(template(&tera, "join.html", &ctx))
+        }
+        // Endpoint to join with an invite code
+        ("/redeem", false) => {
+            if !settings.invites.enabled {
+                return Ok(status_and_text(StatusCode::UNAUTHORIZED, "Sorry, this relay does not accept invite codes"));
+            }
+
+            // Codes are spent, so they are only redeemed by a POST
+            if request.method() != Method::POST {
+                return Ok(redirect("/join"));
+            }
+            let Some(mut form) = form_fields(request.into_body()).await else {
+                return Ok(status_and_text(StatusCode::BAD_REQUEST, "Invalid form"));
+            };
+
+            // Redirect back to join page if no pub key is found in the form
+            let (Some(pubkey), Some(code)) = (form.remove("pubkey"), form.remove("code")) else {
+                return Ok(redirect("/join"));
+            };
+
+            // Checks key is valid
+            if Keys::from_pk_str(&pubkey).is_err() {
+                return Ok(status_and_text(StatusCode::UNAUTHORIZED, "Looks like your key is invalid"));
+            }
+
+            match invite::redeem(&repo, &admissions, code.trim(), &pubkey).await {
+                Ok(Redemption::Admitted | Redemption::AlreadyAdmitted) => {
+                    Ok(redirect(&format!("/account?pubkey={}", &pubkey)))
+                }
+                Ok(Redemption::Invalid) => Ok(status_and_text(
+                    StatusCode::UNAUTHORIZED,
+                    "Sorry, that invite code is invalid, used up, or expired",
+                )),
+                Err(e) => {
+                    warn!("could not redeem invite code: {:?}", e);
+                    Ok(status_and_text(StatusCode::INTERNAL_SERVER_ERROR, "Sorry, something went wrong"))
+                }
+            }
         }
         // Endpoint to display invoice
         ("/invoice", false) => {
@@ -370,7 +523,7 @@ async fn handle_web_request(
         }
         ("/account", false) => {
             // Stops sign ups if disabled
-            if !settings.pay_to_relay.enabled {
+            if !settings.pay_to_relay.enabled && !settings.invites.enabled {
                 return Ok(status_and_text(StatusCode::UNAUTHORIZED, "This relay is not paid"));
             }
 
@@ -391,8 +544,10 @@ async fn handle_web_request(
 
             // Account is checked async so user will have to refresh the page a couple times after
             // they have paid.
-            if let Err(e) = payment_tx.send(PaymentMessage::CheckAccount(pubkey.clone())) {
-                warn!("Could not check account: {}", e);
+            if settings.pay_to_relay.enabled {
+                if let Err(e) = payment_tx.send(PaymentMessage::CheckAccount(pubkey.clone())) {
+                    warn!("Could not check account: {}", e);
+                }
             }
             // Checks if user is already admitted
             let status =
@@ -419,26 +574,227 @@ async fn handle_web_request(
 
 // Get pubkey from request query string
 fn get_pubkey(request: &Request<Body>) -> Option<String> {
+    get_query_value(request, "pubkey")
+}
+
+// Get the last value for a key from the request query string
+fn get_query_value(request: &Request<Body>, name: &str) -> Option<String> {
     let query = request.uri().query().unwrap_or("").to_string();
 
-    // Gets the pubkey value from query string
     query.split('&').fold(None, |acc, pair| {
         let mut parts = pair.splitn(2, '=');
         let key = parts.next();
         let value = parts.next();
-        if key == Some("pubkey") {
+        if key == Some(name) {
             return value.map(|s| s.to_owned());
         }
         acc
     })
 }
 
+/// Is the request from an administrator?  Admin requests that change
+/// state or reveal secrets must carry the configured token as a bearer
+/// token, which a cross-site form cannot send.
+fn admin_authorized(request: &Request<Body>, settings: &Settings) -> bool {
+    let Some(token) = settings.authorization.admin_token.as_deref().filter(|t| !t.is_empty()) else {
+        return false;
+    };
+    get_header_string("authorization", request.headers())
+        .as_deref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|given| bool::from(given.trim().as_bytes().ct_eq(token.as_bytes())))
+}
+
+/// Approve or reject the event held for moderation named by the
+/// "id" query parameter.  The database writer publishes approved
+/// events as it does any other.
+async fn moderate_pending(
+    request: &Request<Body>,
+    settings: &Settings,
+    moderation_tx: &mpsc::Sender<db::Moderation>,
+    approve: bool,
+) -> Response<Body> {
+    if request.method() != Method::POST {
+        return status_and_text(StatusCode::METHOD_NOT_ALLOWED, "Use POST");
+    }
+    if !admin_authorized(request, settings) {
+        return status_and_text(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+    let Some(id) = get_query_value(request, "id").filter(|id| id.len() == 64 && is_hex(id)) else {
+        return status_and_text(StatusCode::BAD_REQUEST, "Missing or invalid event id");
+    };
+    let (reply, reply_rx) = oneshot::channel();
+    let moderation = db::Moderation { id, approve, reply };
+    let result = match moderation_tx.send(moderation).await {
+        Ok(()) => reply_rx
+            .await
+            .unwrap_or_else(|_| Err(Error::CustomError("database writer stopped".to_owned()))),
+        Err(_) => Err(Error::CustomError("database writer stopped".to_owned())),
+    };
+    match result {
+        Ok(true) => status_and_text(StatusCode::OK, "ok"),
+        Ok(false) => status_and_text(StatusCode::NOT_FOUND, "No such event is held"),
+        Err(e) => {
+            warn!("could not moderate held event: {:?}", e);
+            status_and_text(StatusCode::INTERNAL_SERVER_ERROR, "Could not moderate held event")
+        }
+    }
+}
+
+/// List the newest invite codes.  Unspent codes let anyone join, so
+/// they are only shown to an administrator.
+async fn list_invites(
+    request: &Request<Body>,
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+) -> Response<Body> {
+    if !admin_authorized(request, settings) {
+        return status_and_text(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+    match repo.invites(100).await {
+        Ok(invites) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&invites).unwrap()))
+            .unwrap(),
+        Err(e) => {
+            warn!("could not list invite codes: {:?}", e);
+            status_and_text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not list invite codes",
+            )
+        }
+    }
+}
+
+/// Mint an invite code, which may be redeemed "uses" times (default
+/// once) until it "expires" (a duration, such as "7days").
+async fn mint_invite(
+    request: &Request<Body>,
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+) -> Response<Body> {
+    if !admin_authorized(request, settings) {
+        return status_and_text(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+    let uses = get_query_value(request, "uses").map_or(Some(1), |u| u.parse::<u64>().ok());
+    let Some(max_uses) = uses.filter(|u| *u > 0) else {
+        return status_and_text(StatusCode::BAD_REQUEST, "Invalid number of uses");
+    };
+    let expires_at = match get_query_value(request, "expires").map(|e| parse_duration::parse(&e)) {
+        None => None,
+        Some(Ok(d)) => Some(unix_time() + d.as_secs()),
+        Some(Err(_)) => return status_and_text(StatusCode::BAD_REQUEST, "Invalid expiration"),
+    };
+    let invite = Invite::new("admin", max_uses, expires_at);
+    match repo.create_invite(&invite).await {
+        Ok(()) => {
+            info!("admin minted an invite code ({} uses)", max_uses);
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&invite).unwrap()))
+                .unwrap()
+        }
+        Err(e) => {
+            warn!("could not store invite code: {:?}", e);
+            status_and_text(StatusCode::INTERNAL_SERVER_ERROR, "Could not mint invite code")
+        }
+    }
+}
+
+/// Read the fields of a form sent in a request body.  Returns None if
+/// the body is too large or could not be read.
+async fn form_fields(mut body: Body) -> Option<HashMap<String, String>> {
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.ok()?);
+        if bytes.len() > MAX_FORM_BYTES {
+            return None;
+        }
+    }
+    Some(url::form_urlencoded::parse(&bytes).into_owned().collect())
+}
+
 fn get_header_string(header: &str, headers: &HeaderMap) -> Option<String> {
     headers
         .get(header)
         .and_then(|x| x.to_str().ok().map(std::string::ToString::to_string))
 }
 
+/// Determine the client IP, from headers if they exist and were set
+/// by a trusted proxy, using the socket address as a backup.
+fn client_ip(headers: &HeaderMap, settings: &Settings, remote_addr: SocketAddr) -> IpAddr {
+    match &settings.network.remote_ip_header {
+        Some(header) => forwarded::client_ip(
+            headers,
+            header,
+            &settings.network.trusted_proxies,
+            remote_addr.ip(),
+        ),
+        None => remote_addr.ip(),
+    }
+}
+
+/// Ask the admission server if a websocket connection may proceed,
+/// returning the HTTP response to send if it may not.
+async fn connection_rejection(
+    request: &Request<Body>,
+    settings: &Settings,
+    remote_addr: SocketAddr,
+    authz: &nauthz::EventAuthzService,
+) -> Option<Response<Body>> {
+    let headers = request.headers();
+    let remote_ip = client_ip(headers, settings, remote_addr).to_string();
+    let header_pairs = headers
+        .iter()
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_owned())))
+        .collect();
+    let res = authz
+        .admit_connection(
+            &remote_ip,
+            get_header_string("origin", headers),
+            get_header_string("user-agent", headers),
+            header_pairs,
+        )
+        .await;
+    match res {
+        Ok(reply) => {
+            if reply.permitted() {
+                return None;
+            }
+            info!("connection denied by admission server (IP: {:?})", remote_ip);
+            let mut builder = Response::builder().header("Content-Type", "text/plain");
+            builder = match reply.retry_after_seconds {
+                Some(secs) => builder
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::RETRY_AFTER, secs),
+                None => builder.status(StatusCode::FORBIDDEN),
+            };
+            Some(
+                builder
+                    .body(Body::from(
+                        AuthzDecision::message(&reply)
+                            .filter(|m| !m.is_empty())
+                            .unwrap_or_else(|| "Connection not permitted".to_owned()),
+                    ))
+                    .unwrap(),
+            )
+        }
+        Err(e) => {
+            warn!("GRPC connection admission failed: {:?}", e);
+            if settings.grpc.fail_open {
+                None
+            } else {
+                Some(status_and_text(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Connection could not be authorized",
+                ))
+            }
+        }
+    }
+}
+
 // return on a control-c or internally requested shutdown signal
 async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     let mut term_signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
@@ -463,7 +819,7 @@ async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     }
 }
 
-fn create_metrics() -> (Registry, NostrMetrics) {
+pub(crate) fn create_metrics() -> (Registry, NostrMetrics) {
     // setup prometheus registry
     let registry = Registry::new();
 
@@ -511,6 +867,43 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         vec!["reason"].as_slice(),
     )
     .unwrap();
+    let dropped_events = IntCounterVec::new(
+        Opts::new(
+            "nostr_events_dropped_total",
+            "Live events not sent to clients",
+        ),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let rejected_connections = IntCounterVec::new(
+        Opts::new(
+            "nostr_connections_rejected_total",
+            "Websocket connections rejected by client address",
+        ),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let duplicates_rejected = IntCounter::with_opts(Opts::new(
+        "nostr_duplicates_rejected_total",
+        "Events rejected for content posted by too many authors",
+    ))
+    .unwrap();
+    let ws_raw_bytes = IntCounterVec::new(
+        Opts::new(
+            "nostr_ws_raw_bytes_total",
+            "Uncompressed size of compressed websocket messages",
+        ),
+        vec!["direction"].as_slice(),
+    )
+    .unwrap();
+    let ws_compressed_bytes = IntCounterVec::new(
+        Opts::new(
+            "nostr_ws_compressed_bytes_total",
+            "Compressed size of compressed websocket messages",
+        ),
+        vec!["direction"].as_slice(),
+    )
+    .unwrap();
     registry.register(Box::new(query_sub.clone())).unwrap();
     registry.register(Box::new(query_db.clone())).unwrap();
     registry.register(Box::new(write_events.clone())).unwrap();
@@ -523,6 +916,11 @@ fn create_metrics() -> (Registry, NostrMetrics) {
     registry.register(Box::new(cmd_close.clone())).unwrap();
     registry.register(Box::new(cmd_auth.clone())).unwrap();
     registry.register(Box::new(disconnects.clone())).unwrap();
+    registry.register(Box::new(dropped_events.clone())).unwrap();
+    registry.register(Box::new(rejected_connections.clone())).unwrap();
+    registry.register(Box::new(duplicates_rejected.clone())).unwrap();
+    registry.register(Box::new(ws_raw_bytes.clone())).unwrap();
+    registry.register(Box::new(ws_compressed_bytes.clone())).unwrap();
     let metrics = NostrMetrics {
         query_sub,
         query_db,
@@ -536,6 +934,11 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         cmd_event,
         cmd_close,
         cmd_auth,
+        dropped_events,
+        rejected_connections,
+        duplicates_rejected,
+        ws_raw_bytes,
+        ws_compressed_bytes,
     };
     (registry, metrics)
 }
@@ -557,12 +960,31 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         error!("Database directory does not exist");
         return Err(Error::DatabaseDirError);
     }
-    let addr = format!(
-        "{}:{}",
-        settings.network.address.trim(),
-        settings.network.port
-    );
-    let socket_addr = addr.parse().expect("listening address not valid");
+    let listeners = settings.network.all_listeners();
+    for listener in &listeners {
+        match (&listener.address, &listener.path) {
+            (Some(addr), None) => {
+                if addr.trim().parse::<SocketAddr>().is_err() {
+                    error!("listening address not valid: {:?}", addr);
+                    return Err(Error::CustomError(format!(
+                        "listening address not valid: {addr}"
+                    )));
+                }
+            }
+            (None, Some(_)) => {}
+            _ => {
+                error!("each listener needs either an address or a path");
+                return Err(Error::CustomError(
+                    "each listener needs either an address or a path".to_owned(),
+                ));
+            }
+        }
+    }
+    if settings.network.remote_ip_header.is_some() && settings.network.trusted_proxies.is_empty() {
+        warn!(
+            "remote_ip_header is believed from any client; set trusted_proxies to prevent spoofing"
+        );
+    }
     // address whitelisting settings
     if let Some(addr_whitelist) = &settings.authorization.pubkey_whitelist {
         info!(
@@ -615,7 +1037,6 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let persist_buffer_limit = settings.limits.event_persist_buffer;
         let verified_users_active = settings.verified_users.is_active();
         let settings = settings.clone();
-        info!("listening on: {}", socket_addr);
         // all client-submitted valid events are broadcast to every
         // other client on this channel.  This should be large enough
         // to accommodate slower readers (messages are dropped if
@@ -643,20 +1064,79 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
 
         // build a repository for events
         let repo = db::build_repo(&settings, metrics.clone()).await;
+        // event signatures are verified on a pool shared by every
+        // client.
+        let verifier = VerifyPool::new(&settings.limits);
+        // client addresses are checked, and their connections
+        // counted, before websocket upgrades.
+        let ip_access = IpAccess::new(&settings);
+        // content posted by many authors is counted across every
+        // admission worker.
+        let duplicates = DuplicateDetector::new(
+            &settings.duplicate_content,
+            metrics.duplicates_rejected.clone(),
+        );
+        // groups are shared by the database writer, which changes
+        // them, and clients, whose reads they restrict.
+        let groups = match GroupManager::new(&settings) {
+            Ok(g) => g,
+            Err(e) => {
+                error!("Failed to start groups {e}");
+                std::process::exit(1);
+            }
+        };
+        // invite admissions are cached for the database writer, and
+        // updated when codes are redeemed on the join page.
+        let admissions = invite::Admissions::default();
         // start the database writer task.  Give it a channel for
         // writing events, and for publishing events that have been
-        // written (to all connected clients).
-        tokio::task::spawn(db::db_writer(
-            repo.clone(),
-            settings.clone(),
-            event_rx,
-            bcast_tx.clone(),
-            metadata_tx.clone(),
-            payment_tx.clone(),
-            shutdown_listen,
-        ));
+        // written (to all connected clients).  Moderators' decisions
+        // on held events are sent to it as well.
+        let (moderation_tx, moderation_rx) = mpsc::channel::<db::Moderation>(16);
+        let writer_ctx = db::WriterContext {
+            repo: repo.clone(),
+            settings: settings.clone(),
+            bcast_tx: bcast_tx.clone(),
+            metadata_tx: metadata_tx.clone(),
+            payment_tx: payment_tx.clone(),
+            verifier: verifier.clone(),
+            duplicates: duplicates.clone(),
+            groups: groups.clone(),
+            admissions: admissions.clone(),
+        };
+        tokio::task::spawn(db::db_writer(writer_ctx, event_rx, moderation_rx, shutdown_listen));
         info!("db writer created");
 
+        // stream events to GRPC consumers, if configured.
+        if let Some(ref listen) = settings.grpc.listen_address {
+            match listen.parse::<SocketAddr>() {
+                Ok(addr) => {
+                    tokio::task::spawn(nstream::serve(
+                        addr,
+                        repo.clone(),
+                        bcast_tx.clone(),
+                        invoke_shutdown.subscribe(),
+                    ));
+                }
+                Err(e) => {
+                    error!("invalid GRPC listen address {:?}: {}", listen, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // connections and subscriptions are authorized through a
+        // GRPC connection shared by every client, if configured.
+        // Every client's copy shares the connection, its reconnect
+        // holdoff and the decision cache.
+        let authz = if settings.grpc.event_admission_server.is_some()
+            && (settings.grpc.req_admission || settings.grpc.connection_admission)
+        {
+            Some(nauthz::EventAuthzService::connect(&settings.grpc).await)
+        } else {
+            None
+        };
+
         // create a nip-05 verifier thread; if enabled.
         if settings.verified_users.mode != VerifiedUsersMode::Disabled {
             let verifier_opt = nip05::Verifier::new(
@@ -714,9 +1194,6 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         });
         // listen for ctrl-c interruupts
         let ctrl_c_shutdown = invoke_shutdown.clone();
-        // listener for webserver shutdown
-        let webserver_shutdown_listen = invoke_shutdown.subscribe();
-
         tokio::spawn(async move {
             tokio::signal::ctrl_c().await.unwrap();
             info!("shutting down due to SIGINT (main)");
@@ -740,51 +1217,160 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let tera = Arc::new(_tera);
         let static_ = Static::new(Path::new("./public"));
 
-        // A `Service` is needed for every connection, so this
-        // creates one from our `handle_request` function.
-        let make_svc = make_service_fn(|conn: &AddrStream| {
+        // Every listener handles requests with our
+        // `handle_web_request` function.
+        let handler = {
             let repo = repo.clone();
-            let remote_addr = conn.remote_addr();
             let bcast = bcast_tx.clone();
             let event = event_tx.clone();
             let payment_tx = payment_tx.clone();
             let stop = invoke_shutdown.clone();
             let settings = settings.clone();
-            let registry = registry.clone();
-            let metrics = metrics.clone();
-            let tera = tera.clone();
-            let static_ = static_.clone();
-            async move {
-                // service_fn converts our function into a `Service`
-                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
-                    handle_web_request(
-                        request,
-                        repo.clone(),
-                        settings.clone(),
-                        remote_addr,
-                        bcast.clone(),
-                        event.clone(),
-                        payment_tx.clone(),
-                        stop.subscribe(),
-                        registry.clone(),
-                        metrics.clone(),
-                        tera.clone(),
-                        static_.clone(),
-                    )
-                }))
+            move |request: Request<Body>, remote_addr: SocketAddr, role: ListenerRole| {
+                handle_web_request(
+                    request,
+                    repo.clone(),
+                    settings.clone(),
+                    remote_addr,
+                    role,
+                    bcast.clone(),
+                    event.clone(),
+                    payment_tx.clone(),
+                    stop.subscribe(),
+                    registry.clone(),
+                    metrics.clone(),
+                    tera.clone(),
+                    static_.clone(),
+                    authz.clone(),
+                    verifier.clone(),
+                    ip_access.clone(),
+                    duplicates.clone(),
+                    groups.clone(),
+                    moderation_tx.clone(),
+                    admissions.clone(),
+                )
             }
-        });
-        let server = Server::bind(&socket_addr)
-            .serve(make_svc)
-            .with_graceful_shutdown(ctrl_c_or_signal(webserver_shutdown_listen));
+        };
+        let mut servers: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = vec![];
+        let mut socket_paths = vec![];
+        for listener in listeners {
+            let role = listener.role;
+            let handler = handler.clone();
+            let shutdown = ctrl_c_or_signal(invoke_shutdown.subscribe());
+            let socket_listener = if let Some(path) = listener.path {
+                let unix_listener = match bind_unix_socket(&path, listener.socket_mode) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        error!("could not listen on {:?}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                };
+                info!("listening on: {} ({:?})", path, role);
+                socket_paths.push(path);
+                SocketListener::Unix(unix_listener)
+            } else if let Some(addr) = listener.address {
+                // checked when the server started
+                let socket_addr: SocketAddr = addr.trim().parse().unwrap();
+                let tcp_listener = match TcpListener::bind(&socket_addr).await {
+                    Ok(l) => l,
+                    Err(e) => {
+                        error!("could not listen on {}: {}", socket_addr, e);
+                        std::process::exit(1);
+                    }
+                };
+                info!("listening on: {} ({:?})", socket_addr, role);
+                SocketListener::Tcp(tcp_listener)
+            } else {
+                continue;
+            };
+            let mut incoming = accept_connections(
+                socket_listener,
+                listener.proxy_protocol,
+                settings.network.proxy_protocol_trusted.clone(),
+            );
+            let accept = accept::poll_fn(move |cx| incoming.poll_recv(cx));
+            // A `Service` is needed for every connection.
+            let make_svc = make_service_fn(move |conn: &ClientStream| {
+                let handler = handler.clone();
+                let remote_addr = conn.remote_addr();
+                async move {
+                    // service_fn converts our function into a `Service`
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        handler(request, remote_addr, role)
+                    }))
+                }
+            });
+            let server = Server::builder(accept)
+                .serve(make_svc)
+                .with_graceful_shutdown(shutdown);
+            servers.push(Box::pin(async move {
+                if let Err(e) = server.await {
+                    eprintln!("server error: {e}");
+                }
+            }));
+        }
         // run hyper in this thread.  This is why the thread does not return.
-        if let Err(e) = server.await {
-            eprintln!("server error: {e}");
+        join_all(servers).await;
+        for path in socket_paths {
+            std::fs::remove_file(path).ok();
         }
     });
     Ok(())
 }
 
+/// Listen on a Unix domain socket, replacing any stale socket left at
+/// the path, and setting its permissions.
+fn bind_unix_socket(path: &str, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Apply the lag policy to subscriptions that missed live events.
+/// Returns false if the client should be disconnected.
+fn handle_lag(
+    policy: LagPolicy,
+    outbound: &Outbound,
+    conn: &mut conn::ClientConn,
+    subs: &[String],
+    lag_noticed: &mut bool,
+) -> bool {
+    match policy {
+        LagPolicy::DropOldest => true,
+        LagPolicy::Notice => {
+            if !*lag_noticed {
+                *lag_noticed = true;
+                outbound.push(make_notice_message(&Notice::message(
+                    "some events were not delivered because the connection is too slow".into(),
+                )));
+            }
+            true
+        }
+        LagPolicy::Close => {
+            for id in subs {
+                conn.unsubscribe(&Close { id: id.clone() });
+                outbound.push(Message::text(
+                    json!([
+                        "CLOSED",
+                        id,
+                        "error: events were missed because the connection is too slow"
+                    ])
+                    .to_string(),
+                ));
+            }
+            true
+        }
+        LagPolicy::Disconnect => false,
+    }
+}
+
 /// Nostr protocol messages from a client
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
 #[serde(untagged)]
@@ -831,17 +1417,30 @@ fn make_notice_message(notice: &Notice) -> Message {
         Notice::Message(ref msg) => json!(["NOTICE", msg]),
         Notice::EventResult(ref res) => json!(["OK", res.id, res.status.to_bool(), res.msg]),
         Notice::AuthChallenge(ref challenge) => json!(["AUTH", challenge]),
+        // the client is told the event was saved
+        Notice::ShadowRejected(ref event) => json!(["OK", event.id, true, ""]),
     };
 
     Message::text(json.to_string())
 }
 
-fn allowed_to_send(event_str: &str, conn: &conn::ClientConn, settings: &Settings) -> bool {
+fn allowed_to_send(
+    event_str: &str,
+    conn: &conn::ClientConn,
+    settings: &Settings,
+    groups: &GroupManager,
+) -> bool {
     // TODO: pass in kind so that we can avoid deserialization for most events
-    if settings.authorization.nip42_dms {
+    // only events with an h tag can belong to a group.
+    let grouped = groups.is_enabled() && event_str.contains("[\"h\",");
+    if settings.authorization.nip42_dms || grouped {
         match serde_json::from_str::<Event>(event_str) {
             Ok(event) => {
-                if event.kind == 4 || event.kind == 44 || event.kind == 1059 {
+                if grouped && !groups.can_read(&event, conn.auth_pubkey().map(String::as_str)) {
+                    false
+                } else if settings.authorization.nip42_dms
+                    && (event.kind == 4 || event.kind == 44 || event.kind == 1059)
+                {
                     match (conn.auth_pubkey(), event.tag_values_by_name("p").first()) {
                         (Some(auth_pubkey), Some(recipient_pubkey)) => {
                             recipient_pubkey == auth_pubkey || &event.pubkey == auth_pubkey
@@ -859,10 +1458,52 @@ fn allowed_to_send(event_str: &str, conn: &conn::ClientConn, settings: &Settings
     }
 }
 
+/// Check an event against the NIP-13 proof-of-work requirement for
+/// its kind, returning a message describing any shortfall.
+async fn pow_rejection(
+    event: &Event,
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+) -> Option<String> {
+    let required = settings.pow.required_difficulty(event.kind)?;
+    let difficulty = event.pow_difficulty();
+    // a committed target below the requirement means the work was not
+    // done for this relay, even if the id happens to be lucky.
+    let msg = match event.pow_target() {
+        Some(target) if target < required => {
+            format!("committed target difficulty {target} is less than {required}")
+        }
+        _ if difficulty < required => format!("difficulty {difficulty} is less than {required}"),
+        _ => return None,
+    };
+    // exemptions are only looked up once an event falls short
+    if settings.pow.exempt_whitelist {
+        if let Some(ref whitelist) = settings.authorization.pubkey_whitelist {
+            if whitelist.contains(&event.pubkey) {
+                return None;
+            }
+        }
+    }
+    if settings.pow.exempt_paid && settings.pay_to_relay.enabled {
+        if let Ok(key) = Keys::from_pk_str(&event.pubkey) {
+            if let Ok((true, _)) = repo.get_account_balance(&key).await {
+                return None;
+            }
+        }
+    }
+    Some(msg)
+}
+
+/// Events a client may have waiting for verification before its
+/// messages stop being read.
+const MAX_VERIFYING: usize = 32;
+
 struct ClientInfo {
     remote_ip: String,
     user_agent: Option<String>,
     origin: Option<String>,
+    /// Whether permessage-deflate was negotiated
+    compression: bool,
 }
 
 /// Handle new client connections.  This runs through an event loop
@@ -872,16 +1513,33 @@ async fn nostr_server(
     repo: Arc<dyn NostrRepo>,
     client_info: ClientInfo,
     settings: Settings,
-    mut ws_stream: WebSocketStream<Upgraded>,
+    ws_stream: WebSocketStream<DeflateStream<Upgraded>>,
     broadcast: Sender<Event>,
     event_tx: mpsc::Sender<SubmittedEvent>,
     mut shutdown: Receiver<()>,
     metrics: NostrMetrics,
+    authz: Option<nauthz::EventAuthzService>,
+    verifier: VerifyPool,
+    groups: GroupManager,
 ) {
     // the time this websocket nostr server started
     let orig_start = Instant::now();
     // get a broadcast channel for clients to communicate on
     let mut bcast_rx = broadcast.subscribe();
+    // messages are written to the client from a separate task, so
+    // that a slow client does not hold up this one.
+    let (ws_sink, mut ws_stream) = ws_stream.split();
+    let deflater = client_info.compression.then(|| {
+        MessageDeflater::new(
+            &settings.network,
+            metrics.ws_raw_bytes.clone(),
+            metrics.ws_compressed_bytes.clone(),
+        )
+    });
+    let outbound = Outbound::new(ws_sink, settings.limits.outbound_buffer_bytes, deflater);
+    let lag_policy = settings.limits.lag_policy;
+    // only one NOTICE is sent for each run of missed events
+    let mut lag_noticed = false;
     // Track internal client state
     let mut conn = conn::ClientConn::new(client_info.remote_ip);
     // subscription creation rate limiting
@@ -927,6 +1585,8 @@ async fn nostr_server(
     // and how many it received from queries.
     let mut client_published_event_count: usize = 0;
     let mut client_received_event_count: usize = 0;
+    // events waiting for their signatures to be verified
+    let mut verifying = FuturesOrdered::new();
 
     let unspec = "<unspecified>".to_string();
     info!("new client connection (cid: {}, ip: {:?})", cid, conn.ip());
@@ -943,16 +1603,19 @@ async fn nostr_server(
     if settings.authorization.nip42_auth {
         conn.generate_auth_challenge();
         if let Some(challenge) = conn.auth_challenge() {
-            ws_stream
-                .send(make_notice_message(&Notice::AuthChallenge(
-                    challenge.to_string(),
-                )))
-                .await
-                .ok();
+            outbound.push(make_notice_message(&Notice::AuthChallenge(
+                challenge.to_string(),
+            )));
         }
     }
 
     loop {
+        if outbound.overflowed() {
+            // replies were discarded; the client has stopped reading.
+            info!("client stopped reading replies (cid: {})", cid);
+            metrics.disconnects.with_label_values(&["slow"]).inc();
+            break;
+        }
         tokio::select! {
             _ = shutdown.recv() => {
                 metrics.disconnects.with_label_values(&["shutdown"]).inc();
@@ -969,29 +1632,64 @@ async fn nostr_server(
                     break;
                 }
                 // Send a ping
-                ws_stream.send(Message::Ping(Vec::new())).await.ok();
+                outbound.push(Message::Ping(Vec::new()));
             },
             Some(notice_msg) = notice_rx.recv() => {
-                ws_stream.send(make_notice_message(&notice_msg)).await.ok();
+                outbound.push(make_notice_message(&notice_msg));
+                if let Notice::ShadowRejected(ref event) = notice_msg {
+                    // only the author sees the event, as if it had
+                    // been published.
+                    if let Ok(event_str) = serde_json::to_string(event) {
+                        for (s, sub) in conn.subscriptions() {
+                            if sub.interested_in_event(event) && allowed_to_send(&event_str, &conn, &settings, &groups) {
+                                let subesc = s.replace('"', "");
+                                outbound.push(Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]")));
+                            }
+                        }
+                    }
+                }
             },
-            Some(query_result) = query_rx.recv() => {
+            // stop taking query results while the client catches up,
+            // so the database query waits.
+            _ = outbound.drained(), if outbound.is_full() => {},
+            Some(query_result) = query_rx.recv(), if !outbound.is_full() => {
                 // database informed us of a query result we asked for
                 let subesc = query_result.sub_id.replace('"', "");
                 if query_result.event == "EOSE" {
                     let send_str = format!("[\"EOSE\",\"{subesc}\"]");
-                    ws_stream.send(Message::Text(send_str)).await.ok();
-                } else if allowed_to_send(&query_result.event, &conn, &settings) {
+                    outbound.push(Message::Text(send_str));
+                } else if allowed_to_send(&query_result.event, &conn, &settings, &groups) {
                     metrics.sent_events.with_label_values(&["db"]).inc();
                     client_received_event_count += 1;
                     // send a result
                     let send_str = format!("[\"EVENT\",\"{}\",{}]", subesc, &query_result.event);
-                    ws_stream.send(Message::Text(send_str)).await.ok();
+                    outbound.push(Message::Text(send_str));
                 }
             },
-            // TODO: consider logging the LaggedRecv error
-            Ok(global_event) = bcast_rx.recv() => {
+            bcast_next = bcast_rx.recv() => {
+                let global_event = match bcast_next {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        // this client fell behind the broadcast buffer
+                        info!("client missed {} broadcast events (cid: {})", n, cid);
+                        metrics.dropped_events.with_label_values(&["lagged"]).inc_by(n);
+                        let subs: Vec<String> = conn.subscriptions().keys().cloned().collect();
+                        if !handle_lag(lag_policy, &outbound, &mut conn, &subs, &mut lag_noticed) {
+                            metrics.disconnects.with_label_values(&["lagged"]).inc();
+                            break;
+                        }
+                        continue;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => {
+                        // the relay is going away; nothing more will
+                        // be broadcast.
+                        metrics.disconnects.with_label_values(&["shutdown"]).inc();
+                        break;
+                    },
+                };
                 // an event has been broadcast to all clients
                 // first check if there is a subscription for this event.
+                let mut lagged_subs = vec![];
                 for (s, sub) in conn.subscriptions() {
                     if !sub.interested_in_event(&global_event) {
                         continue;
@@ -999,21 +1697,125 @@ async fn nostr_server(
                     // TODO: serialize at broadcast time, instead of
                     // once for each consumer.
                     if let Ok(event_str) = serde_json::to_string(&global_event) {
-                        if allowed_to_send(&event_str, &conn, &settings) {
+                        if allowed_to_send(&event_str, &conn, &settings, &groups) {
                             // create an event response and send it
                             trace!("sub match for client: {}, sub: {:?}, event: {:?}",
                                cid, s,
                                global_event.get_event_id_prefix());
                             let subesc = s.replace('"', "");
+                            let msg = Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"));
+                            if lag_policy == LagPolicy::DropOldest {
+                                let dropped = outbound.push_live_dropping_oldest(msg);
+                                if dropped > 0 {
+                                    metrics.dropped_events.with_label_values(&["queue_full"]).inc_by(dropped);
+                                }
+                            } else if outbound.push_live(msg) {
+                                lag_noticed = false;
+                            } else {
+                                metrics.dropped_events.with_label_values(&["queue_full"]).inc();
+                                lagged_subs.push(s.clone());
+                                continue;
+                            }
                             metrics.sent_events.with_label_values(&["realtime"]).inc();
-                            ws_stream.send(Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"))).await.ok();
                         }
                     } else {
                         warn!("could not serialize event: {:?}", global_event.get_event_id_prefix());
                     }
                 }
+                if !lagged_subs.is_empty() {
+                    debug!("client is too slow for live events (cid: {})", cid);
+                    if !handle_lag(lag_policy, &outbound, &mut conn, &lagged_subs, &mut lag_noticed) {
+                        metrics.disconnects.with_label_values(&["slow"]).inc();
+                        break;
+                    }
+                }
+            },
+            // events are handled in the order they were sent, once
+            // verified.
+            Some((evid, parsed)) = verifying.next(), if !verifying.is_empty() => {
+                match parsed {
+                    Ok(WrappedEvent(e)) => {
+                        metrics.cmd_event.inc();
+                        let id_prefix:String = e.id.chars().take(8).collect();
+                        debug!("successfully parsed/validated event: {:?} (cid: {}, kind: {})", id_prefix, cid, e.kind);
+                        let reject_past_seconds = settings.reject_past_seconds(e.kind);
+                        // check if event is expired
+                        if e.is_expired() {
+                            let notice = Notice::invalid(e.id, "The event has already expired");
+                            outbound.push(make_notice_message(&notice));
+                            // check if the event is too far in the future or past.
+                        } else if !e.is_valid_timestamp(settings.options.reject_future_seconds, reject_past_seconds) {
+                            let range = if e.created_at > unix_time() {
+                                info!("client: {} sent a far future-dated event", cid);
+                                format!("+{}", settings.options.reject_future_seconds.unwrap_or_default())
+                            } else {
+                                info!("client: {} sent a far past-dated event", cid);
+                                format!("-{}", reject_past_seconds.unwrap_or_default())
+                            };
+                            let msg = format!("The event created_at field is out of the acceptable range ({range}sec) for this relay.");
+                            let notice = Notice::invalid(e.id, &msg);
+                            outbound.push(make_notice_message(&notice));
+                            // check the event against limits for its kind.
+                        } else if let Some(msg) = settings.limits.for_kind(e.kind).and_then(|l| e.limit_violation(l)) {
+                            info!("client: {} sent an event exceeding kind limits: {}", cid, msg);
+                            outbound.push(make_notice_message(&Notice::invalid(e.id, &msg)));
+                            // check if the event carries enough proof-of-work.
+                        } else if let Some(msg) = pow_rejection(&e, &settings, &repo).await {
+                            info!("client: {} sent an event with insufficient proof-of-work", cid);
+                            outbound.push(make_notice_message(&Notice::pow(e.id, &msg)));
+                        } else {
+                            // Write this to the database.
+                            let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(pubkey).ok());
+                            let submit_event = SubmittedEvent {
+                                event: e.clone(),
+                                notice_tx: notice_tx.clone(),
+                                source_ip: conn.ip().to_string(),
+                                origin: client_info.origin.clone(),
+                                user_agent: client_info.user_agent.clone(),
+                                auth_pubkey };
+                            event_tx.send(submit_event).await.ok();
+                            client_published_event_count += 1;
+                        }
+                    },
+                    Ok(WrappedAuth(event)) => {
+                        metrics.cmd_auth.inc();
+                        if settings.authorization.nip42_auth {
+                            let id_prefix:String = event.id.chars().take(8).collect();
+                            debug!("successfully parsed auth: {:?} (cid: {})", id_prefix, cid);
+                            match &settings.info.relay_url {
+                                None => {
+                                    error!("AUTH command received, but relay_url is not set in the config file (cid: {})", cid);
+                                },
+                                Some(relay) => {
+                                    match conn.authenticate(&event, relay) {
+                                        Ok(_) => {
+                                            let pubkey = match conn.auth_pubkey() {
+                                                Some(k) => k.chars().take(8).collect(),
+                                                None => "<unspecified>".to_string(),
+                                            };
+                                            info!("client is authenticated: (cid: {}, pubkey: {:?})", cid, pubkey);
+                                        },
+                                        Err(e) => {
+                                            info!("authentication error: {} (cid: {})", e, cid);
+                                            outbound.push(make_notice_message(&Notice::restricted(event.id, format!("authentication error: {e}").as_str())));
+                                        },
+                                    }
+                                }
+                            }
+                        } else {
+                            let e = CommandUnknownError;
+                            info!("client sent an invalid event (cid: {})", cid);
+                            outbound.push(make_notice_message(&Notice::invalid(evid, &format!("{e}"))));
+                        }
+                    },
+                    Err(e) => {
+                        metrics.cmd_event.inc();
+                        info!("client sent an invalid event (cid: {})", cid);
+                        outbound.push(make_notice_message(&Notice::invalid(evid, &format!("{e}"))));
+                    }
+                }
             },
-            ws_next = ws_stream.next() => {
+            ws_next = ws_stream.next(), if verifying.len() < MAX_VERIFYING => {
                 // update most recent message time for client
                 last_message_time = Instant::now();
                 // Consume text messages from the client, parse into Nostr messages.
@@ -1022,8 +1824,7 @@ async fn nostr_server(
                         convert_to_msg(&m,settings.limits.max_event_bytes)
                     },
                     Some(Ok(Message::Binary(_))) => {
-                        ws_stream.send(
-                            make_notice_message(&Notice::message("binary messages are not accepted".into()))).await.ok();
+                        outbound.push(make_notice_message(&Notice::message("binary messages are not accepted".into())));
                         continue;
                     },
                     Some(Ok(Message::Ping(_) | Message::Pong(_))) => {
@@ -1032,8 +1833,7 @@ async fn nostr_server(
                         continue;
                     },
                     Some(Err(WsError::Capacity(MessageTooLong{size, max_size}))) => {
-                        ws_stream.send(
-                            make_notice_message(&Notice::message(format!("message too large ({size} > {max_size})")))).await.ok();
+                        outbound.push(make_notice_message(&Notice::message(format!("message too large ({size} > {max_size})"))));
                         continue;
                     },
                     None |
@@ -1067,77 +1867,22 @@ async fn nostr_server(
                         // An EventCmd needs to be validated to be converted into an Event
                         // handle each type of message
                         let evid = ec.event_id().to_owned();
-                        let parsed : Result<EventWrapper> = Result::<EventWrapper>::from(ec);
-                        match parsed {
-                            Ok(WrappedEvent(e)) => {
-                                metrics.cmd_event.inc();
-                                let id_prefix:String = e.id.chars().take(8).collect();
-                                debug!("successfully parsed/validated event: {:?} (cid: {}, kind: {})", id_prefix, cid, e.kind);
-                                // check if event is expired
-                                if e.is_expired() {
-                                    let notice = Notice::invalid(e.id, "The event has already expired");
-                                    ws_stream.send(make_notice_message(&notice)).await.ok();
-                                    // check if the event is too far in the future.
-                                } else if e.is_valid_timestamp(settings.options.reject_future_seconds) {
-                                    // Write this to the database.
-                                    let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(pubkey).ok());
-                                    let submit_event = SubmittedEvent {
-                                        event: e.clone(),
-                                        notice_tx: notice_tx.clone(),
-                                        source_ip: conn.ip().to_string(),
-                                        origin: client_info.origin.clone(),
-                                        user_agent: client_info.user_agent.clone(),
-                                        auth_pubkey };
-                                    event_tx.send(submit_event).await.ok();
-                                    client_published_event_count += 1;
-                                } else {
-                                    info!("client: {} sent a far future-dated event", cid);
-                                    if let Some(fut_sec) = settings.options.reject_future_seconds {
-                                        let msg = format!("The event created_at field is out of the acceptable range (+{fut_sec}sec) for this relay.");
-                                        let notice = Notice::invalid(e.id, &msg);
-                                        ws_stream.send(make_notice_message(&notice)).await.ok();
-                                    }
-                                }
-                            },
-                            Ok(WrappedAuth(event)) => {
-                                metrics.cmd_auth.inc();
-                                if settings.authorization.nip42_auth {
-                                    let id_prefix:String = event.id.chars().take(8).collect();
-                                    debug!("successfully parsed auth: {:?} (cid: {})", id_prefix, cid);
-                                    match &settings.info.relay_url {
-                                        None => {
-                                            error!("AUTH command received, but relay_url is not set in the config file (cid: {})", cid);
-                                        },
-                                        Some(relay) => {
-                                            match conn.authenticate(&event, relay) {
-                                                Ok(_) => {
-                                                    let pubkey = match conn.auth_pubkey() {
-                                                        Some(k) => k.chars().take(8).collect(),
-                                                        None => "<unspecified>".to_string(),
-                                                    };
-                                                    info!("client is authenticated: (cid: {}, pubkey: {:?})", cid, pubkey);
-                                                },
-                                                Err(e) => {
-                                                    info!("authentication error: {} (cid: {})", e, cid);
-                                                    ws_stream.send(make_notice_message(&Notice::restricted(event.id, format!("authentication error: {e}").as_str()))).await.ok();
-                                                },
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    let e = CommandUnknownError;
-                                    info!("client sent an invalid event (cid: {})", cid);
-                                    ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
-                                }
-                            },
-                            Err(e) => {
-                                metrics.cmd_event.inc();
-                                info!("client sent an invalid event (cid: {})", cid);
-                                ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
-                            }
+                        // recently stored events need not be verified again
+                        if ec.cmd() == "EVENT" && verifier.recently_stored(&evid) {
+                            metrics.cmd_event.inc();
+                            debug!("client sent a recently stored event (cid: {})", cid);
+                            outbound.push(make_notice_message(&Notice::duplicate(evid)));
+                            continue;
                         }
+                        // signatures are verified off this task, so the
+                        // client is still served while they are checked.
+                        let verifier = verifier.clone();
+                        verifying.push_back(async move {
+                            let parsed: Result<EventWrapper> = verifier.parse(ec).await;
+                            (evid, parsed)
+                        });
                     },
-                    Ok(NostrMessage::SubMsg(s)) => {
+                    Ok(NostrMessage::SubMsg(mut s)) => {
                         debug!("subscription requested (cid: {}, sub: {:?})", cid, s.id);
                         // subscription handling consists of:
                         // * check for rate limits
@@ -1154,9 +1899,36 @@ async fn nostr_server(
                             }
                             if settings.limits.limit_scrapers && s.is_scraper() {
                                 info!("subscription was scraper, ignoring (cid: {}, sub: {:?})", cid, s.id);
-                                ws_stream.send(Message::Text(format!("[\"EOSE\",\"{}\"]", s.id))).await.ok();
+                                outbound.push(Message::Text(format!("[\"EOSE\",\"{}\"]", s.id)));
                                 continue
                             }
+                            if let Some(authz) = authz.as_ref().filter(|_| settings.grpc.req_admission) {
+                                let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(pubkey).ok());
+                                match authz.admit_req(&s, conn.ip(), client_info.origin.clone(), client_info.user_agent.clone(), auth_pubkey).await {
+                                    Ok(reply) => {
+                                        if !reply.permitted() {
+                                            info!("subscription denied by admission server (cid: {}, sub: {:?})", cid, s.id);
+                                            let reason = AuthzDecision::message(&reply)
+                                                .filter(|m| !m.is_empty())
+                                                .unwrap_or_else(|| "subscription not permitted".to_owned());
+                                            let msg = format!("blocked: {reason}");
+                                            outbound.push(Message::text(json!(["CLOSED", s.id, msg]).to_string()));
+                                            continue
+                                        }
+                                        if let Some(filters) = reply.rewritten_filters() {
+                                            debug!("subscription narrowed by admission server (cid: {}, sub: {:?})", cid, s.id);
+                                            s.filters = filters;
+                                        }
+                                    },
+                                    Err(e) => {
+                                        warn!("GRPC subscription admission failed: {:?}", e);
+                                        if !settings.grpc.fail_open {
+                                            outbound.push(Message::text(json!(["CLOSED", s.id, "error: relay could not reach its authorization server"]).to_string()));
+                                            continue
+                                        }
+                                    }
+                                }
+                            }
                             let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
                             match conn.subscribe(s.clone()) {
                                 Ok(()) => {
@@ -1171,7 +1943,7 @@ async fn nostr_server(
                                 },
                                 Err(e) => {
                                     info!("Subscription error: {} (cid: {}, sub: {:?})", e, cid, s.id);
-                                    ws_stream.send(make_notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
+                                    outbound.push(make_notice_message(&Notice::message(format!("Subscription error: {e}"))));
                                 }
                             }
                         }
@@ -1192,7 +1964,7 @@ async fn nostr_server(
                             conn.unsubscribe(&c);
                         } else {
                             info!("invalid command ignored");
-                            ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                            outbound.push(make_notice_message(&Notice::message("could not parse command".into())));
                         }
                     },
                     Err(Error::ConnError) => {
@@ -1201,11 +1973,11 @@ async fn nostr_server(
                     }
                     Err(Error::EventMaxLengthError(s)) => {
                         info!("client sent command larger ({} bytes) than max size (cid: {})", s, cid);
-                        ws_stream.send(make_notice_message(&Notice::message("event exceeded max size".into()))).await.ok();
+                        outbound.push(make_notice_message(&Notice::message("event exceeded max size".into())));
                     },
                     Err(Error::ProtoParseError) => {
                         info!("client sent command that could not be parsed (cid: {})", cid);
-                        ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                        outbound.push(make_notice_message(&Notice::message("could not parse command".into())));
                     },
                     Err(e) => {
                         info!("got non-fatal error from client (cid: {}, error: {:?}", cid, e);
@@ -1242,4 +2014,206 @@ pub struct NostrMetrics {
     pub cmd_event: IntCounter,       // count of EVENT commands received
     pub cmd_close: IntCounter,       // count of CLOSE commands received
     pub cmd_auth: IntCounter,        // count of AUTH commands received
+    pub dropped_events: IntCounterVec, // live events not sent to slow clients
+    pub rejected_connections: IntCounterVec, // websocket connections refused by client address
+    pub duplicates_rejected: IntCounter, // events with content posted by too many authors
+    pub ws_raw_bytes: IntCounterVec, // size of compressed websocket messages, before compression
+    pub ws_compressed_bytes: IntCounterVec, // size of compressed websocket messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "a-long-random-admin-token";
+    const ID: &str = "6b7c9a3e2f1d0c8b7a6958473625140f3e2d1c0b9a8978675645342312010f0e";
+
+    fn admin_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.authorization.admin_token = Some(TOKEN.to_owned());
+        settings
+    }
+
+    fn admin_request(method: Method, uri: &str, token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {token}"));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    /// A database writer that holds only the event `ID`.
+    fn moderation_channel() -> mpsc::Sender<db::Moderation> {
+        let (moderation_tx, mut moderation_rx) = mpsc::channel::<db::Moderation>(1);
+        tokio::spawn(async move {
+            while let Some(moderation) = moderation_rx.recv().await {
+                moderation.reply.send(Ok(moderation.id == ID)).ok();
+            }
+        });
+        moderation_tx
+    }
+
+    #[test]
+    fn admin_token_required() {
+        let uri = "/admin/invites";
+        let settings = admin_settings();
+        assert!(admin_authorized(&admin_request(Method::POST, uri, Some(TOKEN)), &settings));
+        assert!(!admin_authorized(&admin_request(Method::POST, uri, Some("wrong")), &settings));
+        assert!(!admin_authorized(&admin_request(Method::POST, uri, None), &settings));
+        // no admin requests are allowed without a token configured
+        assert!(!admin_authorized(
+            &admin_request(Method::POST, uri, Some(TOKEN)),
+            &Settings::default()
+        ));
+        let mut empty = Settings::default();
+        empty.authorization.admin_token = Some(String::new());
+        assert!(!admin_authorized(&admin_request(Method::POST, uri, Some("")), &empty));
+    }
+
+    #[tokio::test]
+    async fn moderate_pending_checks_requests() {
+        let settings = admin_settings();
+        let moderation_tx = moderation_channel();
+        let approve = format!("/admin/quarantine/approve?id={ID}");
+        let status = |res: Response<Body>| res.status();
+        // only authorized POSTs are accepted
+        let res = moderate_pending(
+            &admin_request(Method::GET, &approve, Some(TOKEN)),
+            &settings,
+            &moderation_tx,
+            true,
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::METHOD_NOT_ALLOWED);
+        let res = moderate_pending(
+            &admin_request(Method::POST, &approve, None),
+            &settings,
+            &moderation_tx,
+            true,
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::UNAUTHORIZED);
+        let res = moderate_pending(
+            &admin_request(Method::POST, "/admin/quarantine/approve?id=123", Some(TOKEN)),
+            &settings,
+            &moderation_tx,
+            true,
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::BAD_REQUEST);
+        // the writer's answer is passed on
+        let res = moderate_pending(
+            &admin_request(Method::POST, &approve, Some(TOKEN)),
+            &settings,
+            &moderation_tx,
+            true,
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::OK);
+        let other = format!("/admin/quarantine/reject?id={}", "0".repeat(64));
+        let res = moderate_pending(
+            &admin_request(Method::POST, &other, Some(TOKEN)),
+            &settings,
+            &moderation_tx,
+            false,
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn connection_admission_holdoff() {
+        let mut settings = Settings::default();
+        // nothing listens on the discard port
+        settings.grpc.event_admission_server = Some("http://127.0.0.1:9".to_owned());
+        settings.grpc.connection_admission = true;
+        settings.grpc.fail_open = false;
+        settings.grpc.connect_retries = 2;
+        settings.grpc.retry_backoff_ms = 50;
+        let authz = nauthz::EventAuthzService::connect(&settings.grpc).await;
+        // wait out the holdoff from connecting
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // each connection gets its own copy of the service
+        let (first, second) = (authz.clone(), authz.clone());
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let remote_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let start = Instant::now();
+        let res = connection_rejection(&request, &settings, remote_addr, &first).await;
+        assert_eq!(res.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        // retried with backoff
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        // the next connection is refused without waiting
+        let start = Instant::now();
+        let res = connection_rejection(&request, &settings, remote_addr, &second).await;
+        assert_eq!(res.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn mint_invite_checks_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = admin_settings();
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let repo = db::build_repo(&settings, create_metrics().1).await;
+        let mint = |uri: &str, token| admin_request(Method::POST, uri, token);
+        let res = mint_invite(&mint("/admin/invites", None), &settings, &repo).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = mint_invite(&mint("/admin/invites?uses=0", Some(TOKEN)), &settings, &repo).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = mint_invite(&mint("/admin/invites?uses=3", Some(TOKEN)), &settings, &repo).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let invites = repo.invites(10).await.unwrap();
+        assert_eq!(invites.len(), 1);
+        assert_eq!(invites[0].max_uses, 3);
+    }
+
+    #[tokio::test]
+    async fn list_invites_needs_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = admin_settings();
+        settings.database.data_directory = dir.path().to_str().unwrap().to_owned();
+        let repo = db::build_repo(&settings, create_metrics().1).await;
+        repo.create_invite(&Invite::new("admin", 1, None))
+            .await
+            .unwrap();
+        let list = |token| admin_request(Method::GET, "/admin/invites", token);
+        let res = list_invites(&list(None), &settings, &repo).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = list_invites(&list(Some("wrong")), &settings, &repo).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = list_invites(&list(Some(TOKEN)), &settings, &repo).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn form_fields_decoded() {
+        let form = form_fields(Body::from("pubkey=npub1abc&code=+ab%2Fcd")).await.unwrap();
+        assert_eq!(form.get("pubkey").unwrap(), "npub1abc");
+        assert_eq!(form.get("code").unwrap(), " ab/cd");
+        assert!(form_fields(Body::from(vec![b'a'; MAX_FORM_BYTES + 1])).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tcp_and_unix_listeners_accept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.sock");
+        let path = path.to_str().unwrap();
+        // a stale socket left behind is replaced.
+        drop(bind_unix_socket(path, None).unwrap());
+        let unix_listener = bind_unix_socket(path, Some(0o600)).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_addr = tcp_listener.local_addr().unwrap();
+
+        let mut unix_rx = accept_connections(SocketListener::Unix(unix_listener), false, vec![]);
+        let mut tcp_rx = accept_connections(SocketListener::Tcp(tcp_listener), false, vec![]);
+        let _unix_client = tokio::net::UnixStream::connect(path).await.unwrap();
+        let tcp_client = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+
+        let unix_stream = unix_rx.recv().await.unwrap().unwrap();
+        assert!(unix_stream.remote_addr().ip().is_loopback());
+        let tcp_stream = tcp_rx.recv().await.unwrap().unwrap();
+        assert_eq!(tcp_stream.remote_addr(), tcp_client.local_addr().unwrap());
+    }
 }
@@ -0,0 +1,199 @@
+//! Event signature verification, performed off the connection tasks
+use crate::config::Limits;
+use crate::error::{Error, Result};
+use crate::event::{EventCmd, EventWrapper};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+
+/// Events waiting for each verification worker, before submitters
+/// are made to wait.
+const VERIFY_QUEUE_PER_WORKER: usize = 256;
+
+/// An event command waiting for verification, with the channel its
+/// result is returned on.
+type VerifyJob = (EventCmd, oneshot::Sender<Result<EventWrapper>>);
+
+/// Ids of recently stored events.  This is an approximate LRU: ids
+/// are kept in two generations, and an id seen again while in the
+/// older generation is promoted to the newer one.
+struct RecentIds {
+    current: HashSet<String>,
+    previous: HashSet<String>,
+    generation_size: usize,
+}
+
+impl RecentIds {
+    fn new(capacity: usize) -> RecentIds {
+        RecentIds {
+            current: HashSet::new(),
+            previous: HashSet::new(),
+            generation_size: capacity.div_ceil(2),
+        }
+    }
+
+    fn insert(&mut self, id: &str) {
+        if self.generation_size == 0 || self.current.contains(id) {
+            return;
+        }
+        if self.current.len() >= self.generation_size {
+            // the oldest generation is forgotten
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.current.insert(id.to_owned());
+    }
+
+    fn contains(&mut self, id: &str) -> bool {
+        if self.current.contains(id) {
+            return true;
+        }
+        if self.previous.remove(id) {
+            self.insert(id);
+            return true;
+        }
+        false
+    }
+}
+
+/// A bounded pool of threads verifying event signatures, along with
+/// the ids of recently stored events, which need no verification.
+#[derive(Clone)]
+pub struct VerifyPool {
+    /// Channel to the workers; verification is done inline if there
+    /// are none.
+    job_tx: Option<mpsc::Sender<VerifyJob>>,
+    recent: Arc<Mutex<RecentIds>>,
+}
+
+impl VerifyPool {
+    /// Start verification workers, as configured.
+    #[must_use]
+    pub fn new(limits: &Limits) -> VerifyPool {
+        let recent = Arc::new(Mutex::new(RecentIds::new(limits.recent_ids_cache)));
+        if limits.verify_workers == 0 {
+            return VerifyPool {
+                job_tx: None,
+                recent,
+            };
+        }
+        info!(
+            "starting {} signature verification workers",
+            limits.verify_workers
+        );
+        let (job_tx, job_rx) =
+            mpsc::channel::<VerifyJob>(limits.verify_workers * VERIFY_QUEUE_PER_WORKER);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let per_take = limits.verify_events_per_take.max(1);
+        for i in 0..limits.verify_workers {
+            let job_rx = job_rx.clone();
+            thread::Builder::new()
+                .name(format!("verify-{i}"))
+                .spawn(move || verify_worker(&job_rx, per_take))
+                .expect("could not start verification worker");
+        }
+        VerifyPool {
+            job_tx: Some(job_tx),
+            recent,
+        }
+    }
+
+    /// Validate and convert a network event command.  `EVENT`
+    /// signatures are verified on the worker pool.
+    pub async fn parse(&self, ec: EventCmd) -> Result<EventWrapper> {
+        match &self.job_tx {
+            Some(job_tx) if ec.cmd() == "EVENT" => {
+                let (result_tx, result_rx) = oneshot::channel();
+                job_tx
+                    .send((ec, result_tx))
+                    .await
+                    .map_err(|_| Error::ChannelClosed)?;
+                result_rx.await.map_err(|_| Error::ChannelClosed)?
+            }
+            _ => Result::<EventWrapper>::from(ec),
+        }
+    }
+
+    /// Has an event with this id been stored recently?
+    #[must_use]
+    pub fn recently_stored(&self, id: &str) -> bool {
+        self.recent.lock().unwrap().contains(id)
+    }
+
+    /// Remember that an event with this id was stored.
+    pub fn record_stored(&self, id: &str) {
+        self.recent.lock().unwrap().insert(id);
+    }
+}
+
+/// Verify queued events until every submitter has gone away.  Up to
+/// `per_take` waiting events are taken from the queue at once, and
+/// then verified one at a time; secp256k1 has no batch Schnorr
+/// verification.
+fn verify_worker(job_rx: &Mutex<mpsc::Receiver<VerifyJob>>, per_take: usize) {
+    let mut taken = Vec::with_capacity(per_take);
+    loop {
+        {
+            let mut rx = job_rx.lock().unwrap();
+            match rx.blocking_recv() {
+                Some(job) => taken.push(job),
+                None => break,
+            }
+            while taken.len() < per_take {
+                match rx.try_recv() {
+                    Ok(job) => taken.push(job),
+                    Err(_) => break,
+                }
+            }
+        }
+        for (ec, result_tx) in taken.drain(..) {
+            // the submitter may have disconnected
+            result_tx.send(Result::<EventWrapper>::from(ec)).ok();
+        }
+    }
+    debug!("verification worker stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::event::Event;
+
+    #[test]
+    fn recent_ids_promotes() {
+        let mut recent = RecentIds::new(4);
+        recent.insert("a");
+        recent.insert("b");
+        // "a" and "b" move to the older generation
+        recent.insert("c");
+        assert!(recent.contains("a"));
+        // "b" and "c" are forgotten once "a" is promoted
+        recent.insert("d");
+        recent.insert("e");
+        assert!(recent.contains("a"));
+        assert!(!recent.contains("b"));
+        assert!(recent.contains("e"));
+    }
+
+    #[test]
+    fn recent_ids_disabled() {
+        let mut recent = RecentIds::new(0);
+        recent.insert("a");
+        assert!(!recent.contains("a"));
+    }
+
+    #[tokio::test]
+    async fn pool_rejects_invalid_event() {
+        let mut limits = Settings::default().limits;
+        limits.verify_workers = 2;
+        limits.verify_events_per_take = 4;
+        let pool = VerifyPool::new(&limits);
+        let ec: EventCmd =
+            serde_json::from_value(serde_json::json!(["EVENT", Event::simple_event()])).unwrap();
+        assert!(pool.parse(ec).await.is_err());
+        pool.record_stored("0");
+        assert!(pool.recently_stored("0"));
+    }
+}